@@ -0,0 +1,88 @@
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER primary key not null,
+    name TEXT not null,
+    password TEXT not null,
+    groups TEXT not null,
+    totp_secret TEXT,
+    totp_enabled INTEGER not null DEFAULT 0,
+    recovery_codes TEXT not null DEFAULT ''
+);
+CREATE TABLE IF NOT EXISTS history (
+    id INTEGER primary key not null,
+    username TEXT not null,
+    device TEXT not null,
+    command TEXT not null,
+    qtype TEXT not null,
+    rejected INTEGER not null DEFAULT 0,
+    timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    api_token_id INTEGER,
+    ip TEXT not null DEFAULT '',
+    user_agent TEXT not null DEFAULT '',
+    payload_digest TEXT not null DEFAULT '',
+    outcome TEXT not null DEFAULT '',
+    error TEXT not null DEFAULT '',
+    duration_ms INTEGER not null DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS history_username ON history (username);
+CREATE INDEX IF NOT EXISTS history_device ON history (device);
+CREATE INDEX IF NOT EXISTS history_command ON history (command);
+CREATE INDEX IF NOT EXISTS history_qtype ON history (qtype);
+CREATE INDEX IF NOT EXISTS history_timestamp ON history (timestamp);
+CREATE INDEX IF NOT EXISTS history_rejected ON history (rejected);
+CREATE TABLE IF NOT EXISTS history_daily (
+    day TEXT not null,
+    username TEXT not null,
+    device TEXT not null,
+    command TEXT not null,
+    qtype TEXT not null,
+    rejected INTEGER not null,
+    outcome TEXT not null,
+    counter INTEGER not null,
+    PRIMARY KEY (day, username, device, command, qtype, rejected, outcome)
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER primary key not null,
+    token TEXT not null UNIQUE,
+    username TEXT not null,
+    created_at TIMESTAMP not null,
+    last_seen TIMESTAMP not null,
+    ip TEXT not null DEFAULT '',
+    user_agent TEXT not null DEFAULT '',
+    csrf_token TEXT not null DEFAULT ''
+);
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id INTEGER primary key not null,
+    token TEXT not null UNIQUE,
+    prefix TEXT not null,
+    username TEXT not null,
+    name TEXT not null,
+    groups TEXT not null,
+    created_at TIMESTAMP not null,
+    expires_at TIMESTAMP,
+    last_used TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS roles (
+    id INTEGER primary key not null,
+    name TEXT not null UNIQUE,
+    description TEXT not null DEFAULT ''
+);
+CREATE TABLE IF NOT EXISTS role_groups (
+    role_id INTEGER not null,
+    group_name TEXT not null,
+    PRIMARY KEY (role_id, group_name)
+);
+CREATE TABLE IF NOT EXISTS role_inherits (
+    role_id INTEGER not null,
+    parent_id INTEGER not null,
+    PRIMARY KEY (role_id, parent_id)
+);
+CREATE TABLE IF NOT EXISTS user_roles (
+    username TEXT not null,
+    role_id INTEGER not null,
+    PRIMARY KEY (username, role_id)
+);
+CREATE TABLE IF NOT EXISTS user_groups (
+    username TEXT not null,
+    group_name TEXT not null,
+    PRIMARY KEY (username, group_name)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS filer_quotas (
+    kind TEXT not null,
+    name TEXT not null,
+    limit_bytes BIGINT not null,
+    PRIMARY KEY (kind, name)
+);
+CREATE TABLE IF NOT EXISTS filer_usage (
+    username TEXT primary key not null,
+    used_bytes BIGINT not null
+);
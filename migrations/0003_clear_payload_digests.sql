@@ -0,0 +1 @@
+UPDATE history SET payload_digest = '';
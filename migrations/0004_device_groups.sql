@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS device_groups (
+    group_name TEXT primary key not null,
+    device TEXT not null
+);
@@ -0,0 +1,203 @@
+use crate::database::Database;
+use crate::dashboard::QCommand;
+use crate::device_trait::*;
+use crate::devices::{Devices, Groups, DEV_GROUPS};
+use crate::models::{LineWebify, Principal};
+use crate::totp;
+use crate::command_spec::{CommandSpec, NoPayload, parse_payload};
+
+use serde_json::{Value as jsVal, json};
+use chrono::Utc;
+
+/// Payload of `api_token_create`. Groups are comma-separated, `expires_days` 0 means the token never expires
+#[derive(Deserialize)]
+pub struct ApiTokenPayload {
+    pub name: String,
+    pub groups: String,
+    #[serde(default)]
+    pub expires_days: i64,
+}
+
+/// Lets user manage their own account: the second factor (TOTP) enrollment and personal API tokens
+#[derive(Clone)]
+pub struct AccountDevice {
+    database: Database,
+}
+
+impl AccountDevice {
+    pub fn new(database: &Database) -> AccountDevice {
+        AccountDevice { database: database.clone() }
+    }
+
+    fn get_status(&self, username: &str) -> Result<jsVal, String> {
+        let enabled = self.database.is_totp_enabled(username)
+            .map_err(|err| format!("Error on getting TOTP status: {}", err))?;
+        Ok(json!({
+            "template": "account_status.hbs",
+            "username": username,
+            "totp_enabled": enabled
+        }))
+    }
+
+    fn enroll_totp(&self, username: &str) -> Result<jsVal, String> {
+        let secret = self.database.start_totp_enrollment(username)
+            .map_err(|err| format!("Error on starting TOTP enrollment: {}", err))?;
+        let uri = totp::get_otpauth_uri(username, &secret);
+        Ok(json!({
+            "template": "account_totp_enroll.hbs",
+            "username": username,
+            "secret": secret,
+            "uri": uri,
+            "qr": totp::get_qr_svg(&uri)?
+        }))
+    }
+
+    fn confirm_totp(&self, username: &str, payload: &str) -> Result<jsVal, String> {
+        let codes = self.database.confirm_totp_enrollment(username, payload, Utc::now().timestamp() as u64)
+            .map_err(|err| format!("Error on confirming TOTP: {}", err))?;
+        Ok(json!({
+            "template": "account_recovery_codes.hbs",
+            "username": username,
+            "codes": codes
+        }))
+    }
+
+    fn disable_totp(&self, username: &str, payload: &str) -> Result<jsVal, String> {
+        self.database.disable_totp(username, payload, Utc::now().timestamp() as u64)
+            .map_err(|err| format!("Error on disabling TOTP: {}", err))?;
+        Ok(json!({
+            "template": "simple_message.hbs",
+            "message": "Two-factor authentication has been disabled"
+        }))
+    }
+
+    fn get_api_tokens(&self, principal: &Principal) -> Result<jsVal, String> {
+        let username = principal.username.as_str();
+        let tokens = self.database.get_user_api_tokens(username)
+            .map_err(|err| format!("Error on getting API tokens: {}", err))?;
+        let mut available: Vec<String> = vec![];
+        for group in self.database.get_all_groups()?.into_keys() {
+            if principal.allows_group(&group) && self.database.has_access_to_group(username, &group)? {
+                available.push(group);
+            }
+        }
+        Ok(json!({
+            "template": "account_api_tokens.hbs",
+            "username": username,
+            "available_groups": available.join(","),
+            "tokens": tokens.iter().map(|x| x.get_content()).collect::<jsVal>()
+        }))
+    }
+
+    fn create_api_token(&self, principal: &Principal, data: ApiTokenPayload) -> Result<jsVal, String> {
+        let token = self.database.create_api_token(principal, &data.name, &data.groups, data.expires_days)
+            .map_err(|err| format!("Error on creating API token: {}", err))?;
+        Ok(json!({
+            "template": "account_api_token_created.hbs",
+            "name": data.name.trim(),
+            "token": token
+        }))
+    }
+
+    fn revoke_api_token(&self, principal: &Principal, id: i32) -> Result<jsVal, String> {
+        self.database.revoke_api_token(&principal.username, id)
+            .map_err(|err| format!("Error on revoking API token: {}", err))?;
+        self.get_api_tokens(principal)
+    }
+}
+
+/// Returns the principal, which has sent the command. Tokens are managed within its scope
+fn get_principal(query: &QCommand) -> Result<&Principal, String> {
+    match &query.principal {
+        Some(principal) if principal.username == query.username => Ok(principal),
+        _ => Err("Unknown sender of the command".to_string())
+    }
+}
+
+impl DeviceRead for AccountDevice {
+    fn read_data(&self, query: &QCommand) -> Result<jsVal, String> {
+        let command = query.command.as_str();
+
+        if query.group != DEV_GROUPS[Devices::Account as usize][Groups::Read as usize].unwrap() {
+            return Err("No access to this action".to_string());
+        }
+
+        match command {
+            "totp_status" => self.get_status(&query.username),
+            "api_tokens" => self.get_api_tokens(get_principal(query)?),
+            _ => Err(format!("Unknown for AccountDevice.read command: {}", command))
+        }
+    }
+
+    fn read_status(&self, query: &QCommand) -> Result<jsVal, String> {
+        if query.group != DEV_GROUPS[Devices::Zero as usize][Groups::RStatus as usize].unwrap() {
+            return Err("No access to this action".to_string());
+        }
+        self.get_status(&query.username)
+    }
+}
+
+impl DeviceWrite for AccountDevice {
+    fn write_data(&self, query: &QCommand) -> Result<jsVal, String> {
+        let command = query.command.as_str();
+
+        if query.group != DEV_GROUPS[Devices::Account as usize][Groups::Write as usize].unwrap() {
+            return Err("No access to this action".to_string());
+        }
+
+        match command {
+            "totp_enroll" => self.enroll_totp(&query.username),
+            "totp_confirm" => self.confirm_totp(&query.username, &query.payload),
+            "totp_disable" => self.disable_totp(&query.username, &query.payload),
+            "api_token_create" => self.create_api_token(get_principal(query)?, parse_payload(&query.payload)?),
+            "api_token_revoke" => self.revoke_api_token(get_principal(query)?, parse_payload(&query.payload)?),
+            _ => Err(format!("Unknown for AccountDevice.write command: {}", command))
+        }
+    }
+}
+
+/// TOTP commands take the 6-digit code (or the recovery code for `totp_disable`), tokens are revoked by their ids
+impl DeviceCommands for AccountDevice {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new::<NoPayload>("R", "totp_status").describe("Shows whether the second factor is enabled"),
+            CommandSpec::new::<NoPayload>("R", "api_tokens").describe("Lists your API tokens"),
+            CommandSpec::new::<NoPayload>("W", "totp_enroll").describe("Starts the second factor enrollment"),
+            CommandSpec::new::<String>("W", "totp_confirm")
+                .describe("Enables the second factor")
+                .payload("code from the authenticator"),
+            CommandSpec::new::<String>("W", "totp_disable")
+                .describe("Disables the second factor")
+                .payload("code from the authenticator or the recovery code"),
+            CommandSpec::new::<ApiTokenPayload>("W", "api_token_create")
+                .describe("Creates the API token")
+                .payload(r#"{"name", "groups": "g1,g2", "expires_days"}"#)
+                .with_schema(json!({
+                    "type": "object",
+                    "required": ["name", "groups"],
+                    "properties": {
+                        "name": {"type": "string", "minLength": 1},
+                        "groups": {"type": "string"},
+                        "expires_days": {"type": "integer", "minimum": 0}
+                    }
+                })),
+            CommandSpec::new::<i32>("W", "api_token_revoke").describe("Revokes the API token").payload("id of the token"),
+        ]
+    }
+}
+
+impl DeviceRequest for AccountDevice {
+    fn request_query(&self, _query: &QCommand) -> Result<jsVal, String> {
+        Err("Unimplemented".to_string())
+    }
+}
+
+impl DeviceConfirm for AccountDevice {
+    fn confirm_query(&self, _query: &QCommand) -> Result<jsVal, String> {
+        Err("Unimplemented".to_string())
+    }
+
+    fn dismiss_query(&self, _query: &QCommand) -> Result<jsVal, String> {
+        Err("Unimplemented".to_string())
+    }
+}
@@ -0,0 +1,112 @@
+extern crate actix_web;
+
+use crate::dashboard::{DashBoard, DispatchError, QCommand, ClientInfo};
+use crate::server::get_client_info;
+use crate::models::Principal;
+
+use actix_web::{error, http, web, Error, HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use serde_json::json;
+use log::{debug, warn};
+
+/// Makes structured error response
+pub fn api_error(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": {
+            "code": code,
+            "message": message
+        }
+    }))
+}
+
+fn get_status(err: &DispatchError) -> StatusCode {
+    match err {
+        DispatchError::WrongCredentials | DispatchError::NoAccess(_) => StatusCode::FORBIDDEN,
+        DispatchError::NoDevice(_) => StatusCode::NOT_FOUND,
+        DispatchError::BadQuery(_) | DispatchError::Device(_) => StatusCode::BAD_REQUEST,
+        DispatchError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn unauthorized(message: &str) -> HttpResponse {
+    let mut resp = api_error(StatusCode::UNAUTHORIZED, "unauthorized", message);
+    resp.headers_mut().insert(http::header::WWW_AUTHENTICATE, http::header::HeaderValue::from_static("Bearer"));
+    resp
+}
+
+/// Makes JSON parsing errors look like all other API errors
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _req| {
+        let message = format!("Invalid QCommand: {}", err);
+        error::InternalError::from_response(err, api_error(StatusCode::BAD_REQUEST, "bad_query", &message)).into()
+    })
+}
+
+/// Resolves the bearer token or the session cookie from the `Authorization` header
+fn get_principal(req: &HttpRequest, mdata: &DashBoard<'_>, handler: &str) -> Result<Principal, HttpResponse> {
+    let header = match req.headers().get(http::header::AUTHORIZATION).and_then(|val| val.to_str().ok()) {
+        Some(data) => data,
+        None => return Err(unauthorized("Bearer token is required")),
+    };
+
+    let (ip, user_agent) = get_client_info(req);
+    mdata.database.resolve_authorization(header, &ip, &user_agent).map_err(|e| {
+        warn!("Error in {} at getting the user: {}", handler, e);
+        unauthorized("Invalid or expired token")
+    })
+}
+
+/// Handles the QCommand, sent as JSON. Every device command, which is available in the dashboard,
+/// is available here as `POST /api/v1/{device}` with QCommand in the JSON body:
+///
+/// ```text
+/// curl -X POST https://host/api/v1/printer \
+///      -H "Authorization: Bearer <token>" \
+///      -H "Content-Type: application/json" \
+///      -d '{"qtype": "R", "group": "printer_read", "username": "user", "command": "lpstat", "payload": ""}'
+/// ```
+///
+/// Token is the personal API token (`wfy_...`, see the account device) or the session cookie.
+/// API token is limited to its groups. `username` may be left empty, then it is taken from the token.
+/// On success the raw answer of the device is returned, otherwise the response looks like
+/// `{"error": {"code": "no_access", "message": "..."}}` with the corresponding HTTP status.
+pub async fn api_dispatch(req: HttpRequest, info: web::Path<String>, body: web::Json<QCommand>,
+                          mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
+    let principal = match get_principal(&req, &mdata, "api_dispatch") {
+        Ok(data) => data,
+        Err(resp) => return Ok(resp),
+    };
+
+    let mut query = body.into_inner();
+    if query.username.is_empty() {
+        query.username = principal.username.clone();
+    }
+    debug!("API command `{}` of `{}` to `{}`", query.command, principal.username, info.as_str());
+
+    match mdata.dispatch_logged(&principal, &ClientInfo::from_request(&req), info.as_str(), query).await {
+        Ok(data) => Ok(HttpResponse::Ok().json(data)),
+        Err(e) => Ok(api_error(get_status(&e), e.code(), &e.to_string())),
+    }
+}
+
+/// Lists the commands, which the token may invoke: `GET /api/commands` with the same `Authorization` header.
+/// Each entry tells the device, `qtype`, `command`, the required `group`, whether the command is `mutating`,
+/// its `description` and the `payload` hint with the optional JSON `schema`:
+///
+/// ```text
+/// curl https://host/api/commands -H "Authorization: Bearer <token>"
+/// ```
+pub async fn api_commands(req: HttpRequest, mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
+    let principal = match get_principal(&req, &mdata, "api_commands") {
+        Ok(data) => data,
+        Err(resp) => return Ok(resp),
+    };
+
+    match mdata.get_commands_for(&principal) {
+        Ok(data) => Ok(HttpResponse::Ok().json(json!({"username": principal.username, "commands": data}))),
+        Err(e) => {
+            warn!("Error in api_commands at getting the commands: {}", e);
+            Ok(api_error(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Error on getting the commands"))
+        }
+    }
+}
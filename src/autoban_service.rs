@@ -1,6 +1,7 @@
 use crate::config::Config;
 use crate::database::Database;
 use crate::models::StatEntry;
+use crate::stat_query::{StatQuery, StatColumn};
 
 use log::{debug, info, error};
 
@@ -8,7 +9,7 @@ use std::thread;
 use std::time::Duration;
 
 fn perform_autoban(database: &Database, period_to_view: u32, anomaly_f: f64) -> Result<(), String> {
-    let stats: Vec<StatEntry> = database.load_stats_by_query(&format!("SELECT username, COUNT(*) as counter FROM history WHERE timestamp > date('now', '-{} second') GROUP BY username ORDER BY COUNT(timestamp) DESC;", period_to_view))?;
+    let stats: Vec<StatEntry> = database.load_stats(&StatQuery::count_by(StatColumn::Username).since_s(period_to_view))?;
     let mut sum: usize = 0;
     for entry in &stats {
         sum += entry.counter as usize;
@@ -7,6 +7,7 @@ use crate::news_payload_parser::*;
 use crate::shikimori_scraper::run_parsing;
 use crate::database::Database;
 use crate::devices::{Devices, Groups, DEV_GROUPS};
+use crate::command_spec::{CommandSpec, NoPayload, parse_payload};
 
 use redis::Commands;
 use r2d2_redis::{RedisConnectionManager, r2d2};
@@ -30,25 +31,23 @@ pub struct BlogDevice {
 }
 
 impl BlogDevice {
-    pub fn new(db_config: &str, database: &Database, use_scraper: bool) -> Self {
-        let manager = RedisConnectionManager::new(db_config).unwrap(); // I am a Blade Runner
-        let pool = RedisPool::builder().build(manager).unwrap();
+    pub fn new(db_config: &str, database: &Database, use_scraper: bool) -> Result<Self, String> {
+        let manager = RedisConnectionManager::new(db_config)
+            .map_err(|err| format!("Error on creating redis manager for {}: {:?}", db_config, err))?;
+        let pool = RedisPool::builder().build(manager)
+            .map_err(|err| format!("Error on creating redis pool for {}: {:?}", db_config, err))?;
         if use_scraper {
             run_parsing(pool.clone());
         }
-        BlogDevice {
+        Ok(BlogDevice {
             conn_pool: pool,
             database: database.clone(),
             cache_list: Arc::new(RwLock::new(vec![])),
             cache_last_synced: Arc::new(RwLock::new(0)),
-        }
+        })
     }
 
-    fn new_post(&self, username: &str, payload: &str) -> Result<jsVal, String> {
-        let post: NewsPostParsed = match parse_post(payload) {
-            Ok(val) => val,
-            Err(err) => return Err(format!("Invalid post: {}", err))
-        };
+    fn new_post(&self, username: &str, post: NewsPostParsed) -> Result<jsVal, String> {
         let last_key = "ilast_post";
         let mut curr_conn = match self.conn_pool.get() {
             Ok(val) => val,
@@ -72,15 +71,14 @@ impl BlogDevice {
             .clear();
         *(self.cache_last_synced.write()
             .map_err(|err| format!("Error on clearing sync timestamp: {:?}", err))?) = 0;
-        self.get_post(username, &format!("{}", curr_id))
+        self.get_post(username, curr_id)
     }
 
     fn shownew_post(&self, username: &str, _payload: &str) -> Result<jsVal, String> {
         Ok(json!({"template": "blog_new_post.hbs", "username": username}))
     }
 
-    fn get_post(&self, username: &str, payload: &str) -> Result<jsVal, String> {
-        let post_id: u32 = payload.parse().map_err(|err| { format!("Couldn't parse the argument: {:?}", err) })?;
+    fn get_post(&self, username: &str, post_id: u32) -> Result<jsVal, String> {
         let mut curr_conn = match self.conn_pool.get() {
             Ok(val) => val,
             Err(err) => return Err(format!("Error on getting current redis conn: {:?}", err))
@@ -113,8 +111,7 @@ impl BlogDevice {
         }))
     }
 
-    fn new_cmm(&self, username: &str, payload: &str) -> Result<jsVal, String> {
-        let cmm_parsed = parse_cmm(payload)?;
+    fn new_cmm(&self, username: &str, cmm_parsed: NewsCmmParsed) -> Result<jsVal, String> {
         let mut curr_conn = match self.conn_pool.get() {
             Ok(val) => val,
             Err(err) => return Err(format!("Error on getting current redis conn: {:?}", err))
@@ -124,10 +121,10 @@ impl BlogDevice {
         curr_conn.deref_mut().set(&format!("cmmcount_{}", cmm_parsed.post_id), cmmcount + 1)
             .map_err(|err| { format!("Redis err: {:?}", err) })?;
         curr_conn.deref_mut().rpush(&format!("cmms_{}", cmm_parsed.post_id),
-                                    format!(r#"{{"username": "{}", "timestamp": "{}", "text": "{}"}}"#,
-                                            username, cmm_parsed.date, cmm_parsed.text)).map_err(|err| { format!("Redis err: {:?}", err) })?;
+                                    json!({"username": username, "timestamp": format!("{}", Utc::now()), "text": cmm_parsed.text}).to_string())
+            .map_err(|err| { format!("Redis err: {:?}", err) })?;
 
-        self.get_post(username, &format!("{}", cmm_parsed.post_id))
+        self.get_post(username, cmm_parsed.post_id)
     }
 
     fn get_list_of_posts(&self, username: &str) -> Result<jsVal, String> {
@@ -187,7 +184,7 @@ impl DeviceRead for BlogDevice {
         }
 
         match command {
-            "getpost" => self.get_post(&query.username, &query.payload),
+            "getpost" => self.get_post(&query.username, parse_payload(&query.payload)?),
             _ => return Err(format!("Unknown for BlogDevice.read command: {}", command))
         }
     }
@@ -210,7 +207,7 @@ impl DeviceWrite for BlogDevice {
         }
 
         match command {
-            "createpost" => self.new_post(&query.username, &query.payload),
+            "createpost" => self.new_post(&query.username, parse_payload(&query.payload)?),
             "showcreatepost" => self.shownew_post(&query.username, &query.payload),
             _ => return Err(format!("Unknown for BlogDevice.read command: {}", command))
         }
@@ -218,6 +215,24 @@ impl DeviceWrite for BlogDevice {
 }
 
 
+/// Posts are opened by their ids, new posts and comments are sent as JSON
+impl DeviceCommands for BlogDevice {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new::<u32>("R", "getpost").describe("Shows the post").payload("id of the post"),
+            CommandSpec::new::<NewsPostParsed>("W", "createpost")
+                .describe("Publishes the post")
+                .payload(r#"{"title", "body"}"#)
+                .with_schema(post_schema()),
+            CommandSpec::new::<NoPayload>("W", "showcreatepost").describe("Shows the form of the new post").readonly(),
+            CommandSpec::new::<NewsCmmParsed>("Q", "createcmm")
+                .describe("Comments the post")
+                .payload(r#"{"post_id", "text"}"#)
+                .with_schema(cmm_schema()),
+        ]
+    }
+}
+
 impl DeviceRequest for BlogDevice {
     fn request_query(&self, query: &QCommand) -> Result<jsVal, String> {
         let command = query.command.as_str();
@@ -227,7 +242,7 @@ impl DeviceRequest for BlogDevice {
         }
 
         match command {
-            "createcmm" => self.new_cmm(&query.username, &query.payload),
+            "createcmm" => self.new_cmm(&query.username, parse_payload(&query.payload)?),
             _ => return Err(format!("Unknown for BlogDevice.read command: {}", command))
         }
     }
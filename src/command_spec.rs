@@ -0,0 +1,365 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Value as jsVal, from_str as js_from_str, from_value as js_from_value};
+
+/// Declares the command of the device: its type (`R`, `W`, `Q`, `C`, `D`), name and the payload.
+/// `DashBoard::dispatch` checks the payload against the spec before the device sees it,
+/// so devices may parse it with `parse_payload` and get the same type.
+/// Description and the payload hint go to the command catalogue (`/api/commands`)
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::command_spec::{CommandSpec, NoPayload};
+/// use serde_json::json;
+///
+/// #[derive(serde_derive::Deserialize)]
+/// struct Cancel {
+///     id: u32,
+/// }
+///
+/// let specs = vec![
+///     CommandSpec::new::<NoPayload>("R", "lpstat")
+///         .describe("Shows the printing queue"),
+///     CommandSpec::new::<Cancel>("W", "cancel")
+///         .describe("Cancels the printing job")
+///         .payload("{\"id\": job_id}")
+///         .with_schema(json!({"type": "object", "required": ["id"]})),
+/// ];
+/// assert!(!specs[0].mutating && specs[1].mutating);
+/// assert!(specs[1].validate(r#"{"id": 3}"#).is_ok());
+/// assert!(specs[1].validate("{}").is_err());
+/// ```
+#[derive(Clone)]
+pub struct CommandSpec {
+    pub qtype: &'static str,
+    pub command: &'static str,
+    pub description: &'static str,
+    /// Short human-readable hint of what the payload is, e.g. `path of the file`
+    pub payload: &'static str,
+    /// Whether the command changes anything. By default only `R` commands are read-only
+    pub mutating: bool,
+    /// Optional JSON Schema of the payload. Only the basic keywords are supported, see `check_schema`
+    pub schema: Option<jsVal>,
+    check: fn(&str) -> Result<(), String>,
+}
+
+impl CommandSpec {
+    /// Makes the spec of the command, which payload is parsed into `T`
+    pub fn new<T: DeserializeOwned>(qtype: &'static str, command: &'static str) -> CommandSpec {
+        CommandSpec {
+            qtype,
+            command,
+            description: "",
+            payload: "",
+            mutating: qtype != "R",
+            schema: None,
+            check: check_payload::<T>,
+        }
+    }
+
+    pub fn describe(mut self, description: &'static str) -> CommandSpec {
+        self.description = description;
+        self
+    }
+
+    pub fn payload(mut self, payload: &'static str) -> CommandSpec {
+        self.payload = payload;
+        self
+    }
+
+    /// Marks the command, which only shows something, though its type is not `R`
+    pub fn readonly(mut self) -> CommandSpec {
+        self.mutating = false;
+        self
+    }
+
+    pub fn with_schema(mut self, schema: jsVal) -> CommandSpec {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Checks the payload against the schema and the payload type
+    pub fn validate(&self, payload: &str) -> Result<(), String> {
+        if let Some(schema) = &self.schema {
+            let value = payload_to_value(payload);
+            let is_scalar = !(value.is_object() || value.is_array() || value.is_string());
+            if let Err(err) = check_schema(schema, &value, "payload") {
+                if !is_scalar || check_schema(schema, &jsVal::String(payload.to_string()), "payload").is_err() {
+                    return Err(err);
+                }
+            }
+        }
+        (self.check)(payload)
+    }
+}
+
+/// Entry of the command catalogue: the command spec together with the device and the group,
+/// which the command requires
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandInfo {
+    pub device: String,
+    pub qtype: String,
+    pub command: String,
+    pub group: String,
+    pub mutating: bool,
+    pub description: String,
+    pub payload: String,
+    pub schema: Option<jsVal>,
+}
+
+impl CommandInfo {
+    pub fn new(device: &str, group: &str, spec: &CommandSpec) -> CommandInfo {
+        CommandInfo {
+            device: device.to_string(),
+            qtype: spec.qtype.to_string(),
+            command: spec.command.to_string(),
+            group: group.to_string(),
+            mutating: spec.mutating,
+            description: spec.description.to_string(),
+            payload: spec.payload.to_string(),
+            schema: spec.schema.clone(),
+        }
+    }
+}
+
+/// Payload of the commands, which don't need it. Anything is accepted and ignored
+#[derive(Debug, Clone, Copy)]
+pub struct NoPayload;
+
+impl<'de> serde::Deserialize<'de> for NoPayload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<NoPayload, D::Error> {
+        deserializer.deserialize_ignored_any(serde::de::IgnoredAny).map(|_| NoPayload)
+    }
+}
+
+fn check_payload<T: DeserializeOwned>(payload: &str) -> Result<(), String> {
+    parse_payload::<T>(payload).map(|_| ())
+}
+
+/// Payload is JSON, but HTML forms send plain text (paths, ids, codes), so everything,
+/// which is not valid JSON, is taken as JSON string. Empty payload is `null`
+fn payload_to_value(payload: &str) -> jsVal {
+    if payload.trim().is_empty() {
+        return jsVal::Null;
+    }
+    js_from_str(payload).unwrap_or_else(|_| jsVal::String(payload.to_string()))
+}
+
+/// Parses the payload of `QCommand` into the payload type of the command.
+/// Bare values from the HTML forms work too: `5` is parsed both as number and as string.
+/// Empty payload is also taken as `{}`, so the payloads with all the fields optional may be omitted
+pub fn parse_payload<T: DeserializeOwned>(payload: &str) -> Result<T, String> {
+    let value = payload_to_value(payload);
+    let is_null = value.is_null();
+    let is_scalar = !(value.is_object() || value.is_array() || value.is_string());
+    let err = match js_from_value::<T>(value) {
+        Ok(data) => return Ok(data),
+        Err(err) => format!("{}", err)
+    };
+    if is_null {
+        if let Ok(data) = js_from_value::<T>(jsVal::Object(Default::default())) {
+            return Ok(data);
+        }
+    }
+    if is_scalar {
+        if let Ok(data) = js_from_value::<T>(jsVal::String(payload.to_string())) {
+            return Ok(data);
+        }
+    }
+    Err(err)
+}
+
+fn type_matches(expected: &str, value: &jsVal) -> bool {
+    match expected {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "string" => value.is_string(),
+        _ => false
+    }
+}
+
+/// Checks the value against the subset of JSON Schema: `type`, `enum`, `properties`, `required`,
+/// `additionalProperties` (boolean only), `items`, `minItems`, `maxItems`, `minLength`, `maxLength`,
+/// `minimum` and `maximum`. Other keywords are ignored. `path` names the value in the error message
+pub fn check_schema(schema: &jsVal, value: &jsVal, path: &str) -> Result<(), String> {
+    if let Some(expected) = schema.get("type") {
+        let matches = match expected {
+            jsVal::String(name) => type_matches(name, value),
+            jsVal::Array(names) => names.iter().filter_map(|x| x.as_str()).any(|x| type_matches(x, value)),
+            _ => true
+        };
+        if !matches {
+            let expected = expected.as_str().map(|x| x.to_string()).unwrap_or_else(|| expected.to_string());
+            return Err(format!("`{}` must be of type {}", path, expected));
+        }
+    }
+    if let Some(variants) = schema.get("enum").and_then(|x| x.as_array()) {
+        if !variants.contains(value) {
+            return Err(format!("`{}` must be one of {}", path, jsVal::Array(variants.clone())));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(|x| x.as_object());
+        for field in schema.get("required").and_then(|x| x.as_array()).into_iter().flatten().filter_map(|x| x.as_str()) {
+            if !object.contains_key(field) {
+                return Err(format!("`{}.{}` is required", path, field));
+            }
+        }
+        for (key, field) in object.iter() {
+            match properties.and_then(|x| x.get(key)) {
+                Some(field_schema) => check_schema(field_schema, field, &format!("{}.{}", path, key))?,
+                None => if schema.get("additionalProperties") == Some(&jsVal::Bool(false)) {
+                    return Err(format!("`{}.{}` is not allowed", path, key));
+                }
+            };
+        }
+    }
+
+    if let Some(array) = value.as_array() {
+        if let Some(min) = schema.get("minItems").and_then(|x| x.as_u64()) {
+            if (array.len() as u64) < min {
+                return Err(format!("`{}` must have at least {} items", path, min));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(|x| x.as_u64()) {
+            if array.len() as u64 > max {
+                return Err(format!("`{}` must have at most {} items", path, max));
+            }
+        }
+        if let Some(items) = schema.get("items") {
+            for (index, item) in array.iter().enumerate() {
+                check_schema(items, item, &format!("{}[{}]", path, index))?;
+            }
+        }
+    }
+
+    if let Some(text) = value.as_str() {
+        let len = text.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(|x| x.as_u64()) {
+            if len < min {
+                return Err(format!("`{}` must be at least {} characters long", path, min));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(|x| x.as_u64()) {
+            if len > max {
+                return Err(format!("`{}` must be at most {} characters long", path, max));
+            }
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(min) = schema.get("minimum").and_then(|x| x.as_f64()) {
+            if number < min {
+                return Err(format!("`{}` must be at least {}", path, min));
+            }
+        }
+        if let Some(max) = schema.get("maximum").and_then(|x| x.as_f64()) {
+            if number > max {
+                return Err(format!("`{}` must be at most {}", path, max));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Optional {
+        #[serde(default)]
+        path: String,
+        #[serde(default)]
+        confirm: bool,
+    }
+
+    fn check(schema: jsVal, value: jsVal) -> Result<(), String> {
+        check_schema(&schema, &value, "payload")
+    }
+
+    #[test]
+    fn schema_errors() {
+        let object = json!({
+            "type": "object",
+            "required": ["id"],
+            "additionalProperties": false,
+            "properties": {
+                "id": {"type": "integer", "minimum": 1, "maximum": 10},
+                "name": {"type": "string", "minLength": 2, "maxLength": 4},
+                "tags": {"type": "array", "minItems": 1, "maxItems": 2, "items": {"enum": ["a", "b"]}},
+                "note": {"type": ["string", "null"]}
+            }
+        });
+        assert_eq!(check(object.clone(), json!({"id": 5, "name": "abc", "tags": ["a", "b"], "note": null})), Ok(()));
+        assert_eq!(check(object.clone(), json!({"name": "abc"})), Err("`payload.id` is required".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 5, "other": 1})), Err("`payload.other` is not allowed".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 0})), Err("`payload.id` must be at least 1".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 11})), Err("`payload.id` must be at most 10".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1.5})), Err("`payload.id` must be of type integer".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1, "name": "a"})), Err("`payload.name` must be at least 2 characters long".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1, "name": "abcde"})), Err("`payload.name` must be at most 4 characters long".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1, "tags": []})), Err("`payload.tags` must have at least 1 items".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1, "tags": ["a", "a", "a"]})), Err("`payload.tags` must have at most 2 items".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1, "tags": ["a", "c"]})), Err("`payload.tags[1]` must be one of [\"a\",\"b\"]".to_string()));
+        assert_eq!(check(object.clone(), json!({"id": 1, "note": 1})), Err("`payload.note` must be of type [\"string\",\"null\"]".to_string()));
+        assert_eq!(check(object, json!([1])), Err("`payload` must be of type object".to_string()));
+
+        // unknown fields are allowed without `additionalProperties: false`
+        assert_eq!(check(json!({"type": "object", "properties": {}}), json!({"other": 1})), Ok(()));
+    }
+
+    #[test]
+    fn bare_form_values() {
+        assert_eq!(parse_payload::<String>("5"), Ok("5".to_string()));
+        assert_eq!(parse_payload::<u32>("5"), Ok(5));
+        assert_eq!(parse_payload::<String>("true"), Ok("true".to_string()));
+        assert_eq!(parse_payload::<bool>("true"), Ok(true));
+        assert_eq!(parse_payload::<String>("null"), Ok("null".to_string()));
+        assert_eq!(parse_payload::<Option<u32>>("null"), Ok(None));
+        assert_eq!(parse_payload::<String>("007"), Ok("007".to_string()));
+        assert!(parse_payload::<u32>("007").is_err());
+        assert!(parse_payload::<u32>("-1").is_err());
+        assert!(parse_payload::<u32>("docs/a.txt").is_err());
+        assert_eq!(parse_payload::<String>("docs/a.txt"), Ok("docs/a.txt".to_string()));
+        assert_eq!(parse_payload::<String>("\"quoted\""), Ok("quoted".to_string()));
+    }
+
+    #[test]
+    fn empty_payload_is_empty_object() {
+        assert_eq!(parse_payload::<Optional>(""), Ok(Optional { path: String::new(), confirm: false }));
+        assert_eq!(parse_payload::<Optional>("  "), Ok(Optional { path: String::new(), confirm: false }));
+        assert_eq!(parse_payload::<Optional>(r#"{"confirm": true}"#), Ok(Optional { path: String::new(), confirm: true }));
+        assert!(parse_payload::<NoPayload>("").is_ok());
+        assert!(parse_payload::<u32>("").is_err());
+    }
+
+    #[test]
+    fn validate_retries_scalar_as_string() {
+        let code = CommandSpec::new::<String>("W", "code")
+            .with_schema(json!({"type": "string", "minLength": 6, "maxLength": 6}));
+        assert_eq!(code.validate("012345"), Ok(()));
+        assert_eq!(code.validate("123456"), Ok(()));
+        assert_eq!(code.validate("1234567"), Err("`payload` must be of type string".to_string()));
+        assert!(code.validate("true").is_err());
+        assert!(code.validate(r#"{"code": "123456"}"#).is_err());
+        assert!(code.validate(r#"["123456"]"#).is_err());
+
+        let id = CommandSpec::new::<u32>("W", "cancel")
+            .with_schema(json!({"type": "integer", "minimum": 1}));
+        assert_eq!(id.validate("5"), Ok(()));
+        assert_eq!(id.validate("0"), Err("`payload` must be at least 1".to_string()));
+        assert!(id.validate("five").is_err());
+
+        let optional = CommandSpec::new::<Optional>("W", "rmdir")
+            .with_schema(json!({"type": ["object", "null"], "properties": {"confirm": {"type": "boolean"}}}));
+        assert_eq!(optional.validate(""), Ok(()));
+        assert!(optional.validate(r#"{"confirm": "yes"}"#).is_err());
+    }
+}
@@ -1,13 +1,15 @@
 extern crate toml;
 
 use crate::io_tools;
-use crate::database::{init_db, get_connection, insert_user};
+use crate::database::{init_db, get_connection, upgrade_database, insert_user, assign_user_role, get_random_token, ADMIN_ROLE};
 use crate::printer_device::{PrinterDevice, PRINTER_CONFIG_PATH, PrinterConfig};
-use crate::devices::list_all_groups;
+use crate::startup::StartupError;
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
 use secstr::SecStr;
 
+use std::collections::BTreeMap;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub db_config: String,
@@ -21,39 +23,123 @@ pub struct Config {
     pub autoban_period_s: u32,
     pub autoban_anomaly_factor: f64,
     pub ecg_server: String,
+    #[serde(default = "default_session_idle_timeout_s")]
+    pub session_idle_timeout_s: u32,
+    #[serde(default = "default_session_lifetime_s")]
+    pub session_lifetime_s: u32,
+    #[serde(default = "default_session_sweep_period_s")]
+    pub session_sweep_period_s: u32,
+    #[serde(default = "default_login_window_s")]
+    pub login_window_s: u32,
+    #[serde(default = "default_login_max_user_attempts")]
+    pub login_max_user_attempts: u32,
+    #[serde(default = "default_login_max_ip_attempts")]
+    pub login_max_ip_attempts: u32,
+    #[serde(default = "default_lockout_user_s")]
+    pub lockout_user_s: u32,
+    #[serde(default = "default_lockout_ip_s")]
+    pub lockout_ip_s: u32,
+    /// Plain HTTP is served when it is false, use it only behind the reverse proxy, which terminates TLS
+    #[serde(default = "default_tls_enabled")]
+    pub tls_enabled: bool,
+    #[serde(default = "default_tls_cert_path")]
+    pub tls_cert_path: String,
+    #[serde(default = "default_tls_key_path")]
+    pub tls_key_path: String,
+    #[serde(default = "default_tls_reload_poll_s")]
+    pub tls_reload_poll_s: u32,
+    /// CA bundle for the client certificates. Empty string disables the login with certificates
+    #[serde(default)]
+    pub tls_client_ca_path: String,
+    /// Field of the client certificate with the name of the user: `cn`, `email` or `dns`
+    #[serde(default = "default_tls_client_cert_field")]
+    pub tls_client_cert_field: String,
+    /// History older than this goes to the archive and to the daily aggregates. 0 keeps the history forever
+    #[serde(default)]
+    pub history_retention_days: u32,
+    /// Directory for the gzipped NDJSON archives of the history
+    #[serde(default = "default_history_archive_dir")]
+    pub history_archive_dir: String,
+    #[serde(default = "default_history_archive_period_s")]
+    pub history_archive_period_s: u32,
+    /// Key of the HMAC of the payloads in the history, `--setup` generates it.
+    /// Without the key the payloads are not stored at all
+    #[serde(default)]
+    pub history_digest_key: String,
+    /// Biggest file, which may be uploaded to the filer, in megabytes. 0 is unlimited
+    #[serde(default)]
+    pub filer_max_upload_mb: u32,
+    /// Storage of each user in the filer, in megabytes of the compressed files. 0 is unlimited
+    #[serde(default)]
+    pub filer_quota_mb: u32,
+    /// Addresses of the reverse proxies, whose `Forwarded`/`X-Forwarded-For` headers are trusted.
+    /// Empty list means the client address is always the address of the peer
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Per-device sections like `[devices.ecgdev]`. Devices without the section are enabled.
+    /// Must stay the last field, because TOML tables go after the plain values
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceConfig>,
 }
 
+/// Settings of the single device in `config.toml`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceConfig {
+    #[serde(default = "default_device_enabled")]
+    pub enabled: bool,
+}
+
+fn default_device_enabled() -> bool { true }
+
+fn default_session_idle_timeout_s() -> u32 { 3600 * 2 }
+
+fn default_session_lifetime_s() -> u32 { 3600 * 24 * 7 }
+
+fn default_session_sweep_period_s() -> u32 { 600 }
+
+fn default_login_window_s() -> u32 { 900 }
+
+fn default_login_max_user_attempts() -> u32 { 10 }
+
+fn default_login_max_ip_attempts() -> u32 { 30 }
+
+fn default_lockout_user_s() -> u32 { 900 }
+
+fn default_lockout_ip_s() -> u32 { 3600 }
+
+fn default_tls_enabled() -> bool { true }
+
+fn default_tls_cert_path() -> String { "cert.pem".to_string() }
+
+fn default_tls_key_path() -> String { "key.pem".to_string() }
+
+fn default_tls_reload_poll_s() -> u32 { 60 }
+
+fn default_tls_client_cert_field() -> String { "cn".to_string() }
+
+fn default_history_archive_dir() -> String { "history_archive".to_string() }
+
+fn default_history_archive_period_s() -> u32 { 3600 * 24 }
+
 pub static DEFAULT_CONFIG_PATH: &str = "config.toml";
 
-/// Reads `config.toml` and returns Result with Users on Ok()
+/// Reads `config.toml` and returns Result with Config on Ok()
 ///
 /// # Examples
 ///
 /// ```rust
 /// use webify::config::{read_config, Config};
-/// let users = read_config::<Config>("config.toml").unwrap();
+/// let config = read_config::<Config>("config.toml").unwrap();
 /// ```
-pub fn read_config<T: Serialize + DeserializeOwned + Clone>(conf_path: &str) -> Result<T, String>
+pub fn read_config<T: Serialize + DeserializeOwned + Clone>(conf_path: &str) -> Result<T, StartupError>
 {
     if !io_tools::exists(conf_path) {
-        panic!("No `config.toml` file, run `$ webify --setup` ");
+        return Err(StartupError::ConfigMissing(conf_path.to_string()));
     }
-    let config_str = match io_tools::read_str(conf_path) {
-        Ok(v) => v,
-        Err(err) => {
-            eprintln!("Error on reading the config: {:?}", err);
-            return Err("Error on reading the config".to_string());
-        }
-    };
-    let config: T = match toml::from_str(&config_str) {
-        Ok(value) => value,
-        Err(err) => {
-            println!("Something goes wrong while reading the users: {}", err);
-            return Err(format!("{:?}", err));
-        }
-    };
-
-    Ok(config)
+    let config_str = io_tools::read_str(conf_path)
+        .map_err(|reason| StartupError::ConfigInvalid { path: conf_path.to_string(), reason })?;
+    toml::from_str(&config_str)
+        .map_err(|err| StartupError::ConfigInvalid { path: conf_path.to_string(), reason: format!("{}", err) })
 }
 
 
@@ -63,6 +149,7 @@ pub fn read_config<T: Serialize + DeserializeOwned + Clone>(conf_path: &str) ->
 ///
 /// ```rust
 /// use webify::config::Config;
+/// use std::collections::BTreeMap;
 /// let config = Config {
 ///     db_config: String::from("database.db"),
 ///     bind_address: String::from("127.0.0.1:2280"),
@@ -74,7 +161,29 @@ pub fn read_config<T: Serialize + DeserializeOwned + Clone>(conf_path: &str) ->
 ///     period_to_request_s: 2592000,
 ///     autoban_period_s: 24*3600,
 ///     autoban_anomaly_factor: 8.0,
-///     use_scraper: true
+///     use_scraper: true,
+///     session_idle_timeout_s: 7200,
+///     session_lifetime_s: 7*24*3600,
+///     session_sweep_period_s: 600,
+///     login_window_s: 900,
+///     login_max_user_attempts: 10,
+///     login_max_ip_attempts: 30,
+///     lockout_user_s: 900,
+///     lockout_ip_s: 3600,
+///     tls_enabled: true,
+///     tls_cert_path: String::from("cert.pem"),
+///     tls_key_path: String::from("key.pem"),
+///     tls_reload_poll_s: 60,
+///     tls_client_ca_path: String::from(""),
+///     tls_client_cert_field: String::from("cn"),
+///     history_retention_days: 90,
+///     history_archive_dir: String::from("history_archive"),
+///     history_archive_period_s: 24*3600,
+///     history_digest_key: String::from("a22b4e7c9d20f3e6b1f9c43e24a5d7b0"),
+///     filer_max_upload_mb: 4096,
+///     filer_quota_mb: 0,
+///     trusted_proxies: vec![],
+///     devices: BTreeMap::new(),
 /// };
 /// write_database(config).unwrap();
 /// ```
@@ -110,6 +219,27 @@ pub fn setup() {
     let autoban_s = io_tools::read_std_line("Period of the autoban worker (0 to disable): ").parse::<u32>().unwrap();
     let anomaly_f = io_tools::read_std_line("Factor for detecting anomalies via autoban (0 to disable): ").parse::<f64>().unwrap();
     let ecg_server = io_tools::read_std_line("Enter ECG server URL (eg https://127.0.0.1:8080/): ");
+    let session_idle = io_tools::read_std_line("Session idle timeout in seconds (eg 7200): ").parse::<u32>().unwrap();
+    let session_lifetime = io_tools::read_std_line("Session absolute lifetime in seconds (eg 604800): ").parse::<u32>().unwrap();
+    let session_sweep = io_tools::read_std_line("Period of the expired sessions sweeper in seconds (0 to disable): ").parse::<u32>().unwrap();
+    let login_window = io_tools::read_std_line("Window for counting failed logins in seconds (eg 900): ").parse::<u32>().unwrap();
+    let max_user_attempts = io_tools::read_std_line("Failed logins per user before lockout (eg 10): ").parse::<u32>().unwrap();
+    let max_ip_attempts = io_tools::read_std_line("Failed logins per IP before lockout (eg 30): ").parse::<u32>().unwrap();
+    let lockout_user = io_tools::read_std_line("User lockout duration in seconds (eg 900): ").parse::<u32>().unwrap();
+    let lockout_ip = io_tools::read_std_line("IP lockout duration in seconds (eg 3600): ").parse::<u32>().unwrap();
+    let tls_enabled = io_tools::read_std_line("Serve HTTPS? Choose false only behind the reverse proxy with TLS (true/false) ").parse::<bool>().unwrap();
+    let tls_cert_path = io_tools::read_std_line("Path to the certificate chain in PEM (eg cert.pem): ");
+    let tls_key_path = io_tools::read_std_line("Path to the private key in PEM (eg key.pem): ");
+    let tls_reload_poll = io_tools::read_std_line("Period of checking the certificate for renewal in seconds (0 to disable): ").parse::<u32>().unwrap();
+    let tls_client_ca_path = io_tools::read_std_line("Path to the CA bundle for the client certificates (empty to disable): ");
+    let tls_client_cert_field = io_tools::read_std_line("Field of the client certificate with the username (cn, email or dns): ");
+    let history_retention = io_tools::read_std_line("Days to keep the history before archiving it (0 to keep forever): ").parse::<u32>().unwrap();
+    let history_archive_dir = io_tools::read_std_line("Directory for the history archives (eg history_archive): ");
+    let history_archive_period = io_tools::read_std_line("Period of the history archiver in seconds (eg 86400): ").parse::<u32>().unwrap();
+    let filer_max_upload = io_tools::read_std_line("Biggest upload to the filer in megabytes (0 for unlimited): ").parse::<u32>().unwrap();
+    let filer_quota = io_tools::read_std_line("Filer storage of each user in megabytes (0 for unlimited): ").parse::<u32>().unwrap();
+    let trusted_proxies = io_tools::read_std_line("Addresses of the trusted reverse proxies, separated by comma (empty if there is no proxy): ");
+    let disabled_devices = io_tools::read_std_line("Devices to disable, separated by comma (eg ecgdev,statdev, empty to enable all): ");
 
     println!("\nHere is your printers:\n{}\n", PrinterDevice::get_printers());
     let m_printer = io_tools::read_std_line("Enter name of the printer: ");
@@ -127,6 +257,30 @@ pub fn setup() {
         autoban_anomaly_factor: anomaly_f,
         ecg_server,
         use_scraper,
+        session_idle_timeout_s: session_idle,
+        session_lifetime_s: session_lifetime,
+        session_sweep_period_s: session_sweep,
+        login_window_s: login_window,
+        login_max_user_attempts: max_user_attempts,
+        login_max_ip_attempts: max_ip_attempts,
+        lockout_user_s: lockout_user,
+        lockout_ip_s: lockout_ip,
+        tls_enabled,
+        tls_cert_path,
+        tls_key_path,
+        tls_reload_poll_s: tls_reload_poll,
+        tls_client_ca_path,
+        tls_client_cert_field,
+        history_retention_days: history_retention,
+        history_archive_dir,
+        history_archive_period_s: history_archive_period,
+        history_digest_key: get_random_token(),
+        filer_max_upload_mb: filer_max_upload,
+        filer_quota_mb: filer_quota,
+        trusted_proxies: trusted_proxies.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect(),
+        devices: disabled_devices.split(",").map(|x| x.trim()).filter(|x| !x.is_empty())
+            .map(|x| (x.to_string(), DeviceConfig { enabled: false }))
+            .collect(),
     }, DEFAULT_CONFIG_PATH) {
         Ok(_) => println!("Ok"),
         Err(err) => panic!("{:?}", err),
@@ -166,14 +320,23 @@ pub fn add_root_user() {
             return;
         }
     };
+    if let Err(e) = upgrade_database(&conn) {
+        eprintln!("Error on upgrading db: {}", e);
+        return;
+    }
 
-    match insert_user(&conn, &username, &password, Some(&list_all_groups().join(","))) {
-        Ok(_) => println!("User was added successfully"),
+    match insert_user(&conn, &username, &password, None) {
+        Ok(_) => (),
         Err(e) => {
             eprintln!("Error on adding user to db: {}", e);
             return;
         }
     }
+
+    match assign_user_role(&conn, &username, ADMIN_ROLE) {
+        Ok(_) => println!("User was added successfully"),
+        Err(e) => eprintln!("Error on assigning `{}` role: {}", ADMIN_ROLE, e)
+    }
 }
 
 /// Adds user to the previously configured database
@@ -181,6 +344,7 @@ pub fn add_user() {
     let username = io_tools::read_std_line("Enter new username: ");
     let password = SecStr::from(io_tools::read_std_line("Enter new password: "));
     let groups = io_tools::read_std_line("Enter groups, separated by comma: ");
+    let roles = io_tools::read_std_line("Enter roles, separated by comma (e.g. reader): ");
 
     let conf = match read_config::<Config>(DEFAULT_CONFIG_PATH) {
         Ok(data) => data,
@@ -197,12 +361,27 @@ pub fn add_user() {
             return;
         }
     };
+    if let Err(e) = upgrade_database(&conn) {
+        eprintln!("Error on upgrading db: {}", e);
+        return;
+    }
 
     match insert_user(&conn, &username, &password, Some(&groups)) {
-        Ok(_) => println!("User was added successfully"),
+        Ok(_) => (),
         Err(e) => {
             eprintln!("Error on adding user to db: {}", e);
             return;
         }
     }
+
+    for role in roles.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        match assign_user_role(&conn, &username, role) {
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Error on assigning `{}` role: {}", role, e);
+                return;
+            }
+        }
+    }
+    println!("User was added successfully");
 }
@@ -1,35 +1,33 @@
 extern crate actix_web;
 extern crate actix_form_data;
 
-use crate::database::{Database};
-use crate::root_device::RootDev;
-use crate::device_trait::*;
-use crate::file_device::FileDevice;
-use crate::printer_device::PrinterDevice;
-use crate::blog_device::BlogDevice;
+use crate::database::{Database, is_csrf_token_valid};
+use crate::file_device::{FileDevice, FileDownload, UploadError};
+use crate::safe_path;
 use crate::config::Config;
 use crate::template_cache::TemplateCache;
-use crate::models::RejectReason;
-use crate::stat_device::StatDevice;
-use crate::ecg_device::EcgDevice;
+use crate::models::{RejectReason, Principal, HistoryForm, HistoryFilter, History, HISTORY_CSV_HEADER};
+use crate::device_registry::{DeviceRegistry, RegisterDevices, register_builtin_devices};
+use crate::command_spec::CommandInfo;
+use crate::root_device::RootDev;
 use crate::devices;
-use crate::server::AUTH_COOKIE;
-
-use actix_web::{Error, HttpResponse, web, error, http, HttpRequest};
-use futures::StreamExt;
+use crate::server::{AUTH_COOKIE, get_client_info};
+use crate::session_service::run_session_sweeper;
+use crate::history_service::run_history_retention;
+use crate::startup::StartupError;
+
+use actix_web::{Error, HttpResponse, HttpMessage, web, error, http, HttpRequest};
+use actix_web::http::header::{ContentDisposition, ContentRange, ContentRangeSpec, DispositionParam, DispositionType, ETag,
+                              EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range};
+use futures::{stream, StreamExt};
 use serde_json::Value as jsVal;
 use serde_json::json;
 use actix_multipart::Multipart;
 use log::{debug, error, warn, trace};
-use urlencoding;
 
-use std::sync::Arc;
-use std::collections::BTreeMap;
-use std::convert::TryInto;
-
-trait Device: DeviceRead + DeviceWrite + DeviceConfirm + DeviceRequest {}
-
-impl<T> Device for T where T: DeviceRead + DeviceWrite + DeviceConfirm + DeviceRequest {}
+use std::fmt;
+use std::io::Read;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 
 /// Here we must tell you how requests are handled.
@@ -54,6 +52,8 @@ impl<T> Device for T where T: DeviceRead + DeviceWrite + DeviceConfirm + DeviceR
 ///  * `username` - username of the user, who has made the request. Should match with username, got from database by cookie
 ///  * `command` - name of the command, which is to be sent to the device
 ///  * `payload` - additional data for the command, it can be everything you want
+///  * `principal` - who has sent the command (the session or the API token with its scope). It is set
+///    by the dispatcher and never comes from the client
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct QCommand {
     pub qtype: String,
@@ -61,87 +61,114 @@ pub struct QCommand {
     pub username: String,
     pub command: String,
     pub payload: String,
+    #[serde(skip)]
+    pub principal: Option<Principal>,
 }
 
-/// Dispatches the QCommands between the devices. In most cases it just resolves the device by name
-#[derive(Clone)]
-struct Dispatch {
-    file_device: FileDevice,
-    root_device: RootDev,
-    printer_device: PrinterDevice,
-    blog_device: BlogDevice,
-    stat_device: StatDevice,
-    ecg_device: EcgDevice,
+/// Where the command came from. Goes to the history together with the command
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub ip: String,
+    pub user_agent: String,
 }
 
-impl Dispatch {
-    pub fn new(database: &Database, config: &Config) -> Dispatch {
-        let filer = FileDevice::new(database);
-        Dispatch {
-            printer_device: PrinterDevice::new(Arc::new(filer.clone())),
-            file_device: filer,
-            root_device: RootDev::new(database),
-            blog_device: BlogDevice::new(&config.redis_config, database, config.use_scraper),
-            stat_device: StatDevice::new(database, config),
-            ecg_device: EcgDevice::new(config),
-        }
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> ClientInfo {
+        let (ip, user_agent) = get_client_info(req);
+        ClientInfo { ip, user_agent }
     }
+}
 
-    pub fn resolve_by_name(&self, devname: &str) -> Result<&dyn Device, String> {
-        let index = match devices::DEV_NAMES.iter().position(|x| x == &devname) {
-            Some(v) => v,
-            None => return Err("No such device".to_string())
-        };
-
-        match index.try_into() {
-            Ok(devices::Devices::Filer) => Ok(&self.file_device),
-            Ok(devices::Devices::Printer) => Ok(&self.printer_device),
-            Ok(devices::Devices::Root) => Ok(&self.root_device),
-            Ok(devices::Devices::Blog) => Ok(&self.blog_device),
-            Ok(devices::Devices::Stat) => Ok(&self.stat_device),
-            Ok(devices::Devices::ECG) => Ok(&self.ecg_device),
-            _ => Err("No such device".to_string())
-        }
-    }
+/// Errors longer than this are cut in the history
+const MAX_HISTORY_ERROR_LEN: usize = 512;
+/// Number of the history entries, which the export reads from the database at once
+const HISTORY_EXPORT_CHUNK: i64 = 1000;
+/// Bytes of the file, which are unpacked and sent at once
+const DOWNLOAD_CHUNK: usize = 64 * 1024;
+
+/// Name of the form field (and of the template variable), which carries the CSRF token of the session
+pub const CSRF_FIELD: &str = "csrf_token";
+const MAX_CSRF_FIELD_LEN: usize = 256;
+
+/// QCommand as it comes from the dashboard forms: with the CSRF token of the session
+#[derive(Deserialize, Clone, Debug)]
+pub struct QCommandForm {
+    #[serde(flatten)]
+    pub query: QCommand,
+    pub csrf_token: String,
 }
 
-/// Stores all needed data and dispatcher, and handles all the requests to the devices.
-#[derive(Clone)]
-pub struct DashBoard<'a> {
-    pub database: Database,
-    pub templater: TemplateCache<'a>,
-    dispatcher: Dispatch,
+/// Reasons why the QCommand hasn't reached the device or has been rejected by it
+#[derive(Clone, Debug, PartialEq)]
+pub enum DispatchError {
+    /// Username in the QCommand doesn't match the authenticated user
+    WrongCredentials,
+    /// User has no access to the device or to the requested group
+    NoAccess(String),
+    /// There is no device with such name
+    NoDevice(String),
+    /// QCommand itself is malformed: unknown `qtype` or command, or the payload doesn't match the command spec
+    BadQuery(String),
+    /// Device has refused to execute the command
+    Device(String),
+    /// Something went wrong on our side (database and so on)
+    Internal(String),
 }
 
+impl DispatchError {
+    /// Short machine-readable name of the error, used by the API
+    pub fn code(&self) -> &'static str {
+        match self {
+            DispatchError::WrongCredentials => "wrong_credentials",
+            DispatchError::NoAccess(_) => "no_access",
+            DispatchError::NoDevice(_) => "no_device",
+            DispatchError::BadQuery(_) => "bad_query",
+            DispatchError::Device(_) => "device_error",
+            DispatchError::Internal(_) => "internal_error",
+        }
+    }
 
-impl DashBoard<'_> {
-    pub fn new<'a, 'b>(config: &'a Config) -> Result<DashBoard<'b>, String> {
-        let database = Database::new(config.db_config.as_str(), config.redis_cache.as_str()).unwrap();
-        let ds: DashBoard = DashBoard {
-            dispatcher: Dispatch::new(&database, &config),
-            database: database,
-            templater: TemplateCache::new(),
-        };
-        ds.reload().unwrap();
-        Ok(ds)
+    /// Reason which goes to the history
+    pub fn reject_reason(&self) -> RejectReason {
+        match self {
+            DispatchError::WrongCredentials | DispatchError::NoAccess(_) => RejectReason::NoAuth,
+            DispatchError::NoDevice(_) | DispatchError::BadQuery(_) => RejectReason::Invalid,
+            DispatchError::Device(_) => RejectReason::Error,
+            DispatchError::Internal(_) => RejectReason::Internal,
+        }
     }
+}
 
-    pub fn reload(&self) -> Result<(), String> {
-        self.database.devices_reload()?;
-        self.templater.load("templates")
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::WrongCredentials => write!(f, "Wrong command credentials"),
+            DispatchError::NoAccess(msg) | DispatchError::NoDevice(msg) | DispatchError::BadQuery(msg)
+            | DispatchError::Device(msg) | DispatchError::Internal(msg) => write!(f, "{}", msg),
+        }
     }
+}
+
+/// Dispatches the QCommands between the devices. Devices may block, so it runs on the blocking thread pool,
+/// see `DashBoard::dispatch_logged`
+#[derive(Clone)]
+struct Dispatch {
+    database: Database,
+    devices: DeviceRegistry,
+}
 
+impl Dispatch {
     /// Makes some validity checks and dispatches the command to the device's needed function
-    pub fn dispatch(&self, username: &str, device: &str, query: QCommand) -> Result<jsVal, String> {
+    fn dispatch(&self, username: &str, device: &str, query: QCommand) -> Result<jsVal, DispatchError> {
         if username != query.username {
-            return Err(format!("Wrong command credentials"));
+            return Err(DispatchError::WrongCredentials);
         }
 
         let daccess = match self.database.has_access_to_device(username, device) {
             Ok(d) => d,
             Err(e) => {
                 error!("Error on dispatching (getting access to dev): {}", e);
-                return Err("Error on dispatching".to_string());
+                return Err(DispatchError::Internal("Error on dispatching".to_string()));
             }
         };
 
@@ -149,54 +176,242 @@ impl DashBoard<'_> {
             Ok(d) => d,
             Err(e) => {
                 error!("Error on dispatching (getting access to group): {}", e);
-                return Err("Error on dispatching".to_string());
+                return Err(DispatchError::Internal("Error on dispatching".to_string()));
             }
         };
 
+        let entry = match self.devices.get_entry(device) {
+            Some(d) => d,
+            None => return Err(DispatchError::NoDevice(format!("Error at getting the device `{}`: No such device", device))),
+        };
+        let fdevice = entry.device.as_ref();
+
         if !gaccess || !daccess {
-            return Err(format!("User {} has no access to the {}.{}. Contact the admin.", username, device, &query.group));
+            return Err(DispatchError::NoAccess(format!("User {} has no access to the {}.{}. Contact the admin.", username, device, &query.group)));
         }
 
-        let fdevice = match self.dispatcher.resolve_by_name(device) {
-            Ok(d) => d,
-            Err(e) => return Err(format!("Error at getting the device `{}`: {}", device, e)),
+        // Status queries go through the shared `rstatus` group, if the device has no own one
+        let required = match devices::Groups::from_qtype(&query.qtype) {
+            Some(devices::Groups::RStatus) => entry.groups[devices::Groups::RStatus as usize].clone()
+                .or(devices::DEV_GROUPS[devices::Devices::Zero as usize][devices::Groups::RStatus as usize].map(|x| x.to_string())),
+            Some(kind) => entry.groups[kind as usize].clone(),
+            None => return Err(DispatchError::BadQuery(format!("Unknown type of the query: {}", query.qtype)))
         };
+        if required.as_deref() != Some(query.group.as_str()) {
+            return Err(DispatchError::NoAccess(format!("Group {} doesn't allow queries of the type {} to the {}", &query.group, query.qtype, device)));
+        }
 
-        match query.qtype.as_str() {
+        if query.qtype != "S" && !entry.commands.is_empty() {
+            let spec = match entry.commands.iter().find(|x| x.qtype == query.qtype && x.command == query.command) {
+                Some(d) => d,
+                None => return Err(DispatchError::BadQuery(format!("Unknown command `{}.{}` of the type {}", device, query.command, query.qtype)))
+            };
+            if let Err(e) = spec.validate(&query.payload) {
+                return Err(DispatchError::BadQuery(format!("Invalid payload of `{}.{}`: {}", device, query.command, e)));
+            }
+        }
+
+        let result = match query.qtype.as_str() {
             "R" => fdevice.read_data(&query),
             "W" => fdevice.write_data(&query),
             "Q" => fdevice.request_query(&query),
             "C" => fdevice.confirm_query(&query),
             "D" => fdevice.dismiss_query(&query),
             "S" => fdevice.read_status(&query),
-            _ => Err(format!("Unknown type of the query: {}", query.qtype))
+            _ => return Err(DispatchError::BadQuery(format!("Unknown type of the query: {}", query.qtype)))
+        };
+        result.map_err(DispatchError::Device)
+    }
+
+    fn dispatch_logged(&self, principal: &Principal, client: &ClientInfo, device: &str, mut query: QCommand) -> Result<jsVal, DispatchError> {
+        query.principal = Some(principal.clone());
+        let started = Instant::now();
+        let result = if principal.allows_group(&query.group) {
+            self.dispatch(&principal.username, device, query.clone())
+        } else {
+            Err(DispatchError::NoAccess(format!("Token has no access to the group {}", &query.group)))
+        };
+        let duration_ms = started.elapsed().as_millis().min(i32::MAX as u128) as i32;
+
+        let (reject, error) = match &result {
+            Ok(_) => (RejectReason::Ok, String::new()),
+            Err(e) => (e.reject_reason(), e.to_string().chars().take(MAX_HISTORY_ERROR_LEN).collect()),
+        };
+        let payload_digest = self.database.get_payload_digest(&query.payload);
+        let entry = HistoryForm {
+            username: &principal.username,
+            device,
+            command: &query.command,
+            qtype: &query.qtype,
+            rejected: reject as i32,
+            api_token_id: principal.api_token_id,
+            ip: &client.ip,
+            user_agent: &client.user_agent,
+            payload_digest: &payload_digest,
+            outcome: reject.outcome(),
+            error: &error,
+            duration_ms,
+        };
+        match self.database.insert_history(&entry) {
+            Ok(_) => (),
+            Err(err) => error!("Error on inserting to the history: {}", err),
+        };
+        result
+    }
+}
+
+/// Stores all needed data and the registry of the devices, and handles all the requests to the devices.
+#[derive(Clone)]
+pub struct DashBoard<'a> {
+    pub database: Database,
+    pub templater: TemplateCache<'a>,
+    dispatcher: Dispatch,
+}
+
+
+impl DashBoard<'_> {
+    pub fn new<'b>(config: &Config) -> Result<DashBoard<'b>, StartupError> {
+        DashBoard::with_devices(config, |_, _, _| Ok(()))
+    }
+
+    /// Same as `new`, but `register` adds more devices after the built-in ones
+    pub fn with_devices<'b>(config: &Config, register: RegisterDevices) -> Result<DashBoard<'b>, StartupError> {
+        let database = Database::new(config).map_err(StartupError::Database)?;
+        let mut devices = DeviceRegistry::new(config);
+        register_builtin_devices(&mut devices, &database, config)?;
+        register(&mut devices, &database, config)?;
+        for name in config.devices.keys().filter(|x| devices.is_enabled(x) && devices.resolve(x).is_none()) {
+            warn!("Device `{}` from the config is not registered", name);
+        }
+
+        if let Some(root) = devices.get::<RootDev>(devices::DEV_NAMES[devices::Devices::Root as usize]) {
+            root.set_commands(devices.get_commands())
+                .map_err(|reason| StartupError::Device { device: "root".to_string(), reason })?;
+        }
+
+        let ds: DashBoard = DashBoard {
+            dispatcher: Dispatch { database: database.clone(), devices },
+            database,
+            templater: TemplateCache::new(),
+        };
+        ds.reload().map_err(StartupError::Templates)?;
+        run_session_sweeper(&ds.database, config);
+        run_history_retention(&ds.database, config);
+        Ok(ds)
+    }
+
+    /// Reloads the templates and checks that every device has all its templates
+    pub fn reload(&self) -> Result<(), String> {
+        self.database.set_device_groups(&self.dispatcher.devices.get_group_map())?;
+        self.templater.load("templates")?;
+        for entry in self.dispatcher.devices.entries() {
+            if let Some(template) = entry.templates.iter().find(|x| !self.templater.has_template(x)) {
+                return Err(format!("Template `{}` of the device `{}` is missing", template, entry.name));
+            }
         }
+        Ok(())
+    }
+
+    /// Makes some validity checks and dispatches the command to the device's needed function.
+    /// Blocks until the device answers, async code should use `dispatch_logged`
+    pub fn dispatch(&self, username: &str, device: &str, query: QCommand) -> Result<jsVal, DispatchError> {
+        self.dispatcher.dispatch(username, device, query)
+    }
+
+    /// Checks that the group is in the scope of the principal, dispatches the command
+    /// and writes the result, the client and the time it took to the history. Both HTML dashboard and API go through here.
+    /// Device runs on the blocking thread pool, so the worker keeps serving other requests meanwhile
+    pub async fn dispatch_logged(&self, principal: &Principal, client: &ClientInfo, device: &str, query: QCommand) -> Result<jsVal, DispatchError> {
+        let dispatcher = self.dispatcher.clone();
+        let principal = principal.clone();
+        let client = client.clone();
+        let device = device.to_string();
+        match web::block(move || dispatcher.dispatch_logged(&principal, &client, &device, query)).await {
+            Ok(result) => result,
+            Err(err) => {
+                error!("Error on dispatching (blocking pool): {}", err);
+                Err(DispatchError::Internal("Error on dispatching".to_string()))
+            }
+        }
+    }
+
+    /// Renders the template with the CSRF token of the session added to the context,
+    /// so every form can send it back
+    pub fn render_template(&self, template: &str, data: &jsVal, csrf_token: &str) -> Result<String, String> {
+        let mut context = data.clone();
+        if let Some(map) = context.as_object_mut() {
+            map.insert(CSRF_FIELD.to_string(), json!(csrf_token));
+        }
+        self.templater.render_template(template, &context)
+    }
+
+    /// Returns the commands, which the principal may invoke: the user has the group, and the token allows it
+    pub fn get_commands_for(&self, principal: &Principal) -> Result<Vec<CommandInfo>, String> {
+        let groups = self.database.get_user_groups(&principal.username)?;
+        Ok(self.dispatcher.devices.get_commands().into_iter()
+            .filter(|x| groups.contains(&x.group) && principal.allows_group(&x.group))
+            .collect())
+    }
+
+    /// Returns the filer, if it is enabled
+    pub fn get_filer(&self) -> Result<&FileDevice, String> {
+        self.dispatcher.devices.get::<FileDevice>(devices::DEV_NAMES[devices::Devices::Filer as usize])
+            .ok_or("Filer is disabled".to_string())
+    }
+
+    /// Returns the modification time and the ETag of the file on the blocking thread pool
+    pub async fn file_version_from_filer(&self, username: &str, path: &str) -> Result<(SystemTime, String), String> {
+        let filer = self.get_filer()?.clone();
+        let (username, path) = (username.to_string(), path.to_string());
+        web::block(move || filer.get_file_version(&username, &path)).await
+            .map_err(|err| format!("Error on opening the file (blocking pool): {}", err))?
     }
 
-    pub fn get_file_from_filer(&self, username: &str, path: &str) -> Result<Vec<u8>, String> {
-        let device = &self.dispatcher.file_device;
-        device.get_file(username, path)
+    /// Opens the file on the blocking thread pool
+    pub async fn open_file_from_filer(&self, username: &str, path: &str) -> Result<FileDownload, String> {
+        let filer = self.get_filer()?.clone();
+        let (username, path) = (username.to_string(), path.to_string());
+        web::block(move || filer.open_file(&username, &path)).await
+            .map_err(|err| format!("Error on opening the file (blocking pool): {}", err))?
     }
 }
 
-fn get_available_info(dasher: &DashBoard<'_>, username: &str, device: &str) -> jsVal {
+async fn get_available_info(dasher: &DashBoard<'_>, client: &ClientInfo, username: &str, device: &str) -> jsVal {
     let query = QCommand {
         qtype: "S".to_string(),
         group: "rstatus".to_string(),
         username: username.to_string(),
         command: "rstatus".to_string(),
         payload: "".to_string(),
+        principal: None,
     };
 
-    let (j_val, reject) = match dasher.dispatch(username, device, query.clone()) {
-        Ok(d) => (d, RejectReason::Ok as i32),
-        Err(e) => (json!({"err": format!("Error on getting the available info: {}", e)}), RejectReason::NoAuth as i32)
-    };
-    match dasher.database.insert_history(username, device, &query.command, &query.qtype, reject) {
-        Ok(_) => (),
-        Err(err) => error!("Error on inserting to the history in get_available_info: {}", err),
-    };
-    j_val
+    match dasher.dispatch_logged(&Principal::user(username), client, device, query).await {
+        Ok(d) => d,
+        Err(e) => json!({"err": format!("Error on getting the available info: {}", e)})
+    }
+}
+
+/// Renders the dashboard page with the subpage of the device inside
+fn render_dashboard(mdata: &DashBoard<'_>, user: &str, csrf_token: &str, inner_info: &jsVal) -> Result<HttpResponse, Error> {
+    let inner_template = mdata.render_template(inner_info.get("template").unwrap_or(&json!("")).as_str().unwrap_or(""), inner_info, csrf_token);
+    match mdata.render_template("dashboard.hbs",
+                                &json!({
+                                    "devices": mdata.database.get_user_devices(user).unwrap_or(vec![]),
+                                    "err": match inner_info.get("err") {
+                                        Some(v) => v.as_str().unwrap_or(""),
+                                        None => match &inner_template{Ok(_) => "", Err(err) => err}
+                                    },
+                                    "subpage": match &inner_template{Ok(data) => data, Err(_) => ""}
+                                }), csrf_token) {
+        Ok(data) => Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(data)),
+        Err(err) => {
+            error!("Error in rendering dashboard: {}", err);
+            Ok(HttpResponse::InternalServerError()
+                .content_type("text/html; charset=utf-8")
+                .body("Error on rendering the page. Contact your administrator."))
+        }
+    }
 }
 
 #[cfg(debug_assertions)]
@@ -226,83 +441,57 @@ pub async fn dashboard_page(req: HttpRequest, info: web::Path<String>, mdata: we
         None => return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish()),
     };
 
-    let user = match mdata.database.get_user_from_cookie(&cookie) {
+    let (ip, user_agent) = get_client_info(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &ip, &user_agent) {
         Ok(data) => data,
         Err(e) => {
             warn!("Error in dashboard_page at getting the user: {:?}", e);
             return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish());
         }
     };
-    let inner_info = get_available_info(&mdata, &user, info.as_str());
-    let inner_template = mdata.templater.render_template(&inner_info.get("template").unwrap_or(&json!("")).as_str().unwrap_or(""), &inner_info);
-    match mdata.templater.render_template("dashboard.hbs",
-                                          &json!({
-                                              "devices": mdata.database.get_user_devices(&user).unwrap_or(vec![]),
-                                              "err": match inner_info.get("err") {
-                                                  Some(v) => v.as_str().unwrap_or(""),
-                                                  None => match &inner_template{Ok(_) => "", Err(err) => err}
-                                              },
-                                              "subpage": match &inner_template{Ok(data) => data, Err(_) => ""}
-                                            })) {
-        Ok(data) => Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(data)),
-        Err(err) => {
-            error!("Error in rendering dashboard: {}", err);
-            Ok(HttpResponse::InternalServerError()
-                .content_type("text/html; charset=utf-8")
-                .body("Error on rendering the page. Contact your administrator."))
-        }
-    }
+    let client = ClientInfo { ip, user_agent };
+    let inner_info = get_available_info(&mdata, &client, &session.username, info.as_str()).await;
+    render_dashboard(&mdata, &session.username, &session.csrf_token, &inner_info)
 }
 
 /// Handles the QCommand requests
 pub async fn dashboard_page_req(req: HttpRequest, info: web::Path<String>,
-                                form: web::Form<QCommand>, mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
+                                form: web::Form<QCommandForm>, mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
     let cookie = match req.cookie(AUTH_COOKIE) {
         Some(data) => data.value().to_string(),
         None => return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish()),
     };
 
-    let user = match mdata.database.get_user_from_cookie(&cookie) {
+    let (ip, user_agent) = get_client_info(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &ip, &user_agent) {
         Ok(data) => data,
         Err(e) => {
             error!("Error in dashboard_page_req at getting the user: {:?}", e);
             return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish());
         }
     };
+    let user = &session.username;
+
+    if !is_csrf_token_valid(&session.csrf_token, &form.csrf_token) {
+        warn!("Rejected command `{}` of `{}` from {}: invalid CSRF token", form.query.command, user, ip);
+        return Ok(HttpResponse::Forbidden().body("Forbidden: invalid CSRF token, reload the page"));
+    }
 
-    if user != form.username {
+    if *user != form.query.username {
         return Ok(HttpResponse::BadRequest().body("Bad request: user names doesn't match"));
     }
 
-    let (inner_info, reject) = match mdata.dispatch(&user, info.as_str(), form.0.clone()) {
-        Ok(d) => (d, RejectReason::Ok as i32),
-        Err(e) => (json!({"err": format!("Error on getting the available info: {}", e)}), RejectReason::NoAuth as i32)
-    };
-    match mdata.database.insert_history(&user, info.as_str(), &form.0.command, &form.0.qtype, reject) {
-        Ok(_) => (),
-        Err(err) => error!("Error on inserting to the history: {}", err),
+    let client = ClientInfo { ip, user_agent };
+    let inner_info = match mdata.dispatch_logged(&Principal::user(user), &client, info.as_str(), form.query.clone()).await {
+        Ok(d) => d,
+        Err(e) => json!({"err": format!("Error on getting the available info: {}", e)})
     };
-    let inner_template = mdata.templater.render_template(&inner_info.get("template").unwrap_or(&json!("")).as_str().unwrap_or(""), &inner_info);
-    match mdata.templater.render_template("dashboard.hbs",
-                                          &json!({
-                                              "devices": mdata.database.get_user_devices(&user).unwrap_or(vec![]),
-                                              "err": match inner_info.get("err") {
-                                                  Some(v) => v.as_str().unwrap_or(""),
-                                                  None => match &inner_template{Ok(_) => "", Err(err) => err}
-                                              },
-                                              "subpage": match &inner_template{Ok(data) => data, Err(_) => ""}
-                                            })) {
-        Ok(data) => Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(data)),
-        Err(err) => {
-            error!("Error in rendering dashboard: {}", err);
-            Ok(HttpResponse::InternalServerError()
-                .content_type("text/html; charset=utf-8")
-                .body("Error on rendering the page. Contact your administrator."))
-        }
-    }
+    render_dashboard(&mdata, &session.username, &session.csrf_token, &inner_info)
 }
 
-/// Sends needed file to the user after security checks
+/// Sends needed file to the user after security checks. File is unpacked as it is sent.
+/// Single byte range (`Range: bytes=...`, checked against `If-Range`) and the conditional requests
+/// with `If-None-Match` or `If-Modified-Since` are supported
 pub async fn file_sender(req: HttpRequest, info: web::Path<String>, mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
     trace!("File transfer");
     let cookie = match req.cookie(AUTH_COOKIE) {
@@ -310,7 +499,8 @@ pub async fn file_sender(req: HttpRequest, info: web::Path<String>, mdata: web::
         None => return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish()),
     };
 
-    let user = match mdata.database.get_user_from_cookie(&cookie) {
+    let (ip, user_agent) = get_client_info(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &ip, &user_agent) {
         Ok(data) => data,
         Err(e) => {
             error!("Error in file_sender at getting the user: {:?}", e);
@@ -318,30 +508,210 @@ pub async fn file_sender(req: HttpRequest, info: web::Path<String>, mdata: web::
         }
     };
 
-    let file_data = match mdata.get_file_from_filer(&user, &info.as_str()) {
+    let render_error = |e: String| {
+        error!("Error on getting the file: {}", e);
+        match mdata.render_template("sender_error.hbs", &json!({
+            "filename": info.as_str(),
+            "error_msg": e
+        }), &session.csrf_token) {
+            Ok(htmld) => Ok(HttpResponse::BadRequest().body(htmld)),
+            Err(err) => {
+                error!("Error on rendering template: {}", err);
+                Ok(HttpResponse::InternalServerError().body("Internal error"))
+            }
+        }
+    };
+
+    // conditional requests are answered before opening the file, which may need unpacking to know its size
+    let (modified, etag) = match mdata.file_version_from_filer(&session.username, info.as_str()).await {
         Ok(d) => d,
+        Err(e) => return render_error(e),
+    };
+    let etag = EntityTag::new_strong(etag);
+    // HTTP dates have no fractions of the second
+    let modified = UNIX_EPOCH + Duration::from_secs(modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    let last_modified = HttpDate::from(modified);
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|x| x.weak_eq(&etag)),
+        None => match req.get_header::<IfModifiedSince>() {
+            Some(IfModifiedSince(since)) => modified <= SystemTime::from(since),
+            None => false
+        }
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified().insert_header(ETag(etag)).insert_header(LastModified(last_modified)).finish());
+    }
+
+    let download = match mdata.open_file_from_filer(&session.username, info.as_str()).await {
+        Ok(d) => d,
+        Err(e) => return render_error(e),
+    };
+    // the file may have changed since the version check, range and headers are of the opened one
+    let etag = EntityTag::new_strong(download.etag.clone());
+    let modified = UNIX_EPOCH + Duration::from_secs(download.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    let last_modified = HttpDate::from(modified);
+
+    // range of the older version of the file is useless, so the whole file is sent
+    let range_allowed = match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(&etag),
+        Some(IfRange::Date(date)) => modified <= SystemTime::from(date),
+        None => true
+    };
+    let range = match req.get_header::<Range>() {
+        Some(Range::Bytes(ranges)) if range_allowed && ranges.len() == 1 => match ranges[0].to_satisfiable_range(download.size) {
+            Some(range) => Some(range),
+            None => return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(download.size) }))
+                .finish())
+        },
+        // several ranges are rare, the whole file is sent instead
+        _ => None
+    };
+
+    let (start, length, mut response) = match range {
+        Some((start, end)) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes { range: Some((start, end)), instance_length: Some(download.size) }));
+            (start, end - start + 1, response)
+        }
+        None => (0, download.size, HttpResponse::Ok())
+    };
+    debug!("Sending `{}`: {} bytes from {}", download.filename, length, start);
+    response.insert_header((http::header::CONTENT_TYPE, download.mime.clone()))
+        .insert_header((http::header::ACCEPT_RANGES, "bytes"))
+        .insert_header(ETag(etag))
+        .insert_header(LastModified(last_modified))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(download.filename.clone())],
+        });
+
+    // reader is created on the first chunk, because skipping to the start unpacks everything before it
+    let chunks = stream::unfold(Some((None, download, length)), move |state| async move {
+        let (reader, download, left) = state?;
+        if left == 0 {
+            return None;
+        }
+        let result = web::block(move || {
+            let mut reader: Box<dyn Read + Send> = match reader {
+                Some(reader) => reader,
+                None => download.read_from(start)?,
+            };
+            let mut buffer = vec![0u8; DOWNLOAD_CHUNK.min(left as usize)];
+            let read = reader.read(&mut buffer).map_err(|err| format!("Error on decompressing the file: {:?}", err))?;
+            if read == 0 {
+                return Err(format!("File `{}` has ended before the expected size", download.filename));
+            }
+            buffer.truncate(read);
+            Ok((buffer, reader, download))
+        }).await;
+        match result {
+            Ok(Ok((buffer, reader, download))) => {
+                let left = left - buffer.len() as u64;
+                Some((Ok(web::Bytes::from(buffer)), Some((Some(reader), download, left))))
+            }
+            Ok(Err(e)) => {
+                error!("Error on sending the file: {}", e);
+                Some((Err(error::ErrorInternalServerError("Error on sending the file")), None))
+            }
+            Err(e) => Some((Err(error::ErrorInternalServerError(format!("Error on sending the file: {}", e))), None)),
+        }
+    });
+    Ok(response.no_chunking(length).streaming(chunks))
+}
+
+/// Exports the history, which matches the filter, as `GET /export/history/csv?username=...` or `/export/history/ndjson?...`.
+/// Query parameters are the fields of `HistoryFilter`, sorting and paging are ignored: entries go by id.
+/// History is read by pieces, so the export doesn't load the whole table into memory
+pub async fn history_export(req: HttpRequest, info: web::Path<String>, filter: web::Query<HistoryFilter>,
+                            mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
+    let cookie = match req.cookie(AUTH_COOKIE) {
+        Some(data) => data.value().to_string(),
+        None => return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish()),
+    };
+
+    let client = ClientInfo::from_request(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &client.ip, &client.user_agent) {
+        Ok(data) => data,
         Err(e) => {
-            error!("Error on getting the file: {}", e);
-            match mdata.templater.render_template("sender_error.hbs", &json!({
-                "filename": info.as_str(),
-                "error_msg": e
-            })) {
-                Ok(htmld) => return Ok(HttpResponse::BadRequest().body(htmld)),
-                Err(err) => {
-                    error!("Error on rendering template: {}", err);
-                    return Ok(HttpResponse::InternalServerError().body("Internal error"));
+            error!("Error in history_export at getting the user: {:?}", e);
+            return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish());
+        }
+    };
+
+    let group = devices::DEV_GROUPS[devices::Devices::Root as usize][devices::Groups::Read as usize].unwrap();
+    let gaccess = mdata.database.has_access_to_group(&session.username, group).unwrap_or_else(|e| {
+        error!("Error in history_export at getting the access: {}", e);
+        false
+    });
+    let format = info.into_inner();
+    let (content_type, to_line): (&str, fn(&History) -> String) = match format.as_str() {
+        "csv" => ("text/csv; charset=utf-8", History::to_csv),
+        "ndjson" => ("application/x-ndjson", History::to_ndjson),
+        _ => return Ok(HttpResponse::NotFound().body("Unknown format, use csv or ndjson")),
+    };
+    let filter = filter.into_inner();
+    if let Err(e) = filter.get_range() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+
+    let outcome = if gaccess { RejectReason::Ok } else { RejectReason::NoAuth };
+    let entry = HistoryForm {
+        username: &session.username,
+        device: devices::DEV_NAMES[devices::Devices::Root as usize],
+        command: "export_history",
+        qtype: "R",
+        rejected: outcome as i32,
+        api_token_id: None,
+        ip: &client.ip,
+        user_agent: &client.user_agent,
+        payload_digest: &mdata.database.get_payload_digest(&filter.to_query_string()),
+        outcome: outcome.outcome(),
+        error: "",
+        duration_ms: 0,
+    };
+    if let Err(err) = mdata.database.insert_history(&entry) {
+        error!("Error on inserting to the history: {}", err);
+    }
+    if !gaccess {
+        return Ok(HttpResponse::Forbidden().body("Forbidden: no access to the history"));
+    }
+
+    let database = mdata.database.clone();
+    let header = if format == "csv" { HISTORY_CSV_HEADER } else { "" };
+    // state is the filter and the last exported id, `None` when the history is over
+    let chunks = stream::unfold(Some((filter, None)), move |state| {
+        let database = database.clone();
+        async move {
+            let (filter, after_id) = state?;
+            let (filter, result) = match web::block(move || {
+                let result = database.get_history_chunk(&filter, after_id, HISTORY_EXPORT_CHUNK);
+                (filter, result)
+            }).await {
+                Ok(data) => data,
+                Err(e) => return Some((Err(error::ErrorInternalServerError(format!("Error on exporting the history: {}", e))), None)),
+            };
+            match result {
+                Ok(entries) if entries.is_empty() => None,
+                Ok(entries) => {
+                    let last_id = entries.last().map(|x| x.id);
+                    let next = if (entries.len() as i64) < HISTORY_EXPORT_CHUNK { None } else { Some((filter, last_id)) };
+                    let body: String = entries.iter().map(to_line).collect();
+                    Some((Ok(web::Bytes::from(body)), next))
+                }
+                Err(e) => {
+                    error!("Error on exporting the history: {}", e);
+                    Some((Err(error::ErrorInternalServerError("Error on exporting the history")), None))
                 }
             }
         }
-    };
-    let paths = urlencoding::decode(info.as_str())
-        .map_err(|_| { error::ErrorBadRequest(format!("Cannot decode directory: `{}`", &info.to_string())) })?
-        .to_string();
-    debug!("File size: {}", file_data.len());
-    Ok(HttpResponse::Ok().insert_header((http::header::CONTENT_TYPE, "multipart/form-data"))
-        .insert_header((http::header::CONTENT_LENGTH, file_data.len()))
-        .insert_header((http::header::CONTENT_DISPOSITION, format!("filename=\"{}\"", paths.split("/").collect::<Vec<&str>>().pop().unwrap_or("some_file"))))
-        .body(file_data))
+    });
+    let body = stream::once(async move { Ok::<_, Error>(web::Bytes::from(header)) }).chain(chunks);
+
+    Ok(HttpResponse::Ok().insert_header((http::header::CONTENT_TYPE, content_type))
+        .insert_header((http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"history.{}\"", format)))
+        .streaming(body))
 }
 
 /// Page for uploading the file
@@ -351,15 +721,16 @@ pub async fn upload_index(req: HttpRequest, mdata: web::Data<DashBoard<'_>>, inf
         None => return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish()),
     };
 
-    let user = match mdata.database.get_user_from_cookie(&cookie) {
-        Ok(data) => data.to_string(),
+    let (ip, user_agent) = get_client_info(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &ip, &user_agent) {
+        Ok(data) => data,
         Err(e) => {
             error!("Error in upload_index at getting the user: {:?}", e);
             return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish());
         }
     };
 
-    let gaccess = match mdata.database.has_access_to_group(&user, "filer_write") {
+    let gaccess = match mdata.database.has_access_to_group(&session.username, "filer_write") {
         Ok(d) => d,
         Err(e) => {
             error!("Error on dispatching (getting access to group): {}", e);
@@ -370,9 +741,7 @@ pub async fn upload_index(req: HttpRequest, mdata: web::Data<DashBoard<'_>>, inf
     if !gaccess {
         return Ok(HttpResponse::Forbidden().body("You are not allowed to upload files"));
     }
-    let mut context_data: BTreeMap<String, String> = BTreeMap::new();
-    context_data.insert("target".to_string(), info.to_string());
-    match mdata.templater.render_template("upload.hbs", &context_data) {
+    match mdata.render_template("upload.hbs", &json!({"target": info.to_string()}), &session.csrf_token) {
         Ok(data) => Ok(HttpResponse::Ok().body(data)),
         Err(err) => {
             error!("Error in rendering the page: {}", err);
@@ -381,6 +750,15 @@ pub async fn upload_index(req: HttpRequest, mdata: web::Data<DashBoard<'_>>, inf
     }
 }
 
+fn upload_error(username: &str, err: UploadError) -> Error {
+    warn!("Upload of `{}` has failed: {}", username, err);
+    match err {
+        UploadError::TooLarge(_) => error::ErrorPayloadTooLarge(err.to_string()),
+        UploadError::OverQuota(..) => error::ErrorInsufficientStorage(err.to_string()),
+        UploadError::Io(_) => error::ErrorInternalServerError(format!("Error on writing to disk: {}", err)),
+    }
+}
+
 /// Handles the upload requests
 pub async fn uploader(req: HttpRequest, mut multipart: Multipart, mdata: web::Data<DashBoard<'_>>, info: web::Path<String>) -> Result<HttpResponse, Error> {
     let cookie = match req.cookie(AUTH_COOKIE) {
@@ -388,13 +766,15 @@ pub async fn uploader(req: HttpRequest, mut multipart: Multipart, mdata: web::Da
         None => return Err(error::ErrorUnauthorized("Unauthorized")),
     };
 
-    let user = match mdata.database.get_user_from_cookie(&cookie) {
+    let (ip, user_agent) = get_client_info(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &ip, &user_agent) {
         Ok(data) => data,
         Err(e) => {
             error!("Error in uploader at getting the user: {:?}", e);
             return Err(error::ErrorNotFound("Unauthorized"));
         }
     };
+    let user = session.username.clone();
 
     let gaccess = match mdata.database.has_access_to_group(&user, "filer_write") {
         Ok(d) => d,
@@ -407,43 +787,67 @@ pub async fn uploader(req: HttpRequest, mut multipart: Multipart, mdata: web::Da
     if !gaccess {
         return Err(error::ErrorForbidden("You are not allowed"));
     }
+    let filer = mdata.get_filer().map_err(error::ErrorNotFound)?;
 
-
+    // CSRF token must go as the first field of the form, before any file is written
+    let mut csrf_checked = false;
     while let Some(item) = multipart.next().await {
         let mut field = match item {
             Ok(f) => f,
             Err(e) => return Err(error::ErrorBadRequest(format!("Bad item: {:?}", e)))
         };
-        let file_path_string = match field.content_disposition().get_filename() {
-            Some(filename) => urlencoding::decode(filename)
-                .map_err(|_| { error::ErrorBadRequest(format!("Cannot decode filename: `{}`", filename)) })?
-                .to_string(),
+        if field.content_disposition().get_name() == Some(CSRF_FIELD) {
+            let mut token: Vec<u8> = vec![];
+            while let Some(chunk) = field.next().await {
+                let data = chunk.map_err(|e| error::ErrorBadRequest(format!("Error on getting data chunk: {:?}", e)))?;
+                token.extend_from_slice(data.as_ref());
+                if token.len() > MAX_CSRF_FIELD_LEN {
+                    return Err(error::ErrorBadRequest("CSRF token is too long"));
+                }
+            }
+            csrf_checked = is_csrf_token_valid(&session.csrf_token, &String::from_utf8_lossy(&token));
+            continue;
+        }
+        if !csrf_checked {
+            warn!("Rejected upload of `{}` from {}: invalid CSRF token", user, ip);
+            return Err(error::ErrorForbidden("Invalid CSRF token, reload the page"));
+        }
+        let filename = match field.content_disposition().get_filename() {
+            Some(filename) => safe_path::decode(filename).map_err(error::ErrorBadRequest)?,
             None => return Err(error::ErrorBadRequest("No filename in content-disposition"))
         };
-        let directory = urlencoding::decode(&info.to_string())
-            .map_err(|_| { error::ErrorBadRequest(format!("Cannot decode directory: `{}`", &info.to_string())) })?
-            .to_string();
-        let full_path = format!("{}/{}", directory, file_path_string);
 
-        if full_path.contains("..") {
-            return Err(error::ErrorForbidden("Forbidden path"));
-        }
+        // filesystem operations and compression are blocking, we have to use threadpool
+        let (filer_copy, user_copy, directory) = (filer.clone(), user.clone(), info.to_string());
+        let target = match web::block(move || filer_copy.resolve_encoded(&user_copy, &directory)
+            .and_then(|x| x.join_name(&filename))).await {
+            Ok(Ok(target)) => target,
+            Ok(Err(e)) => return Err(error::ErrorForbidden(e)),
+            Err(e) => return Err(error::ErrorInternalServerError(format!("Error on starting the upload: {}", e)))
+        };
+        let (filer_copy, user_copy) = (filer.clone(), user.clone());
+        let mut upload = match web::block(move || filer_copy.start_upload(&user_copy, &target)).await {
+            Ok(Ok(upload)) => upload,
+            Ok(Err(e)) => return Err(error::ErrorInternalServerError(format!("Error on starting the upload: {}", e))),
+            Err(e) => return Err(error::ErrorInternalServerError(format!("Error on starting the upload: {}", e)))
+        };
 
-        // Field in turn is stream of *Bytes* object
+        // Field in turn is stream of *Bytes* object, each one goes to the disk before the next is read
         while let Some(chunk) = field.next().await {
             let data = match chunk {
                 Ok(d) => d,
                 Err(e) => return Err(error::ErrorInternalServerError(format!("Error on getting data chunk: {:?}", e)))
             };
-            // filesystem operations are blocking, we have to use threadpool
-            match mdata.dispatcher.file_device.write_file(&user, &full_path, data.as_ref()) {
-                Ok(_d) => (),
-                Err(e) => return Err(error::ErrorInternalServerError(format!("Error on writing to buffer: {}", e)))
+            upload = match web::block(move || upload.write(data.as_ref()).map(|_| upload)).await {
+                Ok(Ok(upload)) => upload,
+                Ok(Err(e)) => return Err(upload_error(&user, e)),
+                Err(e) => return Err(error::ErrorInternalServerError(format!("Error on writing to disk: {}", e)))
             };
         }
 
-        match mdata.dispatcher.file_device.finish_file(&user, &full_path, &directory) {
-            Ok(_d) => (),
+        match web::block(move || upload.finish()).await {
+            Ok(Ok(_size)) => (),
+            Ok(Err(e)) => return Err(upload_error(&user, e)),
             Err(e) => return Err(error::ErrorInternalServerError(format!("Error on writing to disk: {}", e)))
         };
     }
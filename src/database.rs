@@ -5,24 +5,36 @@ extern crate rand;
 extern crate redis;
 extern crate r2d2_redis;
 
-use crate::models::{UserAdd, User, History, LineWebify, HistoryForm, StatEntry};
+use crate::models::{UserAdd, User, History, HistoryFilter, LineWebify, HistoryForm, StatEntry, Session, SessionForm, Lockout, ApiToken, ApiTokenForm, Principal,
+                    Role, RoleForm, RoleGroupForm, RoleInheritForm, RoleInfo, UserRoleForm, UserGroupForm, FilerQuota};
 use crate::schema::*;
 use crate::devices;
+use crate::config::Config;
+use crate::totp;
+use crate::stat_query::StatQuery;
+use crate::io_tools::exists;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use rand::random;
+use rand::rngs::OsRng;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
 use redis::Commands;
 use r2d2_redis::{RedisConnectionManager, r2d2 as r2d2_red};
 use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
 use diesel::result::Error as dError;
+use diesel::sqlite::Sqlite;
 use serde_json::from_str as js_from_str;
 use serde_json::to_string as js_to_str;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use secstr::SecStr;
 use rustc_serialize::hex::ToHex;
+use chrono::{Utc, NaiveDateTime, Duration as ChronoDuration};
 
 use std::collections::{HashMap, BTreeSet};
 use std::collections::btree_map::BTreeMap;
@@ -34,17 +46,120 @@ type RedisPool = r2d2_red::Pool<RedisConnectionManager>;
 
 const REDIS_USER_EXPIRE: usize = 3600 * 24;
 
+// colon can't appear in username, so these keys never clash with cached users
+const LOGIN_FAILS_PREFIX: &str = "login_fails:";
+const LOCKOUT_PREFIX: &str = "lockout:";
+const PENDING_LOGIN_PREFIX: &str = "pending_login:";
+const TOTP_LAST_STEP_PREFIX: &str = "totp_last_step:";
+const PENDING_LOGIN_EXPIRE: usize = 300;
+const RECOVERY_CODES_COUNT: usize = 10;
+/// All personal API tokens start with it, so they are easy to tell from the session cookies
+pub const API_TOKEN_PREFIX: &str = "wfy_";
+const API_TOKEN_SHOWN_LEN: usize = 12;
+const API_TOKEN_NAME_MAX_LEN: usize = 64;
+const GROUPS_CACHE_PREFIX: &str = "groups:";
+const ROLE_NAME_MAX_LEN: usize = 32;
+/// Role, which is given to the user, created with `$ webify --root`
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Roles, created together with the roles table: (name, description, parent, groups).
+/// Admin role gets all the groups of the built-in devices, groups of the other devices are given to it,
+/// when they are registered for the first time (see `Database::set_device_groups`)
+const DEFAULT_ROLES: [(&str, &str, &str, &[&str]); 3] = [
+    ("reader", "Reads files, printer queue and blog, manages own account", "",
+     &["rstatus", "filer_read", "printer_read", "printer_request", "blogdev_read", "blogdev_request", "account_read", "account_write"]),
+    ("printer_operator", "Manages files and the printer queue", "reader",
+     &["filer_write", "printer_write", "printer_confirm", "printer_dismiss"]),
+    (ADMIN_ROLE, "Has access to everything", "printer_operator", &[]),
+];
+
+/// Fills the tables after the SQL of the migration, runs in the same transaction
+type MigrationHook = fn(&mut SqliteConnection) -> Result<(), dError>;
+
+/// Versioned change of the schema. SQL is embedded into the binary, `after` fills the new tables.
+/// `schema.rs` must match the schema after the last migration
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: &'static str,
+    after: Option<MigrationHook>,
+}
+
+/// All migrations in the order of versions. Applied migrations must never be changed, add the new one instead
+const MIGRATIONS: [Migration; 4] = [
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql"), after: Some(seed_roles) },
+    Migration { version: 2, name: "filer_quotas", sql: include_str!("../migrations/0002_filer_quotas.sql"), after: None },
+    Migration { version: 3, name: "clear_payload_digests", sql: include_str!("../migrations/0003_clear_payload_digests.sql"), after: None },
+    Migration { version: 4, name: "device_groups", sql: include_str!("../migrations/0004_device_groups.sql"), after: Some(seed_device_groups) },
+];
+
+/// Columns, which were added to the tables before the migrations: (table, column, definition)
+const LEGACY_COLUMNS: [(&str, &str, &str); 11] = [
+    ("users", "totp_secret", "TEXT"),
+    ("users", "totp_enabled", "INTEGER not null DEFAULT 0"),
+    ("users", "recovery_codes", "TEXT not null DEFAULT ''"),
+    ("sessions", "csrf_token", "TEXT not null DEFAULT ''"),
+    ("history", "api_token_id", "INTEGER"),
+    ("history", "ip", "TEXT not null DEFAULT ''"),
+    ("history", "user_agent", "TEXT not null DEFAULT ''"),
+    ("history", "payload_digest", "TEXT not null DEFAULT ''"),
+    ("history", "outcome", "TEXT not null DEFAULT ''"),
+    ("history", "error", "TEXT not null DEFAULT ''"),
+    ("history", "duration_ms", "INTEGER not null DEFAULT 0"),
+];
+
+/// Limits for the failed logins, see `Config`
+#[derive(Clone)]
+struct LoginPolicy {
+    window_s: usize,
+    max_user_attempts: usize,
+    max_ip_attempts: usize,
+    lockout_user_s: usize,
+    lockout_ip_s: usize,
+}
+
 #[derive(Clone)]
 pub struct Database {
     sql_pool: SQLPool,
     redis_pool: RedisPool,
-    cookie_cache: Arc<RwLock<HashMap<String, String>>>,
-    attempts_cache: Arc<RwLock<HashMap<String, u32>>>,
+    session_idle_timeout_s: i64,
+    session_lifetime_s: i64,
+    login_policy: LoginPolicy,
     mapped_devices: Arc<RwLock<HashMap<String, String>>>,
+    history_digest_key: SecStr,
+}
+
+/// Generates hash for the password with Argon2id and random per-user salt.
+/// Result is PHC string (`$argon2id$v=19$...`), which keeps the salt and the parameters inside.
+/// All password must go through this function
+pub fn get_hash(text: &SecStr) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(text.unsecure(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| format!("Error on hashing the password: {:?}", err))
+}
+
+/// Checks password against the stored hash. Returns (is_valid, needs_rehash).
+/// Legacy hashes made by `get_legacy_hash` are still accepted, but marked for rehashing
+pub fn verify_hash(stored: &str, text: &SecStr) -> (bool, bool) {
+    if stored.starts_with("$") {
+        return match PasswordHash::new(stored) {
+            Ok(parsed) => (Argon2::default().verify_password(text.unsecure(), &parsed).is_ok(), false),
+            Err(err) => {
+                error!("Error on parsing stored password hash: {:?}", err);
+                (false, false)
+            }
+        };
+    }
+    if stored.is_empty() {
+        return (false, false);
+    }
+    let is_eq = stored == get_legacy_hash(text);
+    (is_eq, is_eq)
 }
 
-/// Generates hash for the string. All password must go through this function
-pub fn get_hash(text: &SecStr) -> String {
+/// Old static-salt iterated SHA-256. Kept only to verify databases created before Argon2id
+fn get_legacy_hash(text: &SecStr) -> String {
     const SALTY: &str = "af7rifgyurgfixf6547bzmU%^RFVYIjkszfhfzkdg64^&Izkdfh';jkkhuilyug25686hjbghfcrtyegbkhjgvjintyiiohdryujiytu";
     let initial_buff_sz = if 128 > text.unsecure().len() { 128 as usize } else { text.unsecure().len() };
     let mut buff_str = SecStr::new(vec![0; initial_buff_sz]);
@@ -76,6 +191,16 @@ pub fn get_random_token() -> String {
     get_fast_hash(&(0..32).map(|_| random::<char>()).collect::<String>())
 }
 
+/// Compares the CSRF token, sent by the client, with the one stored in the session.
+/// Comparison takes the same time regardless of the position of the first mismatch.
+/// Empty tokens are never valid
+pub fn is_csrf_token_valid(expected: &str, got: &str) -> bool {
+    if expected.is_empty() || expected.len() != got.len() {
+        return false;
+    }
+    expected.bytes().zip(got.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 fn validate_password(password: &SecStr) -> Result<(), String> {
     let sz = password.unsecure().len();
     if sz < 8 || sz > 64 {
@@ -95,56 +220,95 @@ fn validate_username(username: &str) -> Result<(), String> {
 }
 
 impl Database {
-    pub fn new(sql_conf: &str, redis_conf: &str) -> Result<Self, String> {
-        let redis_manager = RedisConnectionManager::new(redis_conf)
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let redis_manager = RedisConnectionManager::new(config.redis_cache.as_str())
             .map_err(|err| { format!("Error on creating redis manager: {:?}", err) })?;
         let redis_pool = RedisPool::builder().build(redis_manager)
             .map_err(|err| { format!("Error on creating redis pool: {:?}", err) })?;
 
-        let sql_manager = ConnectionManager::<SqliteConnection>::new(sql_conf);
+        let sql_manager = ConnectionManager::<SqliteConnection>::new(config.db_config.as_str());
         let sql_pool = SQLPool::builder().build(sql_manager)
             .map_err(|err| { format!("Error on creating sql pool: {:?}", err) })?;
 
+        {
+            let mut connection = sql_pool.get()
+                .map_err(|err| { format!("Error on upgrading the database (connection): {:?}", err) })?;
+            migrate(&mut connection)?;
+        }
+        if config.history_digest_key.is_empty() {
+            warn!("`history_digest_key` is not set in the config, payloads of the commands won't get to the history");
+        }
+
         Ok(Database {
             redis_pool,
             sql_pool,
-            cookie_cache: Arc::new(RwLock::new(HashMap::new())),
-            attempts_cache: Arc::new(RwLock::new(HashMap::new())),
+            session_idle_timeout_s: config.session_idle_timeout_s as i64,
+            session_lifetime_s: config.session_lifetime_s as i64,
+            login_policy: LoginPolicy {
+                window_s: config.login_window_s as usize,
+                max_user_attempts: config.login_max_user_attempts as usize,
+                max_ip_attempts: config.login_max_ip_attempts as usize,
+                lockout_user_s: config.lockout_user_s as usize,
+                lockout_ip_s: config.lockout_ip_s as usize,
+            },
             mapped_devices: Arc::new(RwLock::new(HashMap::new())),
+            history_digest_key: SecStr::from(config.history_digest_key.as_str()),
         })
     }
 
-    /// Returns the list of user groups
-    fn get_user_groups(&self, username: &str) -> Result<Vec<String>, String> {
+    /// Returns effective groups of the user: the groups given directly and the groups of all user's roles,
+    /// including the inherited ones
+    pub fn get_user_groups(&self, username: &str) -> Result<Vec<String>, String> {
         validate_username(username)?;
         let mut redis_conn = self.redis_pool.get()
             .map_err(|err| format!("Error on getting the redis connection get_user_groups: {:?}", err))?;
 
-        match redis_conn.deref_mut().get::<&str, String>(username) {
-            Ok(data) => match js_from_str::<User>(&data) {
-                Ok(usr_data) => return Ok(usr_data.groups.split(",").map(|x| x.to_string()).collect()),
-                Err(_) => ()
-            },
-            Err(_) => ()
-        };
+        let cache_key = format!("{}{}", GROUPS_CACHE_PREFIX, username);
+        if let Ok(data) = redis_conn.deref_mut().get::<&str, String>(&cache_key) {
+            if let Ok(groups) = js_from_str::<Vec<String>>(&data) {
+                return Ok(groups);
+            }
+        }
 
         let mut connection = self.sql_pool.get().map_err(|err| format!("Error on get_user_groups: {:?}", err))?;
 
-        let res: User = match users::table.filter(users::columns::name.eq(username))
-            .first::<User>(&mut connection) {
-            Ok(r) => r,
+        match users::table.filter(users::columns::name.eq(username)).first::<User>(&mut connection) {
+            Ok(_) => (),
             Err(e) => {
                 error!("Error on getting user devices (get_user_groups): {:?}", e);
                 return Err(format!("Error on getting user devices: {:?}", e));
             }
         };
 
-        let _ = redis_conn.deref_mut().set::<&str, String, String>(username, js_to_str(&res.get_content()).unwrap_or("".to_string()))
+        let role_ids = user_roles::table.filter(user_roles::columns::username.eq(username))
+            .select(user_roles::columns::role_id)
+            .load::<i32>(&mut connection)
+            .map_err(|err| format!("Error on loading user roles: {:?}", err))?;
+        let role_ids = expand_roles(&mut connection, &role_ids)?;
+        let mut groups: BTreeSet<String> = get_roles_groups(&mut connection, &role_ids)?;
+        groups.extend(user_groups::table.filter(user_groups::columns::username.eq(username))
+            .select(user_groups::columns::group_name)
+            .load::<String>(&mut connection)
+            .map_err(|err| format!("Error on loading user groups: {:?}", err))?);
+        let groups: Vec<String> = groups.into_iter().collect();
+
+        let _ = redis_conn.deref_mut().set::<&str, String, String>(&cache_key, js_to_str(&groups).unwrap_or("".to_string()))
             .map_err(|err| error!("Error in caching to redis: {:?}", err));
-        let _ = redis_conn.deref_mut().expire::<&str, usize>(username, REDIS_USER_EXPIRE)
+        let _ = redis_conn.deref_mut().expire::<&str, usize>(&cache_key, REDIS_USER_EXPIRE)
             .map_err(|err| error!("Error in setting expire to redis: {:?}", err));
 
-        Ok(res.groups.split(",").map(|x| x.to_string()).collect())
+        Ok(groups)
+    }
+
+    /// Drops cached groups of all users. Needed when the role changes, because it may affect anyone
+    fn flush_groups_cache(&self) -> Result<(), String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection flush_groups_cache: {:?}", err))?;
+        for user in self.get_all_users()? {
+            redis_conn.deref_mut().del::<String, ()>(format!("{}{}", GROUPS_CACHE_PREFIX, user.name))
+                .map_err(|err| format!("Error in deleting key in redis: {:?}", err))?;
+        }
+        Ok(())
     }
 
     fn delete_user_from_cache(&self, username: &str) -> Result<(), String> {
@@ -153,26 +317,66 @@ impl Database {
             .map_err(|err| format!("Error on getting the redis connection delete_user_from_cache: {:?}", err))?;
         redis_conn.deref_mut().del(username)
             .map_err(|err| format!("Error in deleting key in redis: {:?}", err))?;
+        redis_conn.deref_mut().del::<String, ()>(format!("{}{}", GROUPS_CACHE_PREFIX, username))
+            .map_err(|err| format!("Error in deleting key in redis: {:?}", err))?;
 
-        self.cookie_cache.write()
-            .map_err(|err| format!("Error in deleting user from cookies: {:?}", err))?
-            .retain(|_, val| val != username);
+        self.revoke_user_sessions(username)?;
 
-        self.attempts_cache.write()
-            .map_err(|err| format!("Error in deleting user from attempts: {:?}", err))?
-            .remove(username);
+        self.unlock_login(Some(username), None)?;
 
         Ok(())
     }
 
-    /// Identifies user with the cookie
-    pub fn get_user_from_cookie(&self, cookie: &str) -> Result<String, String> {
-        match self.cookie_cache.read()
-            .map_err(|err| format!("Error on reading cookie cache: {:?}", err))?
-            .get(cookie) {
-            Some(val) => return Ok(val.clone()),
-            None => Err("No user for cookie".to_string())
+    /// Identifies user with the cookie. Expired sessions are removed on the spot,
+    /// alive ones get their last-seen time, IP and User-Agent refreshed.
+    pub fn get_user_from_cookie(&self, cookie: &str, ip: &str, user_agent: &str) -> Result<String, String> {
+        self.get_session_from_cookie(cookie, ip, user_agent).map(|session| session.username)
+    }
+
+    /// Same as `get_user_from_cookie`, but returns the whole session, including its CSRF token
+    pub fn get_session_from_cookie(&self, cookie: &str, ip: &str, user_agent: &str) -> Result<Session, String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_session_from_cookie (connection): {:?}", err)),
+        };
+        let token = get_fast_hash(cookie);
+
+        let mut session: Session = match sessions::table.filter(sessions::columns::token.eq(&token))
+            .first::<Session>(&mut connection) {
+            Ok(d) => d,
+            Err(dError::NotFound) => return Err("No user for cookie".to_string()),
+            Err(e) => return Err(format!("Error on loading session: {:?}", e)),
+        };
+
+        let now = Utc::now().naive_utc();
+        if now - session.created_at > ChronoDuration::seconds(self.session_lifetime_s)
+            || now - session.last_seen > ChronoDuration::seconds(self.session_idle_timeout_s) {
+            diesel::delete(sessions::table.filter(sessions::columns::id.eq(session.id)))
+                .execute(&mut connection)
+                .map_err(|err| format!("Error on removing expired session: {:?}", err))?;
+            return Err("Session has expired".to_string());
         }
+
+        diesel::update(sessions::table.filter(sessions::columns::id.eq(session.id)))
+            .set((sessions::columns::last_seen.eq(now),
+                  sessions::columns::ip.eq(ip),
+                  sessions::columns::user_agent.eq(user_agent)))
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on refreshing session: {:?}", err))?;
+
+        // sessions, created before the CSRF tokens, get the token on their next request
+        if session.csrf_token.is_empty() {
+            session.csrf_token = get_random_token();
+            diesel::update(sessions::table.filter(sessions::columns::id.eq(session.id)))
+                .set(sessions::columns::csrf_token.eq(&session.csrf_token))
+                .execute(&mut connection)
+                .map_err(|err| format!("Error on setting the CSRF token of the session: {:?}", err))?;
+        }
+
+        Ok(session)
     }
 
     /// Returns the vector of all devices which are allowed for use by user
@@ -207,23 +411,101 @@ impl Database {
         Ok(users)
     }
 
-    /// Returns vector of all history records as they are represented in the database
-    pub fn get_all_history(&self) -> Result<Vec<History>, String> {
-        let mut connection = match self.sql_pool.get() {
-            Ok(conn) => {
-                debug!("Got connection");
-                conn
-            }
-            Err(err) => return Err(format!("Error on get_all_history (connection): {:?}", err)),
+    /// Returns the page of the history, which matches the filter, and the number of all the matching entries
+    pub fn get_history_page(&self, filter: &HistoryFilter) -> Result<(Vec<History>, i64), String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_history_page (connection): {:?}", err))?;
+
+        let total: i64 = filter_history(filter)?.count().get_result(&mut connection)
+            .map_err(|err| format!("Error on counting history: {:?}", err))?;
+
+        let query = filter_history(filter)?;
+        let ascending = filter.is_ascending();
+        let query = match (filter.sort.as_str(), ascending) {
+            ("timestamp", true) => query.order(history::columns::timestamp.asc()),
+            ("timestamp", false) => query.order(history::columns::timestamp.desc()),
+            ("username", true) => query.order(history::columns::username.asc()),
+            ("username", false) => query.order(history::columns::username.desc()),
+            ("device", true) => query.order(history::columns::device.asc()),
+            ("device", false) => query.order(history::columns::device.desc()),
+            ("command", true) => query.order(history::columns::command.asc()),
+            ("command", false) => query.order(history::columns::command.desc()),
+            ("duration_ms", true) => query.order(history::columns::duration_ms.asc()),
+            ("duration_ms", false) => query.order(history::columns::duration_ms.desc()),
+            (_, true) => query.order(history::columns::id.asc()),
+            (_, false) => query.order(history::columns::id.desc()),
+        };
+        let query = if ascending {
+            query.then_order_by(history::columns::id.asc())
+        } else {
+            query.then_order_by(history::columns::id.desc())
         };
 
+        let per_page = filter.get_per_page();
+        let hist: Vec<History> = query.limit(per_page).offset((filter.get_page() - 1).saturating_mul(per_page))
+            .load::<History>(&mut connection)
+            .map_err(|err| format!("Error on loading history: {:?}", err))?;
+        Ok((hist, total))
+    }
 
-        let hist: Vec<History> = match history::table.order(history::columns::id.desc()).limit(100).load::<History>(&mut connection) {
-            Ok(d) => d,
-            Err(e) => return Err(format!("Error on loading history: {:?}", e)),
-        };
+    /// Returns up to `limit` entries, which match the filter and go after `after_id`. Entries are ordered by id
+    /// (descending, unless the filter asks for `asc`), so the export can walk the whole history by pieces
+    pub fn get_history_chunk(&self, filter: &HistoryFilter, after_id: Option<i32>, limit: i64) -> Result<Vec<History>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_history_chunk (connection): {:?}", err))?;
 
-        Ok(hist)
+        let mut query = filter_history(filter)?;
+        if filter.is_ascending() {
+            if let Some(id) = after_id {
+                query = query.filter(history::columns::id.gt(id));
+            }
+            query = query.order(history::columns::id.asc());
+        } else {
+            if let Some(id) = after_id {
+                query = query.filter(history::columns::id.lt(id));
+            }
+            query = query.order(history::columns::id.desc());
+        }
+        query.limit(limit).load::<History>(&mut connection)
+            .map_err(|err| format!("Error on loading history: {:?}", err))
+    }
+
+    /// Returns up to `limit` entries older than `before`, which go after `after_id`, ordered by id.
+    /// Used by the retention job to archive the history by pieces
+    pub fn get_history_before(&self, before: NaiveDateTime, after_id: i32, limit: i64) -> Result<Vec<History>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_history_before (connection): {:?}", err))?;
+
+        history::table
+            .filter(history::columns::timestamp.lt(before))
+            .filter(history::columns::id.gt(after_id))
+            .order(history::columns::id.asc())
+            .limit(limit)
+            .load::<History>(&mut connection)
+            .map_err(|err| format!("Error on loading history: {:?}", err))
+    }
+
+    /// Adds the entries older than `before` with id up to `last_id` to the daily aggregates in `history_daily`
+    /// and removes them from `history`. Both happen in the same transaction. Returns number of removed entries
+    pub fn roll_up_history(&self, before: NaiveDateTime, last_id: i32) -> Result<usize, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on roll_up_history (connection): {:?}", err))?;
+
+        connection.transaction::<_, dError, _>(|conn| {
+            diesel::sql_query("INSERT INTO history_daily (day, username, device, command, qtype, rejected, outcome, counter)
+                SELECT date(timestamp), username, device, command, qtype, rejected, outcome, COUNT(*) FROM history
+                WHERE timestamp < ? AND id <= ?
+                GROUP BY date(timestamp), username, device, command, qtype, rejected, outcome
+                ON CONFLICT (day, username, device, command, qtype, rejected, outcome)
+                DO UPDATE SET counter = counter + excluded.counter;")
+                .bind::<diesel::sql_types::Timestamp, _>(before)
+                .bind::<diesel::sql_types::Integer, _>(last_id)
+                .execute(conn)?;
+            diesel::delete(history::table
+                .filter(history::columns::timestamp.lt(before))
+                .filter(history::columns::id.le(last_id)))
+                .execute(conn)
+        }).map_err(|err| format!("Error on roll_up_history: {:?}", err))
     }
 
     /// Returns vector of all groups as they are represented in the database
@@ -235,12 +517,14 @@ impl Database {
 
     /// Inserts new user to the database, cookies are not assigned yet
     pub fn insert_user(&self, username: &str, password: &SecStr, groups: Option<&str>) -> Result<(), String> {
-        insert_user(&self.sql_pool, username, password, groups)
+        let groups = split_list(groups.unwrap_or(""));
+        check_groups(&groups, &self.get_known_groups()?)?;
+        insert_user_entry(&self.sql_pool, username, password, &groups)
     }
 
-    /// Inserts new group to the databse
-    pub fn insert_history(&self, username: &str, device: &str, command: &str, qtype: &str, rejected: i32) -> Result<(), String> {
-        validate_username(username)?;
+    /// Writes the command, its outcome and the client to the history
+    pub fn insert_history(&self, entry: &HistoryForm) -> Result<(), String> {
+        validate_username(entry.username)?;
         let mut connection = match self.sql_pool.get() {
             Ok(conn) => {
                 debug!("Got connection");
@@ -249,13 +533,11 @@ impl Database {
             Err(err) => return Err(format!("Error on insert_history (connection): {:?}", err)),
         };
 
-        let entry = HistoryForm { username, device, command, qtype, rejected };
-
         match diesel::insert_into(history::table)
             .values(entry)
             .execute(&mut connection) {
             Ok(_) => Ok(()),
-            Err(err) => Err(format!("Error on update_user_pass (update): {:?}", err))
+            Err(err) => Err(format!("Error on insert_history (insert): {:?}", err))
         }
     }
 
@@ -273,14 +555,35 @@ impl Database {
         self.delete_user_from_cache(username)?;
 
         match diesel::update(users::table.filter(users::columns::name.eq(username)))
-            .set(users::columns::password.eq(get_hash(password)))
+            .set(users::columns::password.eq(get_hash(password)?))
             .execute(&mut connection) {
             Ok(_) => Ok(()),
             Err(err) => Err(format!("Error on update_user_pass (update): {:?}", err))
         }
     }
 
-    /// Updates password for the user to the databse
+    /// Replaces legacy hash with the modern one without touching the sessions of the user
+    fn rehash_user_pass(&self, username: &str, password: &SecStr) -> Result<(), String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on rehash_user_pass (connection): {:?}", err)),
+        };
+
+        diesel::update(users::table.filter(users::columns::name.eq(username)))
+            .set(users::columns::password.eq(get_hash(password)?))
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on rehash_user_pass (update): {:?}", err))?;
+
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection rehash_user_pass: {:?}", err))?;
+        redis_conn.deref_mut().del(username)
+            .map_err(|err| format!("Error in deleting key in redis: {:?}", err))
+    }
+
+    /// Bans the users: clears their passwords, so they can't log in, and revokes their API tokens
     pub fn update_users_ban(&self, usernames: &Vec<String>) -> Result<(), String> {
         let mut connection = match self.sql_pool.get() {
             Ok(conn) => {
@@ -295,17 +598,25 @@ impl Database {
             self.delete_user_from_cache(username)?;
         }
 
-        match diesel::update(users::table.filter(users::columns::name.eq_any(usernames)))
-            .set(users::columns::password.eq(""))
-            .execute(&mut connection) {
+        match connection.transaction::<_, dError, _>(|conn| {
+            diesel::update(users::table.filter(users::columns::name.eq_any(usernames)))
+                .set(users::columns::password.eq(""))
+                .execute(conn)?;
+            diesel::delete(api_tokens::table.filter(api_tokens::columns::username.eq_any(usernames)))
+                .execute(conn)?;
+            Ok(())
+        }) {
             Ok(_) => Ok(()),
             Err(err) => Err(format!("Error on update_user_ban (update): {:?}", err))
         }
     }
 
-    /// Writes groups for the user to the database
+    /// Replaces the groups, which are given to the user directly (not through the roles).
+    /// `group` is comma-separated list of the groups
     pub fn update_user_group(&self, username: &str, group: &str) -> Result<(), String> {
         validate_username(username)?;
+        let groups = split_list(group);
+        check_groups(&groups, &self.get_known_groups()?)?;
         let mut connection = match self.sql_pool.get() {
             Ok(conn) => {
                 debug!("Got connection");
@@ -315,247 +626,1516 @@ impl Database {
         };
 
         self.delete_user_from_cache(username)?;
-        match diesel::update(users::table.filter(users::columns::name.eq(username)))
-            .set(users::columns::groups.eq(group))
-            .execute(&mut connection) {
+        match connection.transaction::<_, dError, _>(|conn| {
+            diesel::delete(user_groups::table.filter(user_groups::columns::username.eq(username)))
+                .execute(conn)?;
+            for group_name in groups.iter() {
+                diesel::insert_into(user_groups::table)
+                    .values(UserGroupForm { username, group_name })
+                    .execute(conn)?;
+            }
+            Ok(())
+        }) {
             Ok(_) => Ok(()),
             Err(err) => Err(format!("Error on update_user_group (update): {:?}", err))
         }
     }
 
-    /// Writes cookies for the user to the database
-    pub fn assign_cookie(&self, username: &str, cookie: &str) -> Result<(), String> {
+    /// Returns the groups, which are given to the user directly
+    pub fn get_user_direct_groups(&self, username: &str) -> Result<Vec<String>, String> {
         validate_username(username)?;
-        self.cookie_cache.write()
-            .map_err(|err| format!("Error on writing to cookie cache: {:?}", err))?
-            .insert(cookie.to_string(), username.to_string());
-        Ok(())
-    }
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_user_direct_groups (connection): {:?}", err)),
+        };
 
-    /// Removes cookie from database, making it impossible to log in
-    pub fn remove_cookie(&self, cookie: &str) -> Result<(), String> {
-        self.cookie_cache.write()
-            .map_err(|err| format!("Error on removing cookie from cache: {:?}", err))?
-            .remove(cookie);
-        Ok(())
+        user_groups::table.filter(user_groups::columns::username.eq(username))
+            .select(user_groups::columns::group_name)
+            .order(user_groups::columns::group_name.asc())
+            .load::<String>(&mut connection)
+            .map_err(|err| format!("Error on loading user groups: {:?}", err))
     }
 
-    /// Returns whether user has access to the group
-    pub fn has_access_to_group(&self, username: &str, group_name: &str) -> Result<bool, String> {
+    /// Returns names of the roles, which are assigned to the user
+    pub fn get_user_roles(&self, username: &str) -> Result<Vec<String>, String> {
         validate_username(username)?;
-        let user_groups = match self.get_user_groups(username) {
-            Ok(data) => data,
-            Err(err) => return Err(format!("Error on has_access on getting user groups: {:?}", err))
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_user_roles (connection): {:?}", err)),
         };
 
-        if user_groups.contains(&group_name.to_string()) {
-            return Ok(true);
-        }
-        Ok(false)
+        roles::table.inner_join(user_roles::table.on(user_roles::columns::role_id.eq(roles::columns::id)))
+            .filter(user_roles::columns::username.eq(username))
+            .select(roles::columns::name)
+            .order(roles::columns::name.asc())
+            .load::<String>(&mut connection)
+            .map_err(|err| format!("Error on loading user roles: {:?}", err))
     }
 
-    /// Returns whether user has access to the device, or not
-    pub fn has_access_to_device(&self, username: &str, device: &str) -> Result<bool, String> {
-        validate_username(username)?;
-        let user_dev = match self.get_user_devices(username) {
-            Ok(data) => data,
-            Err(err) => return Err(format!("Error on has_access on getting user devices: {:?}", err))
-        };
-
-        if user_dev.contains(&device.to_string()) {
-            return Ok(true);
-        }
-        Ok(false)
+    /// Assigns the role to the user
+    pub fn assign_role(&self, username: &str, role: &str) -> Result<(), String> {
+        assign_user_role(&self.sql_pool, username, role)?;
+        self.flush_groups_cache()
     }
 
-    /// Returns whether the user has correct credentials or not.
-    /// Also manages the counter of unsuccessful logins. Currently it is allowed to make 10 wrong
-    /// attempts for user before blocking the account. Every successful login resets the counter to 0.
-    ///
-    /// # Example
-    /// ```rust
-    /// use webify::database::{get_connection, validate_user};
-    /// let conns = get_connection(&"database.db".to_string()).unwrap();
-    /// assert!(validate_user(&conns, "thomasfire", "bestpasswort").unwrap());
-    /// assert_ne!(validate_user(&conns, "eva", "badpasswort").unwrap());
-    /// ```
-    pub fn validate_user(&self, username: &str, password: &SecStr) -> Result<bool, String> {
-        validate_password(password)?;
+    /// Takes the role away from the user
+    pub fn revoke_role(&self, username: &str, role: &str) -> Result<(), String> {
         validate_username(username)?;
-        let mut redis_conn = self.redis_pool.get()
-            .map_err(|err| format!("Error on getting the redis connection get_user_groups: {:?}", err))?;
-
-        let mut user: Option<User> = match redis_conn.deref_mut().get::<&str, String>(username) {
-            Ok(data) => match js_from_str::<User>(&data) {
-                Ok(usr_data) => Some(usr_data),
-                Err(_) => None
-            },
-            Err(_) => None
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on revoke_role (connection): {:?}", err)),
         };
 
-        if user.is_none() {
-            let mut connection = match self.sql_pool.get() {
-                Ok(conn) => {
-                    debug!("Got connection");
-                    conn
-                }
-                Err(err) => return Err(format!("Error on validate_user: {:?}", err)),
-            };
-
-            user = match users::table.filter(users::columns::name.eq(username))
-                .first::<User>(&mut connection) {
-                Ok(d) => Some(d),
-                Err(e) => {
-                    if e == dError::NotFound {
-                        return Ok(false);
-                    }
-                    return Err(format!("Error on validating user: {:?}", e));
-                }
-            };
-        }
+        let role_id = get_role_id(&mut connection, role)?;
+        match diesel::delete(user_roles::table.filter(user_roles::columns::username.eq(username)
+            .and(user_roles::columns::role_id.eq(role_id))))
+            .execute(&mut connection) {
+            Ok(0) => return Err(format!("User `{}` doesn't have role `{}`", username, role)),
+            Ok(_) => (),
+            Err(err) => return Err(format!("Error on revoke_role (delete): {:?}", err))
+        };
+        self.flush_groups_cache()
+    }
 
-        let got_user = match user {
-            Some(d) => d,
-            None => return Err(format!("Error no user: `{}`", username))
+    /// Returns all roles with their own, inherited and effective groups
+    pub fn get_all_roles(&self) -> Result<Vec<RoleInfo>, String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_all_roles (connection): {:?}", err)),
         };
 
-        {
-            let _ = redis_conn.deref_mut().set::<&str, String, String>(username, js_to_str(&got_user.get_content()).unwrap_or("".to_string()))
-                .map_err(|err| error!("Error in caching to redis: {:?}", err));
-            let _ = redis_conn.deref_mut().expire::<&str, usize>(username, REDIS_USER_EXPIRE)
-                .map_err(|err| error!("Error in setting expire to redis: {:?}", err));
+        let all_roles = roles::table.order(roles::columns::name.asc())
+            .load::<Role>(&mut connection)
+            .map_err(|err| format!("Error on loading roles: {:?}", err))?;
+        let names: HashMap<i32, String> = all_roles.iter().map(|x| (x.id, x.name.clone())).collect();
+
+        let mut result: Vec<RoleInfo> = vec![];
+        for role in all_roles {
+            let parents = role_inherits::table.filter(role_inherits::columns::role_id.eq(role.id))
+                .select(role_inherits::columns::parent_id)
+                .load::<i32>(&mut connection)
+                .map_err(|err| format!("Error on loading role parents: {:?}", err))?;
+            let ancestors = expand_roles(&mut connection, &[role.id])?;
+            result.push(RoleInfo {
+                groups: get_roles_groups(&mut connection, &[role.id])?.into_iter().collect(),
+                inherits: parents.iter().filter_map(|x| names.get(x).cloned()).collect(),
+                effective: get_roles_groups(&mut connection, &ancestors)?.into_iter().collect(),
+                name: role.name,
+                description: role.description,
+            });
         }
+        Ok(result)
+    }
 
-        let wrongs = match self.attempts_cache.read()
-            .map_err(|err| format!("Error on reading attempts: {:?}", err))?
-            .get(username) {
-            Some(d) => d.clone(),
-            None => 0,
+    /// Creates new role. `groups` and `inherits` are comma-separated lists of groups and parent roles
+    pub fn create_role(&self, name: &str, description: &str, groups: &str, inherits: &str) -> Result<(), String> {
+        validate_role_name(name)?;
+        let groups = split_list(groups);
+        check_groups(&groups, &self.get_known_groups()?)?;
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on create_role (connection): {:?}", err)),
         };
 
-        if wrongs >= 10 {
-            return Ok(false);
+        if get_role_id(&mut connection, name).is_ok() {
+            return Err(format!("Role `{}` already exists", name));
         }
+        let parents = split_list(inherits).iter()
+            .map(|x| get_role_id(&mut connection, x))
+            .collect::<Result<Vec<i32>, String>>()?;
+
+        connection.transaction::<_, dError, _>(|conn| {
+            let role_id = insert_role(conn, name, description)?;
+            set_role_links(conn, role_id, Some(&groups), Some(&parents))
+        }).map_err(|err| format!("Error on create_role (insert): {:?}", err))
+    }
 
-        let is_password_eq = got_user.password == get_hash(password);
+    /// Changes the role. Only the given parts are replaced: `groups` and `inherits` are comma-separated lists
+    /// of groups and parent roles. Role can't inherit itself, even through other roles
+    pub fn update_role(&self, name: &str, description: Option<&str>, groups: Option<&str>, inherits: Option<&str>) -> Result<(), String> {
+        let groups = groups.map(split_list);
+        if let Some(data) = &groups {
+            check_groups(data, &self.get_known_groups()?)?;
+        }
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on update_role (connection): {:?}", err)),
+        };
 
-        self.attempts_cache.write()
-            .map_err(|err| format!("Error on writing attempts: {:?}", err))?
-            .insert(username.to_string(), if is_password_eq { 0 } else { wrongs + 1 });
-        Ok(is_password_eq)
-    }
+        let role_id = get_role_id(&mut connection, name)?;
+        let parents = match inherits {
+            Some(data) => {
+                let parents = split_list(data).iter()
+                    .map(|x| get_role_id(&mut connection, x))
+                    .collect::<Result<Vec<i32>, String>>()?;
+                if expand_roles(&mut connection, &parents)?.contains(&role_id) {
+                    return Err(format!("Role `{}` can't inherit itself", name));
+                }
+                Some(parents)
+            }
+            None => None
+        };
 
-    /// Returns the devices map by their group name : {group_name: device_name};
-    ///
-    /// # Examples
-    /// ```rust
-    /// use webify::database::{get_connection, on_init};
-    /// let conns = get_connection(&"database.db".to_string()).unwrap();
-    /// let device_by_group = on_init(&conns).unwrap();
-    /// assert_eq!(device_by_group.get("filer_read"), "filer");
-    /// ```
-    pub fn devices_reload(&self) -> Result<(), String> {
-        let mut devices_map = self.mapped_devices.write()
-            .map_err(|err| format!("Error on writing mapped device: {:?}", err))?;
-        for dev_i in 0..devices::DEVICES_LEN {
-            for group_name_o in devices::DEV_GROUPS[dev_i] {
-                match group_name_o {
-                    Some(group_name) => devices_map.insert(group_name.to_string(), devices::DEV_NAMES[dev_i].to_string()),
-                    None => continue,
-                };
+        connection.transaction::<_, dError, _>(|conn| {
+            if let Some(data) = description {
+                diesel::update(roles::table.filter(roles::columns::id.eq(role_id)))
+                    .set(roles::columns::description.eq(data))
+                    .execute(conn)?;
             }
-        }
-        Ok(())
+            set_role_links(conn, role_id, groups.as_ref(), parents.as_ref())
+        }).map_err(|err| format!("Error on update_role (update): {:?}", err))?;
+        self.flush_groups_cache()
     }
 
-    pub fn load_stats_by_query(&self, query: &str) -> Result<Vec<StatEntry>, String> {
+    /// Removes the role, users lose it, and roles, which inherit it, lose its groups
+    pub fn delete_role(&self, name: &str) -> Result<(), String> {
         let mut connection = match self.sql_pool.get() {
             Ok(conn) => {
                 debug!("Got connection");
                 conn
             }
-            Err(err) => return Err(format!("Error on load_stats_by_query (connection): {:?}", err)),
+            Err(err) => return Err(format!("Error on delete_role (connection): {:?}", err)),
         };
 
-        let hist: Vec<StatEntry> = diesel::sql_query(query)
-            .load(&mut connection)
-            .expect("Query failed");
+        let role_id = get_role_id(&mut connection, name)?;
+        connection.transaction::<_, dError, _>(|conn| {
+            diesel::delete(user_roles::table.filter(user_roles::columns::role_id.eq(role_id))).execute(conn)?;
+            diesel::delete(role_groups::table.filter(role_groups::columns::role_id.eq(role_id))).execute(conn)?;
+            diesel::delete(role_inherits::table.filter(role_inherits::columns::role_id.eq(role_id)
+                .or(role_inherits::columns::parent_id.eq(role_id)))).execute(conn)?;
+            diesel::delete(roles::table.filter(roles::columns::id.eq(role_id))).execute(conn)?;
+            Ok(())
+        }).map_err(|err| format!("Error on delete_role (delete): {:?}", err))?;
+        self.flush_groups_cache()
+    }
 
-        Ok(hist)
+    /// Returns all the filer quotas, the users' ones first
+    pub fn get_filer_quotas(&self) -> Result<Vec<FilerQuota>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_filer_quotas (connection): {:?}", err))?;
+        filer_quotas::table.order((filer_quotas::columns::kind.desc(), filer_quotas::columns::name.asc()))
+            .load::<FilerQuota>(&mut connection)
+            .map_err(|err| format!("Error on loading filer quotas: {:?}", err))
     }
-}
 
+    /// Sets the filer quota of the user or the group (`kind` is `user` or `group`), 0 is unlimited
+    pub fn set_filer_quota(&self, kind: &str, name: &str, limit_bytes: i64) -> Result<(), String> {
+        match kind {
+            "user" => {
+                validate_username(name)?;
+                if !self.user_exists(name)? {
+                    return Err(format!("User `{}` doesn't exist", name));
+                }
+            }
+            "group" => check_groups(&[name.to_string()], &self.get_known_groups()?)?,
+            _ => return Err(format!("Unknown kind of the quota: `{}`", kind))
+        };
+        if limit_bytes < 0 {
+            return Err("Quota can't be negative".to_string());
+        }
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on set_filer_quota (connection): {:?}", err))?;
+        diesel::replace_into(filer_quotas::table)
+            .values(&FilerQuota { kind: kind.to_string(), name: name.to_string(), limit_bytes })
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on set_filer_quota (insert): {:?}", err))?;
+        Ok(())
+    }
 
-pub fn insert_user(pool: &SQLPool, username: &str, password: &SecStr, groups: Option<&str>) -> Result<(), String> {
-    validate_password(password)?;
-    validate_username(username)?;
-    let mut connection = match pool.get() {
-        Ok(conn) => {
-            debug!("Got connection");
-            conn
+    /// Removes the filer quota of the user or the group
+    pub fn remove_filer_quota(&self, kind: &str, name: &str) -> Result<(), String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on remove_filer_quota (connection): {:?}", err))?;
+        match diesel::delete(filer_quotas::table.filter(filer_quotas::columns::kind.eq(kind)
+            .and(filer_quotas::columns::name.eq(name))))
+            .execute(&mut connection) {
+            Ok(0) => Err(format!("No quota for {} `{}`", kind, name)),
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error on remove_filer_quota (delete): {:?}", err))
         }
-        Err(err) => return Err(format!("Error on insert_user (connection): {:?}", err)),
-    };
+    }
 
-    let gs = match groups {
-        Some(d) => d,
-        None => ""
-    };
-    let new_user = UserAdd {
-        name: username,
-        password: &get_hash(password),
-        groups: gs,
-    };
+    /// Returns the filer quota of the user in bytes, `None` if neither the user nor the user's groups have it.
+    /// Quota of the user wins, otherwise the biggest quota of the user's groups is taken
+    pub fn get_filer_quota(&self, username: &str) -> Result<Option<i64>, String> {
+        let groups = self.get_user_groups(username)?;
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_filer_quota (connection): {:?}", err))?;
+        let own = filer_quotas::table.filter(filer_quotas::columns::kind.eq("user")
+            .and(filer_quotas::columns::name.eq(username)))
+            .select(filer_quotas::columns::limit_bytes)
+            .first::<i64>(&mut connection)
+            .optional()
+            .map_err(|err| format!("Error on loading the quota of the user: {:?}", err))?;
+        if own.is_some() {
+            return Ok(own);
+        }
+        let by_groups = filer_quotas::table.filter(filer_quotas::columns::kind.eq("group")
+            .and(filer_quotas::columns::name.eq_any(&groups)))
+            .select(filer_quotas::columns::limit_bytes)
+            .load::<i64>(&mut connection)
+            .map_err(|err| format!("Error on loading the quotas of the groups: {:?}", err))?;
+        // 0 is unlimited, so it beats any other limit
+        Ok(by_groups.into_iter().max_by_key(|x| if *x == 0 { i64::MAX } else { *x }))
+    }
 
-    match diesel::insert_into(users::table)
-        .values(&new_user)
-        .execute(&mut connection) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(format!("Error on inserting users (insert): {:?}", err))
+    /// Returns the compressed size of the user's files, `None` if it hasn't been counted yet
+    pub fn get_filer_usage(&self, username: &str) -> Result<Option<i64>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_filer_usage (connection): {:?}", err))?;
+        filer_usage::table.filter(filer_usage::columns::username.eq(username))
+            .select(filer_usage::columns::used_bytes)
+            .first::<i64>(&mut connection)
+            .optional()
+            .map_err(|err| format!("Error on loading the filer usage: {:?}", err))
     }
-}
 
-/// Returns the connection pool by the path to the database
-///
-/// # Example
-/// ```rust
-/// use webify::database::get_connection;
-/// let connections = get_connection(&"database.db".to_string()).unwrap();
-/// ```
-pub fn get_connection(db_config: &String) -> Result<SQLPool, String> {
-    let manager = ConnectionManager::<SqliteConnection>::new(db_config);
-    match SQLPool::builder().build(manager) {
-        Ok(pool) => Ok(pool),
-        Err(err) => Err(format!("Error on getting connection to DB: {:?}", err))
+    /// Returns the counted usage of all users
+    pub fn get_all_filer_usage(&self) -> Result<Vec<(String, i64)>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on get_all_filer_usage (connection): {:?}", err))?;
+        filer_usage::table.order(filer_usage::columns::username.asc())
+            .load::<(String, i64)>(&mut connection)
+            .map_err(|err| format!("Error on loading the filer usage: {:?}", err))
     }
-}
 
-/// Writes initial data to the database: tables and list of groups
-///
-/// # Example
-/// ```rust
-/// use webify::database::init_db;
-/// init_db(&"database.db".to_string()).unwrap();
-/// ```
-pub fn init_db(db_config: &String) -> Result<(), String> {
-    let r_pool = get_connection(db_config);
-    let pool = match r_pool {
+    /// Stores the counted usage of the user, see `add_filer_usage`
+    pub fn set_filer_usage(&self, username: &str, used_bytes: i64) -> Result<(), String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on set_filer_usage (connection): {:?}", err))?;
+        diesel::replace_into(filer_usage::table)
+            .values((filer_usage::columns::username.eq(username), filer_usage::columns::used_bytes.eq(used_bytes)))
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on set_filer_usage (insert): {:?}", err))?;
+        Ok(())
+    }
+
+    /// Changes the usage of the user by `delta` bytes. Usage, which hasn't been counted yet, stays uncounted
+    pub fn add_filer_usage(&self, username: &str, delta: i64) -> Result<(), String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on add_filer_usage (connection): {:?}", err))?;
+        diesel::sql_query("UPDATE filer_usage SET used_bytes = max(used_bytes + ?, 0) WHERE username = ?")
+            .bind::<diesel::sql_types::BigInt, _>(delta)
+            .bind::<diesel::sql_types::Text, _>(username)
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on add_filer_usage (update): {:?}", err))?;
+        Ok(())
+    }
+
+    /// Changes the usage of the user by `delta` bytes, only if it stays within `quota` (growing usage) or shrinks.
+    /// It is checked and changed by one statement, so concurrent uploads can't exceed the quota together.
+    /// Returns `None`, if the usage hasn't been counted yet, otherwise whether it has been changed
+    pub fn add_filer_usage_within(&self, username: &str, delta: i64, quota: i64) -> Result<Option<bool>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on add_filer_usage_within (connection): {:?}", err))?;
+        let changed = diesel::sql_query("UPDATE filer_usage SET used_bytes = max(used_bytes + ?, 0) \
+                                         WHERE username = ? AND (? <= 0 OR used_bytes + ? <= ?)")
+            .bind::<diesel::sql_types::BigInt, _>(delta)
+            .bind::<diesel::sql_types::Text, _>(username)
+            .bind::<diesel::sql_types::BigInt, _>(delta)
+            .bind::<diesel::sql_types::BigInt, _>(delta)
+            .bind::<diesel::sql_types::BigInt, _>(quota)
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on add_filer_usage_within (update): {:?}", err))?;
+        if changed > 0 {
+            return Ok(Some(true));
+        }
+        Ok(self.get_filer_usage(username)?.map(|_| false))
+    }
+
+    /// Forgets the usage of the user, so it is counted from the disk again
+    pub fn reset_filer_usage(&self, username: &str) -> Result<(), String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on reset_filer_usage (connection): {:?}", err))?;
+        diesel::delete(filer_usage::table.filter(filer_usage::columns::username.eq(username)))
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on reset_filer_usage (delete): {:?}", err))?;
+        Ok(())
+    }
+
+    /// Writes cookies for the user to the database, starting new session
+    pub fn assign_cookie(&self, username: &str, cookie: &str, ip: &str, user_agent: &str) -> Result<(), String> {
+        validate_username(username)?;
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on assign_cookie (connection): {:?}", err)),
+        };
+
+        let now = Utc::now().naive_utc();
+        let entry = SessionForm {
+            token: &get_fast_hash(cookie),
+            username,
+            created_at: now,
+            last_seen: now,
+            ip,
+            user_agent,
+            csrf_token: &get_random_token(),
+        };
+
+        match diesel::insert_into(sessions::table)
+            .values(entry)
+            .execute(&mut connection) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error on assign_cookie (insert): {:?}", err))
+        }
+    }
+
+    /// Removes cookie from database, making it impossible to log in
+    pub fn remove_cookie(&self, cookie: &str) -> Result<(), String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on remove_cookie (connection): {:?}", err)),
+        };
+
+        match diesel::delete(sessions::table.filter(sessions::columns::token.eq(get_fast_hash(cookie))))
+            .execute(&mut connection) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error on remove_cookie (delete): {:?}", err))
+        }
+    }
+
+    /// Removes all sessions of the user, logging the user out everywhere
+    pub fn revoke_user_sessions(&self, username: &str) -> Result<(), String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on revoke_user_sessions (connection): {:?}", err)),
+        };
+
+        match diesel::delete(sessions::table.filter(sessions::columns::username.eq(username)))
+            .execute(&mut connection) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error on revoke_user_sessions (delete): {:?}", err))
+        }
+    }
+
+    /// Removes all sessions which are idle or live for too long. Returns number of removed sessions
+    pub fn sweep_sessions(&self) -> Result<usize, String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on sweep_sessions (connection): {:?}", err)),
+        };
+
+        let now = Utc::now().naive_utc();
+        let created_border = now - ChronoDuration::seconds(self.session_lifetime_s);
+        let seen_border = now - ChronoDuration::seconds(self.session_idle_timeout_s);
+
+        diesel::delete(sessions::table.filter(sessions::columns::created_at.lt(created_border)
+            .or(sessions::columns::last_seen.lt(seen_border))))
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on sweep_sessions (delete): {:?}", err))
+    }
+
+    /// Whether the user exists, banned users as well
+    fn user_exists(&self, username: &str) -> Result<bool, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on user_exists (connection): {:?}", err))?;
+        users::table.filter(users::columns::name.eq(username))
+            .count()
+            .get_result::<i64>(&mut connection)
+            .map(|x| x > 0)
+            .map_err(|err| format!("Error on loading user: {:?}", err))
+    }
+
+    /// Returns the user with the name, if the user exists and isn't banned.
+    /// Used to map the client certificate to the user
+    pub fn find_user_by_cert_name(&self, name: &str) -> Result<Option<String>, String> {
+        if validate_username(name).is_err() {
+            return Ok(None);
+        }
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on find_user_by_cert_name (connection): {:?}", err)),
+        };
+
+        match users::table.filter(users::columns::name.eq(name)).first::<User>(&mut connection) {
+            Ok(user) if !user.password.is_empty() => Ok(Some(user.name)),
+            Ok(_) | Err(dError::NotFound) => Ok(None),
+            Err(e) => Err(format!("Error on loading user: {:?}", e)),
+        }
+    }
+
+    /// Creates personal API token for the user of the principal. Token is limited to `groups` (comma-separated),
+    /// which must be the groups of the user and in the scope of the principal, so a token can't create
+    /// a wider one. `expires_days` equal to 0 means the token never expires.
+    /// Returns the token itself, it is shown to the user only once
+    pub fn create_api_token(&self, principal: &Principal, name: &str, groups: &str, expires_days: i64) -> Result<String, String> {
+        let username = principal.username.as_str();
+        validate_username(username)?;
+        let name = name.trim();
+        if name.is_empty() || name.len() > API_TOKEN_NAME_MAX_LEN {
+            return Err(format!("Name of the token should be from 1 to {} symbols", API_TOKEN_NAME_MAX_LEN));
+        }
+        if expires_days < 0 {
+            return Err("Expiry can't be negative".to_string());
+        }
+
+        let scope = get_token_scope(principal, &self.get_user_groups(username)?, &self.get_known_groups()?, groups)?;
+
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on create_api_token (connection): {:?}", err)),
+        };
+
+        let token = format!("{}{}", API_TOKEN_PREFIX, get_random_token());
+        let now = Utc::now().naive_utc();
+        let entry = ApiTokenForm {
+            token: &get_fast_hash(&token),
+            prefix: &token[0..API_TOKEN_SHOWN_LEN],
+            username,
+            name,
+            groups: &scope.join(","),
+            created_at: now,
+            expires_at: if expires_days > 0 { Some(now + ChronoDuration::days(expires_days)) } else { None },
+        };
+
+        match diesel::insert_into(api_tokens::table)
+            .values(entry)
+            .execute(&mut connection) {
+            Ok(_) => Ok(token),
+            Err(err) => Err(format!("Error on create_api_token (insert): {:?}", err))
+        }
+    }
+
+    /// Returns all API tokens of the user
+    pub fn get_user_api_tokens(&self, username: &str) -> Result<Vec<ApiToken>, String> {
+        validate_username(username)?;
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_user_api_tokens (connection): {:?}", err)),
+        };
+
+        api_tokens::table.filter(api_tokens::columns::username.eq(username))
+            .order(api_tokens::columns::id.asc())
+            .load::<ApiToken>(&mut connection)
+            .map_err(|err| format!("Error on loading API tokens: {:?}", err))
+    }
+
+    /// Removes API token of the user, token of another user can't be removed this way
+    pub fn revoke_api_token(&self, username: &str, id: i32) -> Result<(), String> {
+        validate_username(username)?;
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on revoke_api_token (connection): {:?}", err)),
+        };
+
+        match diesel::delete(api_tokens::table.filter(api_tokens::columns::id.eq(id)
+            .and(api_tokens::columns::username.eq(username))))
+            .execute(&mut connection) {
+            Ok(0) => Err(format!("No such token: {}", id)),
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error on revoke_api_token (delete): {:?}", err))
+        }
+    }
+
+    /// Resolves the API token to the user and the groups of the token, which the user still has
+    fn get_principal_from_api_token(&self, token: &str) -> Result<Principal, String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_principal_from_api_token (connection): {:?}", err)),
+        };
+
+        let entry: ApiToken = match api_tokens::table.filter(api_tokens::columns::token.eq(get_fast_hash(token)))
+            .first::<ApiToken>(&mut connection) {
+            Ok(d) => d,
+            Err(dError::NotFound) => return Err("No such API token".to_string()),
+            Err(e) => return Err(format!("Error on loading API token: {:?}", e)),
+        };
+
+        let now = Utc::now().naive_utc();
+        if entry.expires_at.map(|x| x < now).unwrap_or(false) {
+            return Err("API token has expired".to_string());
+        }
+
+        match users::table.filter(users::columns::name.eq(&entry.username))
+            .select(users::columns::password)
+            .first::<String>(&mut connection) {
+            Ok(password) if !password.is_empty() => (),
+            Ok(_) | Err(dError::NotFound) => return Err("Owner of the API token is banned or removed".to_string()),
+            Err(e) => return Err(format!("Error on loading the owner of API token: {:?}", e)),
+        };
+
+        diesel::update(api_tokens::table.filter(api_tokens::columns::id.eq(entry.id)))
+            .set(api_tokens::columns::last_used.eq(Some(now)))
+            .execute(&mut connection)
+            .map_err(|err| format!("Error on refreshing API token: {:?}", err))?;
+
+        let user_groups = self.get_user_groups(&entry.username)?;
+        Ok(Principal {
+            groups: Some(entry.groups.split(",")
+                .filter(|x| user_groups.iter().any(|y| y == x))
+                .map(|x| x.to_string())
+                .collect()),
+            username: entry.username,
+            api_token_id: Some(entry.id),
+        })
+    }
+
+    /// Resolves value of the `Authorization` header (`Bearer <token>`) to the principal.
+    /// Token is either personal API token or the session cookie
+    pub fn resolve_authorization(&self, header: &str, ip: &str, user_agent: &str) -> Result<Principal, String> {
+        let token = match header.trim().split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") && !token.trim().is_empty() => token.trim(),
+            _ => return Err("Bearer token is required".to_string())
+        };
+
+        if token.starts_with(API_TOKEN_PREFIX) {
+            self.get_principal_from_api_token(token)
+        } else {
+            self.get_user_from_cookie(token, ip, user_agent).map(|username| Principal::user(&username))
+        }
+    }
+
+    /// Returns whether user has access to the group, either directly or through any of the roles
+    pub fn has_access_to_group(&self, username: &str, group_name: &str) -> Result<bool, String> {
+        validate_username(username)?;
+        let user_groups = match self.get_user_groups(username) {
+            Ok(data) => data,
+            Err(err) => return Err(format!("Error on has_access on getting user groups: {:?}", err))
+        };
+
+        if user_groups.contains(&group_name.to_string()) {
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Returns whether user has access to the device, or not
+    pub fn has_access_to_device(&self, username: &str, device: &str) -> Result<bool, String> {
+        validate_username(username)?;
+        let user_dev = match self.get_user_devices(username) {
+            Ok(data) => data,
+            Err(err) => return Err(format!("Error on has_access on getting user devices: {:?}", err))
+        };
+
+        if user_dev.contains(&device.to_string()) {
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Records failed login for both username and IP. When number of failures in the sliding window
+    /// exceeds the limit, the username or IP gets locked out for the configured time.
+    fn register_login_failure(&self, username: &str, ip: &str) -> Result<(), String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection register_login_failure: {:?}", err))?;
+        let now_ms = Utc::now().timestamp_millis();
+        let window_start_ms = now_ms - (self.login_policy.window_s as i64) * 1000;
+
+        for (kind, subject, max_attempts, lockout_s) in [
+            ("user", username, self.login_policy.max_user_attempts, self.login_policy.lockout_user_s),
+            ("ip", ip, self.login_policy.max_ip_attempts, self.login_policy.lockout_ip_s)] {
+            if subject.is_empty() || max_attempts == 0 {
+                continue;
+            }
+            let fails_key = format!("{}{}:{}", LOGIN_FAILS_PREFIX, kind, subject);
+            redis_conn.deref_mut().zadd::<&str, i64, String, ()>(&fails_key, format!("{}:{}", now_ms, get_random_token()), now_ms)
+                .map_err(|err| format!("Error on adding login failure: {:?}", err))?;
+            redis_conn.deref_mut().zrembyscore::<&str, i64, i64, ()>(&fails_key, 0, window_start_ms)
+                .map_err(|err| format!("Error on trimming login failures: {:?}", err))?;
+            redis_conn.deref_mut().expire::<&str, ()>(&fails_key, self.login_policy.window_s)
+                .map_err(|err| format!("Error on setting expire for login failures: {:?}", err))?;
+            let fails: usize = redis_conn.deref_mut().zcard(&fails_key)
+                .map_err(|err| format!("Error on counting login failures: {:?}", err))?;
+
+            if fails >= max_attempts {
+                warn!("Locking out {} `{}` for {} seconds after {} failed logins", kind, subject, lockout_s, fails);
+                redis_conn.deref_mut().set_ex::<String, i64, ()>(format!("{}{}:{}", LOCKOUT_PREFIX, kind, subject), now_ms / 1000, lockout_s)
+                    .map_err(|err| format!("Error on setting lockout: {:?}", err))?;
+                redis_conn.deref_mut().del::<&str, ()>(&fails_key)
+                    .map_err(|err| format!("Error on clearing login failures: {:?}", err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns number of seconds left till the end of lockout, if user or IP is locked out
+    pub fn get_lockout(&self, username: &str, ip: &str) -> Result<Option<i64>, String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection get_lockout: {:?}", err))?;
+
+        let mut left: Option<i64> = None;
+        for (kind, subject) in [("user", username), ("ip", ip)] {
+            if subject.is_empty() {
+                continue;
+            }
+            let ttl: i64 = redis_conn.deref_mut().ttl(format!("{}{}:{}", LOCKOUT_PREFIX, kind, subject))
+                .map_err(|err| format!("Error on reading lockout: {:?}", err))?;
+            // -2 means there is no such key, -1 means key without expiration
+            if ttl == -1 || ttl > 0 {
+                left = Some(std::cmp::max(left.unwrap_or(0), ttl));
+            }
+        }
+        Ok(left)
+    }
+
+    /// Returns all active lockouts of users and IPs
+    pub fn get_all_lockouts(&self) -> Result<Vec<Lockout>, String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection get_all_lockouts: {:?}", err))?;
+
+        let keys: Vec<String> = redis_conn.deref_mut().scan_match::<String, String>(format!("{}*", LOCKOUT_PREFIX))
+            .map_err(|err| format!("Error on scanning lockouts: {:?}", err))?
+            .collect();
+
+        let mut lockouts: Vec<Lockout> = vec![];
+        for key in keys {
+            let ttl: i64 = redis_conn.deref_mut().ttl(&key)
+                .map_err(|err| format!("Error on reading lockout: {:?}", err))?;
+            let (kind, subject) = match key[LOCKOUT_PREFIX.len()..].split_once(":") {
+                Some(d) => d,
+                None => continue
+            };
+            lockouts.push(Lockout { kind: kind.to_string(), subject: subject.to_string(), expires_in: ttl });
+        }
+        Ok(lockouts)
+    }
+
+    /// Removes lockouts and counters of failed logins for the user and/or IP
+    pub fn unlock_login(&self, username: Option<&str>, ip: Option<&str>) -> Result<(), String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection unlock_login: {:?}", err))?;
+
+        for (kind, subject) in [("user", username), ("ip", ip)] {
+            let subject = match subject {
+                Some(d) => d,
+                None => continue
+            };
+            redis_conn.deref_mut().del::<Vec<String>, ()>(vec![
+                format!("{}{}:{}", LOCKOUT_PREFIX, kind, subject),
+                format!("{}{}:{}", LOGIN_FAILS_PREFIX, kind, subject)])
+                .map_err(|err| format!("Error on removing lockout: {:?}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Returns user entry straight from the database, including second factor settings
+    fn get_user_entry(&self, username: &str) -> Result<User, String> {
+        validate_username(username)?;
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on get_user_entry (connection): {:?}", err)),
+        };
+
+        users::table.filter(users::columns::name.eq(username))
+            .first::<User>(&mut connection)
+            .map_err(|err| format!("Error on loading user: {:?}", err))
+    }
+
+    fn update_user_totp(&self, username: &str, secret: Option<&str>, enabled: bool, recovery_codes: &str) -> Result<(), String> {
+        let mut connection = match self.sql_pool.get() {
+            Ok(conn) => {
+                debug!("Got connection");
+                conn
+            }
+            Err(err) => return Err(format!("Error on update_user_totp (connection): {:?}", err)),
+        };
+
+        match diesel::update(users::table.filter(users::columns::name.eq(username)))
+            .set((users::columns::totp_secret.eq(secret),
+                  users::columns::totp_enabled.eq(if enabled { 1 } else { 0 }),
+                  users::columns::recovery_codes.eq(recovery_codes)))
+            .execute(&mut connection) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error on update_user_totp (update): {:?}", err))
+        }
+    }
+
+    /// Checks TOTP code of the user and makes sure the same time step is never accepted twice
+    fn check_totp_code(&self, username: &str, secret: &str, code: &str, unix_time: u64) -> Result<bool, String> {
+        let step = match totp::verify_totp(secret, code, unix_time)? {
+            Some(d) => d,
+            None => return Ok(false)
+        };
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection check_totp_code: {:?}", err))?;
+        let last_key = format!("{}{}", TOTP_LAST_STEP_PREFIX, username);
+        let last_step: u64 = redis_conn.deref_mut().get(&last_key).unwrap_or(0);
+        if step <= last_step {
+            warn!("Replayed TOTP code of `{}`", username);
+            return Ok(false);
+        }
+        redis_conn.deref_mut().set_ex::<&str, u64, ()>(&last_key, step, ((totp::TOTP_SKEW_STEPS * 2 + 1) * totp::TOTP_STEP_S) as usize)
+            .map_err(|err| format!("Error on saving TOTP step: {:?}", err))?;
+        Ok(true)
+    }
+
+    /// Returns whether the user has to enter the second factor on login
+    pub fn is_totp_enabled(&self, username: &str) -> Result<bool, String> {
+        Ok(self.get_user_entry(username)?.totp_enabled != 0)
+    }
+
+    /// Generates new TOTP secret for the user. It is not required on login until confirmed with the code
+    pub fn start_totp_enrollment(&self, username: &str) -> Result<String, String> {
+        if self.is_totp_enabled(username)? {
+            return Err("Two-factor authentication is already enabled".to_string());
+        }
+        let secret = totp::generate_secret();
+        self.update_user_totp(username, Some(&secret), false, "")?;
+        Ok(secret)
+    }
+
+    /// Enables TOTP for the user, if the code matches the pending secret. Returns recovery codes
+    pub fn confirm_totp_enrollment(&self, username: &str, code: &str, unix_time: u64) -> Result<Vec<String>, String> {
+        let user = self.get_user_entry(username)?;
+        if user.totp_enabled != 0 {
+            return Err("Two-factor authentication is already enabled".to_string());
+        }
+        let secret = match user.totp_secret {
+            Some(d) => d,
+            None => return Err("Enrollment was not started".to_string())
+        };
+        if !self.check_totp_code(username, &secret, code, unix_time)? {
+            return Err("Wrong code".to_string());
+        }
+        let codes = totp::generate_recovery_codes(RECOVERY_CODES_COUNT);
+        let hashed = codes.iter().map(|x| get_fast_hash(x)).collect::<Vec<String>>().join(",");
+        self.update_user_totp(username, Some(&secret), true, &hashed)?;
+        Ok(codes)
+    }
+
+    /// Disables TOTP for the user, requires valid TOTP or recovery code
+    pub fn disable_totp(&self, username: &str, code: &str, unix_time: u64) -> Result<(), String> {
+        if !self.verify_second_factor(username, code, unix_time)? {
+            return Err("Wrong code".to_string());
+        }
+        self.update_user_totp(username, None, false, "")
+    }
+
+    /// Drops TOTP settings of the user without any codes, used by root when user lost the device
+    pub fn reset_totp(&self, username: &str) -> Result<(), String> {
+        self.get_user_entry(username)?;
+        self.update_user_totp(username, None, false, "")
+    }
+
+    /// Accepts either current TOTP code, or one of the recovery codes, which is burnt after use
+    fn verify_second_factor(&self, username: &str, code: &str, unix_time: u64) -> Result<bool, String> {
+        let user = self.get_user_entry(username)?;
+        let secret = match (user.totp_enabled, user.totp_secret) {
+            (1, Some(d)) => d,
+            _ => return Err("Two-factor authentication is not enabled".to_string())
+        };
+        if self.check_totp_code(username, &secret, code, unix_time)? {
+            return Ok(true);
+        }
+
+        let code_hash = get_fast_hash(code.trim());
+        let mut recovery: Vec<&str> = user.recovery_codes.split(",").filter(|x| !x.is_empty()).collect();
+        match recovery.iter().position(|x| *x == code_hash) {
+            Some(pos) => {
+                recovery.remove(pos);
+                info!("Recovery code used by `{}`, {} left", username, recovery.len());
+                self.update_user_totp(username, Some(&secret), true, &recovery.join(","))?;
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    /// Checks the second factor on login, managing the same lockouts as `validate_user`
+    pub fn validate_second_factor(&self, username: &str, code: &str, ip: &str, unix_time: u64) -> Result<bool, String> {
+        if self.get_lockout(username, ip)?.is_some() {
+            return Ok(false);
+        }
+        if self.verify_second_factor(username, code, unix_time)? {
+            return Ok(true);
+        }
+        self.register_login_failure(username, ip)?;
+        Ok(false)
+    }
+
+    /// Remembers that the user has passed the password check, but not the second factor yet.
+    /// Returns token for the half-authenticated state
+    pub fn create_pending_login(&self, username: &str) -> Result<String, String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection create_pending_login: {:?}", err))?;
+        let token = get_random_token();
+        redis_conn.deref_mut().set_ex::<String, &str, ()>(format!("{}{}", PENDING_LOGIN_PREFIX, get_fast_hash(&token)), username, PENDING_LOGIN_EXPIRE)
+            .map_err(|err| format!("Error on saving pending login: {:?}", err))?;
+        Ok(token)
+    }
+
+    /// Returns the user, who has passed the password check with this token
+    pub fn get_pending_login(&self, token: &str) -> Result<String, String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection get_pending_login: {:?}", err))?;
+        redis_conn.deref_mut().get::<String, String>(format!("{}{}", PENDING_LOGIN_PREFIX, get_fast_hash(token)))
+            .map_err(|err| format!("No pending login: {:?}", err))
+    }
+
+    pub fn remove_pending_login(&self, token: &str) -> Result<(), String> {
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection remove_pending_login: {:?}", err))?;
+        redis_conn.deref_mut().del::<String, ()>(format!("{}{}", PENDING_LOGIN_PREFIX, get_fast_hash(token)))
+            .map_err(|err| format!("Error on removing pending login: {:?}", err))
+    }
+
+    /// Returns whether the user has correct credentials or not.
+    /// Also manages the sliding window of unsuccessful logins per username and per IP:
+    /// while username or IP is locked out, every attempt is rejected. Successful login clears
+    /// the failures of the user, but not of the IP.
+    /// Legacy SHA-256 hashes are silently replaced with Argon2id ones on successful login.
+    ///
+    /// # Example
+    /// ```rust
+    /// use webify::database::{get_connection, validate_user};
+    /// let conns = get_connection(&"database.db".to_string()).unwrap();
+    /// assert!(validate_user(&conns, "thomasfire", "bestpasswort").unwrap());
+    /// assert_ne!(validate_user(&conns, "eva", "badpasswort").unwrap());
+    /// ```
+    pub fn validate_user(&self, username: &str, password: &SecStr, ip: &str) -> Result<bool, String> {
+        validate_password(password)?;
+        validate_username(username)?;
+        if self.get_lockout(username, ip)?.is_some() {
+            return Ok(false);
+        }
+        let mut redis_conn = self.redis_pool.get()
+            .map_err(|err| format!("Error on getting the redis connection get_user_groups: {:?}", err))?;
+
+        let mut user: Option<User> = match redis_conn.deref_mut().get::<&str, String>(username) {
+            Ok(data) => match js_from_str::<User>(&data) {
+                Ok(usr_data) => Some(usr_data),
+                Err(_) => None
+            },
+            Err(_) => None
+        };
+
+        if user.is_none() {
+            let mut connection = match self.sql_pool.get() {
+                Ok(conn) => {
+                    debug!("Got connection");
+                    conn
+                }
+                Err(err) => return Err(format!("Error on validate_user: {:?}", err)),
+            };
+
+            user = match users::table.filter(users::columns::name.eq(username))
+                .first::<User>(&mut connection) {
+                Ok(d) => Some(d),
+                Err(e) => {
+                    if e == dError::NotFound {
+                        self.register_login_failure(username, ip)?;
+                        return Ok(false);
+                    }
+                    return Err(format!("Error on validating user: {:?}", e));
+                }
+            };
+        }
+
+        let got_user = match user {
+            Some(d) => d,
+            None => return Err(format!("Error no user: `{}`", username))
+        };
+
+        {
+            let _ = redis_conn.deref_mut().set::<&str, String, String>(username, js_to_str(&got_user.get_content()).unwrap_or("".to_string()))
+                .map_err(|err| error!("Error in caching to redis: {:?}", err));
+            let _ = redis_conn.deref_mut().expire::<&str, usize>(username, REDIS_USER_EXPIRE)
+                .map_err(|err| error!("Error in setting expire to redis: {:?}", err));
+        }
+
+        let (is_password_eq, needs_rehash) = verify_hash(&got_user.password, password);
+        if needs_rehash {
+            match self.rehash_user_pass(username, password) {
+                Ok(_) => info!("Password hash of `{}` has been upgraded", username),
+                Err(err) => error!("Error on upgrading the password hash of `{}`: {}", username, err)
+            };
+        }
+
+        if is_password_eq {
+            redis_conn.deref_mut().del::<String, ()>(format!("{}user:{}", LOGIN_FAILS_PREFIX, username))
+                .map_err(|err| format!("Error on clearing login failures: {:?}", err))?;
+        } else {
+            self.register_login_failure(username, ip)?;
+        }
+        Ok(is_password_eq)
+    }
+
+    /// Replaces the devices map by their group name : {group_name: device_name}, it comes from `DeviceRegistry`.
+    /// Groups are also stored in the database for `insert_user`, the ones, which are seen for the first time,
+    /// are given to the admin role
+    ///
+    /// # Examples
+    /// ```rust
+    /// use webify::database::Database;
+    /// use webify::config::{read_config, Config};
+    /// use std::collections::BTreeMap;
+    /// let database = Database::new(&read_config::<Config>("config.toml").unwrap()).unwrap();
+    /// let mut device_by_group = BTreeMap::new();
+    /// device_by_group.insert("filer_read".to_string(), "filer".to_string());
+    /// database.set_device_groups(&device_by_group).unwrap();
+    /// ```
+    pub fn set_device_groups(&self, groups: &BTreeMap<String, String>) -> Result<(), String> {
+        let mut devices_map = self.mapped_devices.write()
+            .map_err(|err| format!("Error on writing mapped device: {:?}", err))?;
+        *devices_map = groups.iter().map(|(x, y)| (x.clone(), y.clone())).collect();
+        drop(devices_map);
+
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on set_device_groups (connection): {:?}", err))?;
+        let new_groups = connection.transaction::<_, dError, _>(|conn| {
+            let stored = device_groups::table.select(device_groups::columns::group_name).load::<String>(conn)?;
+            let new_groups: Vec<&String> = groups.keys().filter(|x| !stored.contains(x)).collect();
+            for (group_name, device) in groups.iter() {
+                diesel::replace_into(device_groups::table)
+                    .values((device_groups::columns::group_name.eq(group_name), device_groups::columns::device.eq(device)))
+                    .execute(conn)?;
+            }
+            let admin_id = roles::table.filter(roles::columns::name.eq(ADMIN_ROLE))
+                .select(roles::columns::id)
+                .first::<i32>(conn)
+                .optional()?;
+            if let Some(role_id) = admin_id {
+                for group_name in new_groups.iter() {
+                    diesel::insert_or_ignore_into(role_groups::table)
+                        .values(RoleGroupForm { role_id, group_name })
+                        .execute(conn)?;
+                }
+            }
+            Ok(new_groups.into_iter().cloned().collect::<Vec<String>>())
+        }).map_err(|err| format!("Error on storing device groups: {:?}", err))?;
+
+        if !new_groups.is_empty() {
+            info!("New groups have been given to the `{}` role: {}", ADMIN_ROLE, new_groups.join(","));
+            self.flush_groups_cache()?;
+        }
+        Ok(())
+    }
+
+    /// Returns names of the groups of all registered devices
+    fn get_known_groups(&self) -> Result<Vec<String>, String> {
+        Ok(self.get_all_groups()?.into_keys().collect())
+    }
+
+    /// HMAC of the command's payload for the history. Same payloads give the same digest,
+    /// but the short ones (passwords, TOTP codes) can't be guessed from it without `history_digest_key`.
+    /// Empty, if there is no payload or no key
+    pub fn get_payload_digest(&self, payload: &str) -> String {
+        if payload.is_empty() || self.history_digest_key.unsecure().is_empty() {
+            return String::new();
+        }
+        let mut hmac = Hmac::new(Sha256::new(), self.history_digest_key.unsecure());
+        hmac.input(payload.as_bytes());
+        hmac.result().code().to_hex()
+    }
+
+    /// Runs the stat query, see `StatQuery`
+    pub fn load_stats(&self, query: &StatQuery) -> Result<Vec<StatEntry>, String> {
+        let mut connection = self.sql_pool.get()
+            .map_err(|err| format!("Error on load_stats (connection): {:?}", err))?;
+        query.load(&mut connection)
+    }
+}
+
+
+/// Inserts new user from the command line, before the server is started. Groups are checked against
+/// the groups of the built-in devices and of the devices, which have been registered by the server
+pub fn insert_user(pool: &SQLPool, username: &str, password: &SecStr, groups: Option<&str>) -> Result<(), String> {
+    let groups = split_list(groups.unwrap_or(""));
+    let known_groups = {
+        let mut connection = pool.get()
+            .map_err(|err| format!("Error on insert_user (connection): {:?}", err))?;
+        device_groups::table.select(device_groups::columns::group_name)
+            .load::<String>(&mut connection)
+            .map_err(|err| format!("Error on loading device groups: {:?}", err))?
+    };
+    check_groups(&groups, &known_groups)?;
+    insert_user_entry(pool, username, password, &groups)
+}
+
+fn insert_user_entry(pool: &SQLPool, username: &str, password: &SecStr, groups: &[String]) -> Result<(), String> {
+    validate_password(password)?;
+    validate_username(username)?;
+    let mut connection = match pool.get() {
         Ok(conn) => {
-            debug!("Connection established");
+            debug!("Got connection");
             conn
         }
-        Err(err) => return Err(format!("Error on init_db: {}", err)),
+        Err(err) => return Err(format!("Error on insert_user (connection): {:?}", err)),
+    };
+
+    let new_user = UserAdd {
+        name: username,
+        password: &get_hash(password)?,
+        groups: "",
     };
 
+    match connection.transaction::<_, dError, _>(|conn| {
+        diesel::insert_into(users::table)
+            .values(&new_user)
+            .execute(conn)?;
+        for group_name in groups.iter() {
+            diesel::insert_into(user_groups::table)
+                .values(UserGroupForm { username, group_name })
+                .execute(conn)?;
+        }
+        Ok(())
+    }) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error on inserting users (insert): {:?}", err))
+    }
+}
+
+/// Assigns the role to the user. Used for the root user, before the server is started
+///
+/// # Example
+/// ```rust
+/// use webify::database::{get_connection, assign_user_role, ADMIN_ROLE};
+/// let conns = get_connection(&"database.db".to_string()).unwrap();
+/// assign_user_role(&conns, "thomasfire", ADMIN_ROLE).unwrap();
+/// ```
+pub fn assign_user_role(pool: &SQLPool, username: &str, role: &str) -> Result<(), String> {
+    validate_username(username)?;
     let mut connection = match pool.get() {
         Ok(conn) => {
             debug!("Got connection");
             conn
         }
-        Err(err) => return Err(format!("Error on init_db: {:?}", err)),
+        Err(err) => return Err(format!("Error on assign_user_role (connection): {:?}", err)),
     };
 
-    match connection.batch_execute("
+    let role_id = get_role_id(&mut connection, role)?;
+    match diesel::insert_or_ignore_into(user_roles::table)
+        .values(UserRoleForm { username, role_id })
+        .execute(&mut connection) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error on assign_user_role (insert): {:?}", err))
+    }
+}
+
+/// Splits comma-separated list, dropping empty items and duplicates
+fn split_list(list: &str) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    for item in list.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        if !result.iter().any(|x| x == item) {
+            result.push(item.to_string());
+        }
+    }
+    result
+}
+
+fn check_groups(groups: &[String], known_groups: &[String]) -> Result<(), String> {
+    match groups.iter().find(|x| !known_groups.contains(x)) {
+        Some(group) => Err(format!("Unknown group: {}", group)),
+        None => Ok(())
+    }
+}
+
+fn validate_role_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > ROLE_NAME_MAX_LEN {
+        return Err(format!("Name of the role should be from 1 to {} symbols", ROLE_NAME_MAX_LEN));
+    }
+    if !name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') {
+        return Err(format!("Unexpected role's symbols: `{}`, allowed symbols are latin symbols, numbers and underline _", name));
+    }
+    Ok(())
+}
+
+fn get_role_id(connection: &mut SqliteConnection, name: &str) -> Result<i32, String> {
+    match roles::table.filter(roles::columns::name.eq(name)).select(roles::columns::id).first::<i32>(connection) {
+        Ok(id) => Ok(id),
+        Err(dError::NotFound) => Err(format!("No such role: {}", name)),
+        Err(err) => Err(format!("Error on loading role: {:?}", err)),
+    }
+}
+
+fn insert_role(connection: &mut SqliteConnection, name: &str, description: &str) -> Result<i32, dError> {
+    diesel::insert_into(roles::table)
+        .values(RoleForm { name, description })
+        .execute(connection)?;
+    roles::table.filter(roles::columns::name.eq(name)).select(roles::columns::id).first::<i32>(connection)
+}
+
+/// Replaces own groups and parents of the role, `None` keeps the current ones
+fn set_role_links(connection: &mut SqliteConnection, role_id: i32, groups: Option<&Vec<String>>, parents: Option<&Vec<i32>>) -> Result<(), dError> {
+    if let Some(data) = groups {
+        diesel::delete(role_groups::table.filter(role_groups::columns::role_id.eq(role_id))).execute(connection)?;
+        for group_name in data.iter() {
+            diesel::insert_into(role_groups::table)
+                .values(RoleGroupForm { role_id, group_name })
+                .execute(connection)?;
+        }
+    }
+    if let Some(data) = parents {
+        diesel::delete(role_inherits::table.filter(role_inherits::columns::role_id.eq(role_id))).execute(connection)?;
+        for parent_id in data.iter() {
+            diesel::insert_into(role_inherits::table)
+                .values(RoleInheritForm { role_id, parent_id: *parent_id })
+                .execute(connection)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the roles together with all the roles they inherit, directly or through other roles
+fn expand_roles(connection: &mut SqliteConnection, role_ids: &[i32]) -> Result<Vec<i32>, String> {
+    let links = role_inherits::table.select((role_inherits::columns::role_id, role_inherits::columns::parent_id))
+        .load::<(i32, i32)>(connection)
+        .map_err(|err| format!("Error on loading role inheritance: {:?}", err))?;
+
+    let mut result: BTreeSet<i32> = BTreeSet::new();
+    let mut queue: Vec<i32> = role_ids.to_vec();
+    while let Some(role_id) = queue.pop() {
+        if !result.insert(role_id) {
+            continue;
+        }
+        queue.extend(links.iter().filter(|x| x.0 == role_id).map(|x| x.1));
+    }
+    Ok(result.into_iter().collect())
+}
+
+/// Returns own groups of all the roles together
+fn get_roles_groups(connection: &mut SqliteConnection, role_ids: &[i32]) -> Result<BTreeSet<String>, String> {
+    role_groups::table.filter(role_groups::columns::role_id.eq_any(role_ids))
+        .select(role_groups::columns::group_name)
+        .load::<String>(connection)
+        .map(|x| x.into_iter().collect())
+        .map_err(|err| format!("Error on loading role groups: {:?}", err))
+}
+
+/// Parses comma-separated `groups` of the new API token. Each group must be known, belong to the user
+/// and be in the scope of the principal, who creates the token
+///
+/// # Example
+/// ```rust
+/// use webify::database::get_token_scope;
+/// use webify::models::Principal;
+///
+/// let known = vec!["account_write".to_string(), "root_write".to_string()];
+/// let token = Principal { username: "admin".to_string(), groups: Some(vec!["account_write".to_string()]), api_token_id: Some(1) };
+/// assert_eq!(get_token_scope(&token, &known, &known, "account_write").unwrap(), vec!["account_write"]);
+/// assert!(get_token_scope(&token, &known, &known, "account_write,root_write").is_err());
+/// assert!(get_token_scope(&Principal::user("admin"), &known, &known, "root_write").is_ok());
+/// ```
+pub fn get_token_scope(principal: &Principal, user_groups: &[String], known_groups: &[String], groups: &str) -> Result<Vec<String>, String> {
+    let mut scope: Vec<String> = vec![];
+    for group in groups.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        if !known_groups.iter().any(|x| x == group) {
+            return Err(format!("Unknown group: {}", group));
+        }
+        if !user_groups.iter().any(|x| x == group) {
+            return Err(format!("You don't have the group: {}", group));
+        }
+        if !principal.allows_group(group) {
+            return Err(format!("Token can't grant the group it doesn't have: {}", group));
+        }
+        if !scope.iter().any(|x| x == group) {
+            scope.push(group.to_string());
+        }
+    }
+    if scope.is_empty() {
+        return Err("Token should have at least one group".to_string());
+    }
+    Ok(scope)
+}
+
+/// Returns the connection pool by the path to the database
+///
+/// # Example
+/// ```rust
+/// use webify::database::get_connection;
+/// let connections = get_connection(&"database.db".to_string()).unwrap();
+/// ```
+pub fn get_connection(db_config: &String) -> Result<SQLPool, String> {
+    let manager = ConnectionManager::<SqliteConnection>::new(db_config);
+    match SQLPool::builder().build(manager) {
+        Ok(pool) => Ok(pool),
+        Err(err) => Err(format!("Error on getting connection to DB: {:?}", err))
+    }
+}
+
+/// Applies all the pending migrations. `Database::new` does it as well, so it is needed only
+/// for the command line tools, which work without the server. Returns names of the applied migrations
+///
+/// # Example
+/// ```rust
+/// use webify::database::{get_connection, upgrade_database};
+/// let connections = get_connection(&"database.db".to_string()).unwrap();
+/// for name in upgrade_database(&connections).unwrap() {
+///     println!("Applied {}", name);
+/// }
+/// ```
+pub fn upgrade_database(pool: &SQLPool) -> Result<Vec<String>, String> {
+    let mut connection = pool.get()
+        .map_err(|err| format!("Error on upgrade_database (connection): {:?}", err))?;
+    migrate(&mut connection)
+}
+
+/// Checks that the database exists, has the tables, created by `init_db`, and isn't newer than
+/// the migrations of this build. Doesn't modify anything
+///
+/// # Example
+/// ```rust
+/// use webify::database::check_database;
+/// check_database("database.db").unwrap();
+/// ```
+pub fn check_database(db_config: &str) -> Result<(), String> {
+    if !exists(db_config) {
+        return Err(format!("`{}` doesn't exist, run `$ webify --setup`", db_config));
+    }
+    let mut connection = SqliteConnection::establish(db_config)
+        .map_err(|err| format!("Error on opening `{}`: {}", db_config, err))?;
+    let tables = get_table_names(&mut connection)?;
+    for table in ["users", "history"] {
+        if !tables.iter().any(|x| x == table) {
+            return Err(format!("`{}` has no table `{}`, run `$ webify --setup`", db_config, table));
+        }
+    }
+    if tables.iter().any(|x| x == "schema_version") {
+        let version = get_schema_version(&mut connection)?;
+        if version > latest_schema_version() {
+            return Err(format!("`{}` has schema version {}, but this build knows only up to {}",
+                               db_config, version, latest_schema_version()));
+        }
+    }
+    Ok(())
+}
+
+/// Creates the database and applies all the migrations to it
+///
+/// # Example
+/// ```rust
+/// use webify::database::init_db;
+/// init_db(&"database.db".to_string()).unwrap();
+/// ```
+pub fn init_db(db_config: &String) -> Result<(), String> {
+    let pool = get_connection(db_config)
+        .map_err(|err| format!("Error on init_db: {}", err))?;
+    let applied = upgrade_database(&pool)?;
+    info!("DB has been initialized successfully, {} migrations applied", applied.len());
+    Ok(())
+}
+
+/// Makes the query of the history entries, which match the filter. Every filtered column is indexed
+fn filter_history(filter: &HistoryFilter) -> Result<history::BoxedQuery<'_, Sqlite>, String> {
+    let (from, to) = filter.get_range()?;
+    let mut query = history::table.into_boxed();
+    if !filter.username.is_empty() {
+        query = query.filter(history::columns::username.eq(&filter.username));
+    }
+    if !filter.device.is_empty() {
+        query = query.filter(history::columns::device.eq(&filter.device));
+    }
+    if !filter.command.is_empty() {
+        query = query.filter(history::columns::command.eq(&filter.command));
+    }
+    if !filter.qtype.is_empty() {
+        query = query.filter(history::columns::qtype.eq(&filter.qtype));
+    }
+    if let Some(rejected) = filter.rejected {
+        query = query.filter(history::columns::rejected.eq(rejected));
+    }
+    if let Some(from) = from {
+        query = query.filter(history::columns::timestamp.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(history::columns::timestamp.le(to));
+    }
+    Ok(query)
+}
+
+/// Adds column to the table, if the table doesn't have it yet
+fn add_column_if_missing(connection: &mut SqliteConnection, table: &str, column: &str, definition: &str) -> Result<(), dError> {
+    let columns: Vec<StatEntry> = diesel::sql_query(format!("SELECT name AS label, 0 AS counter FROM pragma_table_info('{}');", table))
+        .load(connection)?;
+    if columns.iter().any(|x| x.label == column) {
+        return Ok(());
+    }
+    connection.batch_execute(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))
+}
+
+fn get_table_names(connection: &mut SqliteConnection) -> Result<Vec<String>, String> {
+    let tables: Vec<StatEntry> = diesel::sql_query("SELECT name AS label, 0 AS counter FROM sqlite_master WHERE type = 'table';")
+        .load(connection)
+        .map_err(|err| format!("Error on reading tables: {:?}", err))?;
+    Ok(tables.into_iter().map(|x| x.label).collect())
+}
+
+fn get_schema_version(connection: &mut SqliteConnection) -> Result<i32, String> {
+    schema_version::table.select(diesel::dsl::max(schema_version::columns::version))
+        .first::<Option<i32>>(connection)
+        .map(|version| version.unwrap_or(0))
+        .map_err(|err| format!("Error on reading schema version: {:?}", err))
+}
+
+fn latest_schema_version() -> i32 {
+    MIGRATIONS.last().map(|x| x.version).unwrap_or(0)
+}
+
+/// Brings the database up to the latest migration. Every migration is applied in its own transaction
+/// together with the record in `schema_version`, so the database is never left half-migrated.
+/// The database with users, but without the recorded version, is a legacy one, see `adopt_legacy_db`
+fn migrate(connection: &mut SqliteConnection) -> Result<Vec<String>, String> {
+    let tables = get_table_names(connection)?;
+    let is_legacy = tables.iter().any(|x| x == "users")
+        && (!tables.iter().any(|x| x == "schema_version") || get_schema_version(connection)? == 0);
+
+    connection.transaction::<_, dError, _>(|conn| {
+        conn.batch_execute("
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER primary key not null,
+            name TEXT not null,
+            applied_at TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
+        );
+        ")?;
+        if is_legacy {
+            adopt_legacy_db(conn)?;
+        }
+        Ok(())
+    }).map_err(|err| format!("Error on upgrading legacy database: {:?}", err))?;
+    if is_legacy {
+        info!("Legacy database has been brought to schema version {}", MIGRATIONS[0].version);
+    }
+
+    let current = get_schema_version(connection)?;
+    if current > latest_schema_version() {
+        return Err(format!("Database has schema version {}, but this build knows only up to {}",
+                           current, latest_schema_version()));
+    }
+
+    let mut applied: Vec<String> = vec![];
+    for migration in MIGRATIONS.iter().filter(|x| x.version > current) {
+        connection.transaction::<_, dError, _>(|conn| {
+            conn.batch_execute(migration.sql)?;
+            if let Some(after) = migration.after {
+                after(conn)?;
+            }
+            diesel::insert_into(schema_version::table)
+                .values((schema_version::columns::version.eq(migration.version),
+                         schema_version::columns::name.eq(migration.name)))
+                .execute(conn)
+                .map(|_| ())
+        }).map_err(|err| format!("Error on applying migration {:04}_{}: {:?}", migration.version, migration.name, err))?;
+        info!("Migration {:04}_{} has been applied", migration.version, migration.name);
+        applied.push(format!("{:04}_{}", migration.version, migration.name));
+    }
+    Ok(applied)
+}
+
+/// Databases, created before the migrations, are brought to the schema of the first migration
+/// and marked with its version, so only the later migrations are applied to them.
+/// Runs inside the transaction of `migrate`
+fn adopt_legacy_db(connection: &mut SqliteConnection) -> Result<(), dError> {
+    connection.batch_execute(MIGRATIONS[0].sql)?;
+    for (table, column, definition) in LEGACY_COLUMNS.iter() {
+        add_column_if_missing(connection, table, column, definition)?;
+    }
+    if roles::table.count().get_result::<i64>(connection)? == 0 {
+        seed_roles(connection)?;
+    }
+    move_legacy_groups(connection)?;
+
+    diesel::insert_into(schema_version::table)
+        .values((schema_version::columns::version.eq(MIGRATIONS[0].version),
+                 schema_version::columns::name.eq(MIGRATIONS[0].name)))
+        .execute(connection)
+        .map(|_| ())
+}
+
+/// Creates `DEFAULT_ROLES`
+fn seed_roles(connection: &mut SqliteConnection) -> Result<(), dError> {
+    let mut ids: HashMap<&str, i32> = HashMap::new();
+    for (name, description, parent, groups) in DEFAULT_ROLES.iter() {
+        let role_id = insert_role(connection, name, description)?;
+        let groups: Vec<String> = if *name == ADMIN_ROLE {
+            devices::list_all_groups()
+        } else {
+            groups.iter().map(|x| x.to_string()).collect()
+        };
+        let parents: Vec<i32> = ids.get(parent).cloned().into_iter().collect();
+        set_role_links(connection, role_id, Some(&groups), Some(&parents))?;
+        ids.insert(*name, role_id);
+    }
+    info!("Default roles have been created");
+    Ok(())
+}
+
+/// Stores the groups of the built-in devices, so they are known before the server is started
+/// and are not given to the admin role again
+fn seed_device_groups(connection: &mut SqliteConnection) -> Result<(), dError> {
+    for (device, groups) in devices::DEV_NAMES.iter().zip(devices::DEV_GROUPS.iter()) {
+        for group_name in groups.iter().flatten() {
+            diesel::insert_or_ignore_into(device_groups::table)
+                .values((device_groups::columns::group_name.eq(group_name), device_groups::columns::device.eq(device)))
+                .execute(connection)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves groups from the old comma-separated `users.groups` to `user_groups`
+fn move_legacy_groups(connection: &mut SqliteConnection) -> Result<(), dError> {
+    let legacy_users = users::table.filter(users::columns::groups.ne(""))
+        .load::<User>(connection)?;
+    for user in legacy_users.iter() {
+        for group_name in split_list(&user.groups).iter() {
+            diesel::insert_or_ignore_into(user_groups::table)
+                .values(UserGroupForm { username: &user.name, group_name })
+                .execute(connection)?;
+        }
+        diesel::update(users::table.filter(users::columns::id.eq(user.id)))
+            .set(users::columns::groups.eq(""))
+            .execute(connection)?;
+    }
+    if !legacy_users.is_empty() {
+        info!("Groups of {} users have been moved to user_groups", legacy_users.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Schema of the databases, created by `init_db` before the migrations
+    const BASELINE_SCHEMA: &str = "
     CREATE TABLE users (
         id INTEGER primary key not null,
         name TEXT not null,
@@ -571,10 +2151,78 @@ pub fn init_db(db_config: &String) -> Result<(), String> {
         rejected INTEGER not null DEFAULT 0,
         timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP
     );
-    ") {
-        Ok(_) => info!("DB has been initialized successfully"),
-        Err(err) => return Err(format!("Error on init_db at execution: {:?}", err))
-    };
+    INSERT INTO users (name, password, groups) VALUES ('thomasfire', 'hash', 'filer_read,root_read');
+    INSERT INTO history (username, device, command, qtype) VALUES ('thomasfire', 'filer', 'list', 'R');
+    ";
+
+    fn open_baseline(dir: &tempfile::TempDir) -> SqliteConnection {
+        let mut connection = SqliteConnection::establish(dir.path().join("database.db").to_str().unwrap()).unwrap();
+        connection.batch_execute(BASELINE_SCHEMA).unwrap();
+        connection
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    fn get_groups_of(connection: &mut SqliteConnection, username: &str) -> Vec<String> {
+        user_groups::table.filter(user_groups::columns::username.eq(username))
+            .select(user_groups::columns::group_name)
+            .order(user_groups::columns::group_name)
+            .load::<String>(connection)
+            .unwrap()
+    }
+
+    #[test]
+    fn upgrades_baseline_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut connection = open_baseline(&dir);
+
+        let applied = migrate(&mut connection).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len() - 1);
+        assert_eq!(get_schema_version(&mut connection).unwrap(), latest_schema_version());
+        assert_eq!(get_groups_of(&mut connection, "thomasfire"), vec!["filer_read", "root_read"]);
+
+        let user = users::table.first::<User>(&mut connection).unwrap();
+        assert_eq!(user.groups, "");
+        assert_eq!(user.totp_enabled, 0);
+        assert_eq!(history::table.count().get_result::<i64>(&mut connection).unwrap(), 1);
+        assert!(get_role_id(&mut connection, ADMIN_ROLE).is_ok());
+
+        assert!(migrate(&mut connection).unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_adoption_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut connection = open_baseline(&dir);
+        // `user_groups` without `group_name` breaks moving of the legacy groups, which goes last
+        connection.batch_execute("CREATE TABLE user_groups (username TEXT not null);").unwrap();
+
+        assert!(migrate(&mut connection).is_err());
+        let tables = get_table_names(&mut connection).unwrap();
+        assert!(!tables.iter().any(|x| x == "schema_version" || x == "roles" || x == "sessions"));
+        let user = users::table.select((users::columns::name, users::columns::groups))
+            .first::<(String, String)>(&mut connection).unwrap();
+        assert_eq!(user.1, "filer_read,root_read");
+
+        connection.batch_execute("DROP TABLE user_groups;").unwrap();
+        migrate(&mut connection).unwrap();
+        assert_eq!(get_schema_version(&mut connection).unwrap(), latest_schema_version());
+        assert_eq!(get_groups_of(&mut connection, "thomasfire"), vec!["filer_read", "root_read"]);
+    }
+
+    #[test]
+    fn empty_schema_version_is_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut connection = open_baseline(&dir);
+        // Left by the builds, which created `schema_version` before adopting the database
+        connection.batch_execute("
+        CREATE TABLE schema_version (
+            version INTEGER primary key not null,
+            name TEXT not null,
+            applied_at TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
+        );
+        ").unwrap();
+
+        migrate(&mut connection).unwrap();
+        assert_eq!(get_groups_of(&mut connection, "thomasfire"), vec!["filer_read", "root_read"]);
+        assert_eq!(users::table.first::<User>(&mut connection).unwrap().totp_enabled, 0);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::device_trait::Device;
+use crate::command_spec::{CommandSpec, CommandInfo};
+use crate::devices::{Devices, Groups, DEV_GROUPS, DEV_NAMES, GROUP_LEN};
+use crate::database::Database;
+use crate::config::Config;
+use crate::startup::StartupError;
+use crate::root_device::RootDev;
+use crate::file_device::FileDevice;
+use crate::printer_device::PrinterDevice;
+use crate::blog_device::BlogDevice;
+use crate::stat_device::StatDevice;
+use crate::ecg_device::EcgDevice;
+use crate::account_device::AccountDevice;
+
+use log::info;
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Function, which registers additional devices. It is called once on start, after the built-in devices are registered
+pub type RegisterDevices = fn(&mut DeviceRegistry, &Database, &Config) -> Result<(), StartupError>;
+
+/// Everything the dashboard needs to know about the device
+#[derive(Clone)]
+pub struct DeviceEntry {
+    pub name: String,
+    /// Groups of the device by `Groups` kind: [RStatus, Read, Write, Request, Confirm, Dismiss]
+    pub groups: [Option<String>; GROUP_LEN],
+    /// Templates, which the device renders. Server doesn't start if any of them is missing
+    pub templates: Vec<String>,
+    /// Commands, which the device has declared. Empty, if the device checks its payloads itself
+    pub commands: Vec<CommandSpec>,
+    pub device: Arc<dyn Device>,
+    instance: Arc<dyn Any + Send + Sync>,
+}
+
+/// Keeps all the devices, which are available through the dashboard and the API.
+/// Devices, which are disabled in `config.toml`, are skipped on registration:
+///
+/// ```toml
+/// [devices.ecgdev]
+/// enabled = false
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::device_registry::{DeviceRegistry, register_builtin_devices};
+/// use webify::config::{read_config, Config};
+/// use webify::file_device::FileDevice;
+/// use webify::database::Database;
+///
+/// let config = read_config::<Config>("config.toml").unwrap();
+/// let database = Database::new(&config).unwrap();
+/// let mut registry = DeviceRegistry::new(&config);
+/// register_builtin_devices(&mut registry, &database, &config).unwrap();
+/// assert!(registry.resolve("root").is_some());
+/// assert!(registry.get::<FileDevice>("filer").is_some());
+/// ```
+#[derive(Clone)]
+pub struct DeviceRegistry {
+    entries: Vec<DeviceEntry>,
+    disabled: Vec<String>,
+}
+
+impl DeviceRegistry {
+    pub fn new(config: &Config) -> DeviceRegistry {
+        DeviceRegistry {
+            entries: vec![],
+            disabled: config.devices.iter()
+                .filter(|(_, device)| !device.enabled)
+                .map(|(name, _)| name.clone())
+                .collect(),
+        }
+    }
+
+    /// Whether the device is enabled in the config. Check it before constructing the device,
+    /// so disabled devices don't connect anywhere
+    pub fn is_enabled(&self, name: &str) -> bool {
+        !self.disabled.iter().any(|x| x == name)
+    }
+
+    /// Adds the device. Name is used in the URLs (`/dashboard/{name}`, `/api/v1/{name}`),
+    /// groups must not belong to any other device. Disabled devices are silently skipped
+    pub fn register<T: Device + 'static>(&mut self, name: &str, groups: [Option<&str>; GROUP_LEN],
+                                         templates: &[&str], device: T) -> Result<(), String> {
+        if name.is_empty() || !name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') {
+            return Err(format!("Invalid device name: `{}`", name));
+        }
+        if !self.is_enabled(name) {
+            info!("Device `{}` is disabled", name);
+            return Ok(());
+        }
+        if self.entries.iter().any(|x| x.name == name) {
+            return Err(format!("Device `{}` is already registered", name));
+        }
+        let group_map = self.get_group_map();
+        for group in groups.iter().flatten() {
+            if let Some(owner) = group_map.get(*group) {
+                return Err(format!("Group `{}` of `{}` already belongs to `{}`", group, name, owner));
+            }
+        }
+
+        let instance = Arc::new(device);
+        self.entries.push(DeviceEntry {
+            name: name.to_string(),
+            groups: groups.map(|x| x.map(|y| y.to_string())),
+            templates: templates.iter().map(|x| x.to_string()).collect(),
+            commands: instance.commands(),
+            device: instance.clone(),
+            instance,
+        });
+        info!("Device `{}` has been registered", name);
+        Ok(())
+    }
+
+    /// Returns the device by its name
+    pub fn resolve(&self, name: &str) -> Option<&dyn Device> {
+        self.get_entry(name).map(|x| x.device.as_ref())
+    }
+
+    pub fn get_entry(&self, name: &str) -> Option<&DeviceEntry> {
+        self.entries.iter().find(|x| x.name == name)
+    }
+
+    /// Returns the device as its own type, for the handlers, which need more than the device traits
+    pub fn get<T: Any>(&self, name: &str) -> Option<&T> {
+        self.entries.iter().find(|x| x.name == name).and_then(|x| x.instance.downcast_ref::<T>())
+    }
+
+    pub fn entries(&self) -> &[DeviceEntry] {
+        &self.entries
+    }
+
+    /// Returns all the declared commands with the groups they require.
+    /// Commands, for which the device has no group, can't be invoked and are skipped
+    pub fn get_commands(&self) -> Vec<CommandInfo> {
+        let mut commands = vec![];
+        for entry in self.entries.iter() {
+            for spec in entry.commands.iter() {
+                let group = Groups::from_qtype(spec.qtype).and_then(|kind| entry.groups[kind as usize].as_ref());
+                if let Some(group) = group {
+                    commands.push(CommandInfo::new(&entry.name, group, spec));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Returns the map {group_name: device_name} of all the registered devices.
+    /// Status group is shared by all devices, so it belongs to no device
+    pub fn get_group_map(&self) -> BTreeMap<String, String> {
+        let mut map: BTreeMap<String, String> = BTreeMap::new();
+        map.insert(DEV_GROUPS[Devices::Zero as usize][Groups::RStatus as usize].unwrap().to_string(), String::new());
+        for entry in self.entries.iter() {
+            for group in entry.groups.iter().flatten() {
+                map.insert(group.clone(), entry.name.clone());
+            }
+        }
+        map
+    }
+}
+
+fn device_error(device: &'static str) -> impl FnOnce(String) -> StartupError {
+    move |reason| StartupError::Device { device: device.to_string(), reason }
+}
+
+fn register_builtin<T: Device + 'static>(registry: &mut DeviceRegistry, device: Devices, templates: &[&str], instance: T) -> Result<(), StartupError> {
+    let index = device as usize;
+    let name = DEV_NAMES[index];
+    registry.register(name, DEV_GROUPS[index], templates, instance)
+        .map_err(|reason| StartupError::Device { device: name.to_string(), reason })
+}
+
+/// Registers the devices, which come with webify
+pub fn register_builtin_devices(registry: &mut DeviceRegistry, database: &Database, config: &Config) -> Result<(), StartupError> {
+    let is_enabled = |registry: &DeviceRegistry, device: Devices| registry.is_enabled(DEV_NAMES[device as usize]);
+    let filer_on = is_enabled(registry, Devices::Filer);
+    let printer_on = is_enabled(registry, Devices::Printer);
+
+    if filer_on || printer_on {
+        // printer sends the files from the filer storage, so it needs the filer even when the filer itself is disabled
+        let filer = FileDevice::new(database, config).map_err(device_error("filer"))?;
+        if filer_on {
+            register_builtin(registry, Devices::Filer, &["file_device.hbs", "upload.hbs", "sender_error.hbs"], filer.clone())?;
+        }
+        if printer_on {
+            register_builtin(registry, Devices::Printer, &["printer_device.hbs", "printer_table.hbs", "simple_message.hbs"],
+                             PrinterDevice::new(Arc::new(filer)))?;
+        }
+    }
+    if is_enabled(registry, Devices::Root) {
+        register_builtin(registry, Devices::Root, &["root_status.hbs", "root_users_table.hbs", "root_history_table.hbs",
+            "root_groups_table.hbs", "root_lockouts_table.hbs", "root_roles_table.hbs", "root_commands_table.hbs",
+            "root_quotas_table.hbs", "simple_message.hbs"], RootDev::new(database))?;
+    }
+    if is_enabled(registry, Devices::Blog) {
+        register_builtin(registry, Devices::Blog, &["blog_new_post.hbs", "blog_post_list.hbs", "blog_post_view.hbs"],
+                         BlogDevice::new(&config.redis_config, database, config.use_scraper).map_err(device_error("blogdev"))?)?;
+    }
+    if is_enabled(registry, Devices::Stat) {
+        register_builtin(registry, Devices::Stat, &["stat_status.hbs", "stat_chart.hbs", "stat_cross_chart.hbs"],
+                         StatDevice::new(database, config).map_err(device_error("statdev"))?)?;
+    }
+    if is_enabled(registry, Devices::ECG) {
+        register_builtin(registry, Devices::ECG, &["ecg_status.hbs", "ecg_webapp.hbs"], EcgDevice::new(config))?;
+    }
+    if is_enabled(registry, Devices::Account) {
+        register_builtin(registry, Devices::Account, &["account_status.hbs", "account_totp_enroll.hbs", "account_recovery_codes.hbs",
+            "account_api_tokens.hbs", "account_api_token_created.hbs", "simple_message.hbs"], AccountDevice::new(database))?;
+    }
+    Ok(())
+}
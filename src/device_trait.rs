@@ -1,9 +1,13 @@
 use serde_json::Value as jsVal;
 
 use crate::dashboard::QCommand;
+use crate::command_spec::CommandSpec;
 
-/// See examples in `src/printer_device.rs`, `src/file_device.rs` and `src/root_device.rs`
-
+/// See examples in `src/printer_device.rs`, `src/file_device.rs` and `src/root_device.rs`.
+/// Devices are registered in `src/device_registry.rs`.
+/// All the methods are called on the blocking thread pool (`web::block`), so devices may do blocking I/O:
+/// run commands, call HTTP with blocking clients, read and write files. They must not rely on the async runtime
+///
 /// Manages read commands for specific device
 pub trait DeviceRead {
     fn read_data(&self, query: &QCommand) -> Result<jsVal, String>;
@@ -25,3 +29,18 @@ pub trait DeviceConfirm {
     fn confirm_query(&self, query: &QCommand) -> Result<jsVal, String>;
     fn dismiss_query(&self, query: &QCommand) -> Result<jsVal, String>;
 }
+
+/// Declares commands of the device and their payloads. Dispatcher rejects commands, which are not declared,
+/// and payloads, which don't match the spec. Status queries (`S`) are not declared.
+/// Devices, which declare nothing, get the payload unchecked
+pub trait DeviceCommands {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![]
+    }
+}
+
+/// Everything the device must implement to be registered in `DeviceRegistry`.
+/// Devices are shared between the server workers, so they must be `Send + Sync`
+pub trait Device: DeviceRead + DeviceWrite + DeviceConfirm + DeviceRequest + DeviceCommands + Send + Sync {}
+
+impl<T> Device for T where T: DeviceRead + DeviceWrite + DeviceConfirm + DeviceRequest + DeviceCommands + Send + Sync {}
@@ -1,5 +1,3 @@
-use std::convert::TryFrom;
-
 #[derive(Clone)]
 pub enum Devices {
     Zero = 0,
@@ -9,6 +7,7 @@ pub enum Devices {
     Blog = 4,
     Stat = 5,
     ECG = 6,
+    Account = 7,
     LEN,
 }
 
@@ -22,20 +21,26 @@ pub enum Groups {
     LEN,
 }
 
+impl Groups {
+    /// Kind of the group, which is required by the query type (`R`, `W`, `Q`, `C`, `D` or `S`)
+    pub fn from_qtype(qtype: &str) -> Option<Groups> {
+        match qtype {
+            "S" => Some(Groups::RStatus),
+            "R" => Some(Groups::Read),
+            "W" => Some(Groups::Write),
+            "Q" => Some(Groups::Request),
+            "C" => Some(Groups::Confirm),
+            "D" => Some(Groups::Dismiss),
+            _ => None
+        }
+    }
+}
+
 pub const GROUP_LEN: usize = Groups::LEN as usize;
 pub const DEVICES_LEN: usize = Devices::LEN as usize;
 
-const DEVICE_ARRAY: [Devices; DEVICES_LEN] = [
-    Devices::Zero,
-    Devices::Filer,
-    Devices::Root,
-    Devices::Printer,
-    Devices::Blog,
-    Devices::Stat,
-    Devices::ECG,
-];
-
-
+/// Names and groups of the built-in devices, `register_builtin_devices` puts them to `DeviceRegistry`.
+/// Other devices bring their own names and groups on registration
 pub const DEV_NAMES: [&'static str; DEVICES_LEN] = [
     "",
     "filer",
@@ -44,6 +49,7 @@ pub const DEV_NAMES: [&'static str; DEVICES_LEN] = [
     "blogdev",
     "statdev",
     "ecgdev",
+    "account",
 ];
 
 pub const DEV_GROUPS: [[Option<&'static str>; GROUP_LEN]; DEVICES_LEN] = [
@@ -55,8 +61,10 @@ pub const DEV_GROUPS: [[Option<&'static str>; GROUP_LEN]; DEVICES_LEN] = [
     [None, Some("blogdev_read"), Some("blogdev_write"), Some("blogdev_request"), None, None], // Blog device
     [None, Some("statdev_read"), None, None, None, None], // Stat device
     [None, Some("ecgdev_read"), None, None, None, None], // ECG device
+    [None, Some("account_read"), Some("account_write"), None, None, None], // Account device
 ];
 
+/// Returns groups of all the built-in devices
 pub fn list_all_groups() -> Vec<String> {
     let mut buffer: Vec<String> = vec![];
     for device in DEV_GROUPS {
@@ -69,15 +77,3 @@ pub fn list_all_groups() -> Vec<String> {
     }
     buffer
 }
-
-impl TryFrom<usize> for Devices {
-    type Error = ();
-
-    fn try_from(v: usize) -> Result<Self, Self::Error> {
-        if v < DEVICES_LEN {
-            Ok(DEVICE_ARRAY[v].clone())
-        } else {
-            Err(())
-        }
-    }
-}
\ No newline at end of file
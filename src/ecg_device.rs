@@ -2,13 +2,12 @@ use crate::device_trait::*;
 use crate::dashboard::QCommand;
 use crate::config::Config;
 use crate::devices::{Devices, Groups, DEV_GROUPS};
+use crate::command_spec::CommandSpec;
 
 use serde_json::{Value as jsVal, json, from_str as js_from_str};
 use reqwest;
 use log::{debug};
 
-use std::thread;
-
 
 #[derive(Clone)]
 pub struct EcgDevice {
@@ -19,6 +18,13 @@ impl EcgDevice {
     pub fn new(config: &Config) -> Self {
         EcgDevice { server_addr: config.ecg_server.clone() }
     }
+
+    /// Devices are called on the blocking thread pool, so the blocking client is fine here
+    fn fetch(&self, url: String) -> Result<String, String> {
+        reqwest::blocking::get(url)
+            .map_err(|err| { format!("Reqwest to ECG server failed at request: {:?}", err) })?.text()
+            .map_err(|err| { format!("Reqwest to ECG server failed at request: {:?}", err) })
+    }
 }
 
 impl DeviceRead for EcgDevice {
@@ -35,13 +41,7 @@ impl DeviceRead for EcgDevice {
 
         let lnurl = format!("{}/{}", self.server_addr.strip_suffix("/").unwrap_or(""), query.payload);
 
-        let body = thread::spawn(move || {
-            Ok(reqwest::blocking::get(lnurl)
-                .map_err(|err| { format!("Reqwest to ECG server failed at request: {:?}", err) })?.text()
-                .map_err(|err| { format!("Reqwest to ECG server failed at request: {:?}", err) })?)
-        }).join()
-            .map_err(|err| { format!("Reqwest to ECG server failed at article: {:?}", err) })?
-            .map_err(|err: String| { format!("Reqwest to ECG server failed at article: {}", err) })?;
+        let body = self.fetch(lnurl)?;
 
         let js_body: jsVal = js_from_str(&body).unwrap_or(json!([]));
 
@@ -64,13 +64,7 @@ impl DeviceRead for EcgDevice {
         }
         let lnurl = format!("{}/list", self.server_addr.strip_suffix("/").unwrap_or(""));
 
-        let body = thread::spawn(move || {
-            Ok(reqwest::blocking::get(lnurl)
-                .map_err(|err| { format!("Reqwest to ECG server failed at request: {:?}", err) })?.text()
-                .map_err(|err| { format!("Reqwest to ECG server failed at request: {:?}", err) })?)
-        }).join()
-            .map_err(|err| { format!("Reqwest to ECG server failed at article: {:?}", err) })?
-            .map_err(|err: String| { format!("Reqwest to ECG server failed at article: {:?}", err) })?;
+        let body = self.fetch(lnurl)?;
         let js_body: jsVal = js_from_str(&body).unwrap_or(json!([]));
 
         debug!("got: {}", body);
@@ -89,6 +83,13 @@ impl DeviceRead for EcgDevice {
 }
 
 
+/// `read` takes the path of the record on the ECG server
+impl DeviceCommands for EcgDevice {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new::<String>("R", "read").describe("Shows the ECG record").payload("path of the record")]
+    }
+}
+
 impl DeviceWrite for EcgDevice {
     fn write_data(&self, _query: &QCommand) -> Result<jsVal, String> {
         Err("Unimplemented".to_string())
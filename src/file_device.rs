@@ -3,165 +3,477 @@ use crate::dashboard::QCommand;
 use crate::io_tools::exists;
 use crate::devices::{Devices, Groups, DEV_GROUPS};
 use crate::database::Database;
+use crate::command_spec::{CommandSpec, parse_payload};
+use crate::config::Config;
+use crate::database::get_random_token;
+use crate::safe_path::{self, SafePath};
 
 use serde_json::Value as jsVal;
 use serde_json::json;
-use flate2::read::{GzDecoder, GzEncoder};
-use flate2::Compression;
-use log::{debug, info};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::{Compression, GzBuilder};
+use log::{debug, info, error};
 use urlencoding;
 
 use std::fs;
-use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::io::{self, BufReader, BufWriter, SeekFrom};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const UPLOAD_SUFFIX: &str = ".upload";
+/// Id of the gzip header subfield, which keeps the size of the unpacked file as little-endian u64,
+/// so the downloads know it without unpacking the whole file
+const SIZE_SUBFIELD: [u8; 2] = *b"WS";
+/// 10 bytes of the fixed gzip header, length of the extra field, id and length of the subfield
+const SIZE_OFFSET: u64 = 16;
+/// Magic bytes of the types, which are not known by the extension: (offset, bytes, MIME type)
+const MAGIC_TYPES: [(usize, &[u8], &str); 15] = [
+    (0, b"\x89PNG", "image/png"),
+    (0, b"\xFF\xD8\xFF", "image/jpeg"),
+    (0, b"GIF8", "image/gif"),
+    (0, b"%PDF", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1F\x8B", "application/gzip"),
+    (0, b"7z\xBC\xAF\x27\x1C", "application/x-7z-compressed"),
+    (0, b"\x1A\x45\xDF\xA3", "video/webm"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"ID3", "audio/mpeg"),
+    (8, b"WAVE", "audio/wav"),
+    (8, b"AVI ", "video/x-msvideo"),
+    (8, b"WEBP", "image/webp"),
+    (0x8001, b"CD001", "application/x-iso9660-image"),
+];
+const MAGIC_HEAD_LEN: usize = 0x8006;
+
+/// Payload of `delete` and `rmdir`. Directory with files is deleted only with `confirm`
+#[derive(Deserialize)]
+pub struct FilerPathPayload {
+    pub path: String,
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// Payload of `rename`, `name` is the new name without the directory
+#[derive(Deserialize)]
+pub struct FilerRenamePayload {
+    pub path: String,
+    pub name: String,
+}
+
+/// Payload of `move` and `copy`, `to` is the directory
+#[derive(Deserialize)]
+pub struct FilerTargetPayload {
+    pub path: String,
+    pub to: String,
+}
+
+/// Why the upload has been stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadError {
+    /// File is bigger than `filer_max_upload_mb`, the limit is in bytes
+    TooLarge(u64),
+    /// Compressed file doesn't fit into what is left of the quota: (quota, bytes left), both in bytes
+    OverQuota(u64, u64),
+    /// Filesystem or compression has failed
+    Io(String),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::TooLarge(limit) => write!(f, "File is bigger than {} bytes", limit),
+            UploadError::OverQuota(quota, left) => write!(f, "Storage quota of {} is exceeded, only {} is left",
+                                                          format_size(*quota), format_size(*left)),
+            UploadError::Io(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Counts the bytes, which go to the disk
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// File, which is being uploaded. Chunks go through the gzip encoder to the temp file next to the target,
+/// which replaces the target only in `finish`, so nobody sees a half-written file and concurrent uploads
+/// of the same path don't mix. Temp file of the upload, which is dropped unfinished, is removed
+pub struct FileUpload {
+    target: PathBuf,
+    temp: PathBuf,
+    encoder: Option<GzEncoder<CountingWriter<BufWriter<fs::File>>>>,
+    received: u64,
+    max_size: Option<u64>,
+    /// Quota of the user and how much of it was left at the start, if `target` is replaced.
+    /// Chunks are checked against it, `finish` checks the quota again with the current usage
+    quota: Option<(u64, u64)>,
+    database: Database,
+    username: String,
+    /// Storage of the user, its usage is counted again, if it has been reset during the upload
+    container: PathBuf,
+}
+
+impl FileUpload {
+    /// Compresses and writes the next chunk. Limits are checked before anything is written
+    pub fn write(&mut self, data: &[u8]) -> Result<(), UploadError> {
+        self.received += data.len() as u64;
+        if let Some(max_size) = self.max_size {
+            if self.received > max_size {
+                return Err(UploadError::TooLarge(max_size));
+            }
+        }
+        let encoder = self.encoder.as_mut().ok_or(UploadError::Io("Upload is already finished".to_string()))?;
+        encoder.write_all(data)
+            .map_err(|err| UploadError::Io(format!("Error on writing the file: {:?}", err)))?;
+        match self.quota {
+            Some((quota, left)) if encoder.get_ref().count > left => Err(UploadError::OverQuota(quota, left)),
+            _ => Ok(())
+        }
+    }
+
+    /// Flushes the encoder and moves the file to its place, the usage of the user is changed by the difference
+    /// of the compressed sizes. With the quota, the difference is added to the usage before the move,
+    /// only if it still fits, so parallel uploads can't exceed the quota. Returns number of received bytes
+    pub fn finish(mut self) -> Result<u64, UploadError> {
+        let encoder = self.encoder.take().ok_or(UploadError::Io("Upload is already finished".to_string()))?;
+        let writer = encoder.finish()
+            .map_err(|err| UploadError::Io(format!("Error on compressing the file: {:?}", err)))?;
+        if let Some((quota, left)) = self.quota {
+            if writer.count > left {
+                return Err(UploadError::OverQuota(quota, left));
+            }
+        }
+        let stored = writer.count;
+        let mut file = writer.inner.into_inner()
+            .map_err(|err| UploadError::Io(format!("Error on writing the file: {:?}", err.error())))?;
+        file.seek(SeekFrom::Start(SIZE_OFFSET))
+            .and_then(|_| file.write_all(&self.received.to_le_bytes()))
+            .map_err(|err| UploadError::Io(format!("Error on writing the size of the file: {:?}", err)))?;
+        file.sync_all()
+            .map_err(|err| UploadError::Io(format!("Error on syncing the file: {:?}", err)))?;
+        let replaced = fs::metadata(&self.target).map(|x| x.len()).unwrap_or(0);
+        let delta = stored as i64 - replaced as i64;
+        if let Some((quota, _)) = self.quota {
+            reserve_usage(&self.database, &self.username, &self.container, quota, delta, replaced)?;
+        }
+        if let Err(err) = fs::rename(&self.temp, &self.target) {
+            if self.quota.is_some() {
+                if let Err(err) = self.database.add_filer_usage(&self.username, -delta) {
+                    error!("Error on returning the filer usage of `{}`: {}", self.username, err);
+                }
+            }
+            return Err(UploadError::Io(format!("Error on moving the file to its place: {:?}", err)));
+        }
+        info!("Uploaded `{}`: {} bytes", self.target.display(), self.received);
+        if self.quota.is_none() {
+            // file is already in its place, so the wrong usage is only logged, it is fixed by `reset_filer_usage`
+            if let Err(err) = self.database.add_filer_usage(&self.username, delta) {
+                error!("Error on updating the filer usage of `{}`: {}", self.username, err);
+            }
+        }
+        Ok(self.received)
+    }
+}
+
+/// Adds `delta` bytes to the usage of the user, if they fit into the quota. Usage, which has been reset,
+/// is counted from the `container` first. `replaced` is the size of the file, which is replaced, for the error
+fn reserve_usage(database: &Database, username: &str, container: &Path, quota: u64, delta: i64, replaced: u64) -> Result<(), UploadError> {
+    let db_error = |err: String| UploadError::Io(format!("Error on updating the filer usage: {}", err));
+    let mut added = database.add_filer_usage_within(username, delta, quota as i64).map_err(db_error)?;
+    if added.is_none() {
+        database.set_filer_usage(username, get_dir_size(container) as i64).map_err(db_error)?;
+        added = database.add_filer_usage_within(username, delta, quota as i64).map_err(db_error)?;
+    }
+    if added == Some(true) {
+        return Ok(());
+    }
+    let used = database.get_filer_usage(username).map_err(db_error)?.unwrap_or(0) as u64;
+    Err(UploadError::OverQuota(quota, quota.saturating_sub(used.saturating_sub(replaced))))
+}
 
-struct BufferedFile {
-    pub data: Vec<u8>,
+impl Drop for FileUpload {
+    fn drop(&mut self) {
+        if self.temp.exists() {
+            if let Err(err) = fs::remove_file(&self.temp) {
+                error!("Error on removing unfinished upload `{}`: {:?}", self.temp.display(), err);
+            }
+        }
+    }
+}
+
+/// Returns size of all the files in the directory, including subdirectories. Unfinished uploads are not counted
+fn get_dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0
+    };
+    entries.filter_map(|x| x.ok())
+        .filter(|entry| !is_upload_temp(&entry.file_name().to_string_lossy()))
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => get_dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0
+        }).sum()
+}
+
+/// File of the filer, opened for the download. Sizes and offsets are of the unpacked content
+pub struct FileDownload {
+    path: PathBuf,
+    pub filename: String,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Changes together with the file, without quotes
+    pub etag: String,
+    pub mime: String,
+}
+
+impl FileDownload {
+    /// Returns the unpacked content, starting at `start`. Gzip can't seek, so the skipped part is unpacked as well
+    pub fn read_from(&self, start: u64) -> Result<Box<dyn Read + Send>, String> {
+        let file = fs::File::open(&self.path).map_err(|err| format!("Error on opening the file: {:?}", err))?;
+        let mut decoder = GzDecoder::new(BufReader::new(file));
+        let skipped = io::copy(&mut (&mut decoder).take(start), &mut io::sink())
+            .map_err(|err| format!("Error on decompressing the file: {:?}", err))?;
+        if skipped < start {
+            return Err(format!("File is shorter than {} bytes", start));
+        }
+        Ok(Box::new(decoder))
+    }
+}
+
+/// Reads the size of the unpacked file from the header, see `SIZE_SUBFIELD`
+fn read_unpacked_size(file: &mut fs::File) -> Option<u64> {
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header).ok()?;
+    // magic bytes and FEXTRA flag
+    if header[0..2] != [0x1f, 0x8b] || header[3] & 0x04 == 0 {
+        return None;
+    }
+    let mut extra = vec![0u8; u16::from_le_bytes([header[10], header[11]]) as usize];
+    file.read_exact(&mut extra).ok()?;
+    let mut rest = &extra[..];
+    while rest.len() >= 4 {
+        let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let data = rest.get(4..4 + len)?;
+        if rest[0..2] == SIZE_SUBFIELD && len == 8 {
+            let mut size = [0u8; 8];
+            size.copy_from_slice(data);
+            return Some(u64::from_le_bytes(size));
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+/// Detects MIME type by the first bytes of the file, see `MAGIC_TYPES`. Text is `text/plain`
+fn sniff_mime(head: &[u8]) -> &'static str {
+    for (offset, magic, mime) in MAGIC_TYPES.iter() {
+        if head.get(*offset..offset + magic.len()) == Some(magic) {
+            return mime;
+        }
+    }
+    // the head may end in the middle of the character
+    let is_utf8 = std::str::from_utf8(head).map(|_| true).unwrap_or_else(|err| err.error_len().is_none());
+    if is_utf8 && !head.contains(&0) {
+        return "text/plain; charset=utf-8";
+    }
+    "application/octet-stream"
 }
 
-/// Contains path to the storage, and buffered files. And yes, files are stored in the RAM before
-/// actual writing.
+/// Contains path to the storage and limits of the uploads. Files are stored gzipped
 #[derive(Clone)]
 pub struct FileDevice {
     storage: String,
-    buffered_files: Arc<Mutex<BTreeMap<String, BufferedFile>>>,
     database: Database,
+    max_upload_size: Option<u64>,
+    quota: Option<u64>,
+    /// Unpacked sizes of the files without `SIZE_SUBFIELD`: path -> (ETag, size)
+    legacy_sizes: Arc<RwLock<HashMap<PathBuf, (String, u64)>>>,
 }
 
 impl FileDevice {
     /// Creates new instance of FileDevice
-    pub fn new(database: &Database) -> FileDevice {
+    pub fn new(database: &Database, config: &Config) -> Result<FileDevice, String> {
         let store = "filer".to_string();
         if !exists(&store) {
-            fs::create_dir(&store).unwrap();
+            fs::create_dir(&store).map_err(|err| format!("Error on creating the storage `{}`: {:?}", store, err))?;
         }
-        FileDevice {
+        let megabytes = |value: u32| if value == 0 { None } else { Some(value as u64 * 1024 * 1024) };
+        Ok(FileDevice {
             storage: store,
-            buffered_files: Arc::new(Mutex::new(BTreeMap::new())),
             database: database.clone(),
-        }
+            max_upload_size: megabytes(config.filer_max_upload_mb),
+            quota: megabytes(config.filer_quota_mb),
+            legacy_sizes: Arc::new(RwLock::new(HashMap::new())),
+        })
     }
 
-    /// Returns content of the file as vector of bytes
-    pub fn get_file(&self, username: &str, payload: &str) -> Result<Vec<u8>, String> {
-        debug!("Trying to open the file");
-        if payload.contains("..") {
-            return Err("Forbidden path".to_string());
-        }
-        let filepath = format!("{}/{}", &self.storage, username);
-        if !exists(&filepath) {
+    /// Returns the path, the modification time and the ETag of the file (url-encoded path) of the user
+    fn stat_file(&self, username: &str, payload: &str) -> Result<(PathBuf, SystemTime, String), String> {
+        let path = self.resolve_encoded(username, payload)?.full();
+        if !self.get_container(username).exists() {
             return Err("No container was found".to_string());
         }
-        let mut file = match fs::File::open(
-            &format!("{}/{}", filepath, urlencoding::decode(payload).map_err(|_| { format!("Couldn't decode payload: `{}`", payload) })?)) {
-            Ok(f) => f,
-            Err(e) => return Err(format!("Error on opening the file: {:?}", e))
-        };
-
-        debug!("Start reading the file");
-        let mut file_data: Vec<u8> = vec![];
-        match file.read_to_end(&mut file_data) {
-            Ok(_) => {
-                let mut decoder = GzDecoder::new(&file_data[..]);
-                let mut decompressed: Vec<u8> = vec![];
-                match decoder.read_to_end(&mut decompressed) {
-                    Ok(_) => {
-                        info!("Size of decompressed: {}", decompressed.len());
-                        return Ok(decompressed);
-                    }
-                    Err(e) => return Err(format!("Error on decompressing the file: {}", e)),
-                }
-            }
-            Err(e) => return Err(format!("Error on reading the file: {:?}", e))
-        };
+        let metadata = fs::metadata(&path).map_err(|err| format!("Error on opening the file: {:?}", err))?;
+        if !metadata.is_file() {
+            return Err("Not a file".to_string());
+        }
+        let modified = metadata.modified().map_err(|err| format!("Error on reading the file time: {:?}", err))?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let etag = format!("{:x}-{:x}-{:x}", since_epoch.as_secs(), since_epoch.subsec_nanos(), metadata.len());
+        Ok((path, modified, etag))
     }
 
-    /// Writes content to the the RAM
-    pub fn write_file(&self, username: &str, payload: &str, data: &[u8]) -> Result<(), String> {
-        if payload.contains("..") {
-            return Err("Wrong symbols were supplied".to_string());
-        }
-        let filepath = format!("{}/{}/{}",
-                               &self.storage,
-                               username,
-                               urlencoding::decode(payload).map_err(|_| { format!("Couldn't decode payload: `{}`", payload) })?);
-        self.buffered_files.lock()
-            .map(move |mut x| {
-                match x.get_mut(&filepath) {
-                    Some(f) => {
-                        debug!("Adding bytes: {}", data.len());
-                        f.data.extend_from_slice(data);
-                        info!("Added bytes: {}; Total: {}", data.len(), f.data.len());
-                        return ();
-                    }
-                    None => {
-                        debug!("Initial Adding bytes: {}", data.len());
-                        x.insert(filepath.clone(), BufferedFile { data: data.to_vec() });
-                        debug!("Wrote...");
-                        return ();
-                    }
-                };
-            }).map_err(|_x| {
-            return format!("Internal error");
-        })
+    /// Returns the modification time and the ETag of the file (url-encoded path) of the user.
+    /// The file isn't read, so the conditional requests are answered before `open_file`
+    pub fn get_file_version(&self, username: &str, payload: &str) -> Result<(SystemTime, String), String> {
+        self.stat_file(username, payload).map(|(_, modified, etag)| (modified, etag))
     }
 
-    /// Writes file from buffer to the disk after being compressed
-    pub fn finish_file(&self, username: &str, payload: &str, directory: &str) -> Result<(), String> {
-        let filepath = format!("{}/{}", &self.storage, username);
+    /// Files, uploaded by the older versions, don't have the size in the header. Such file is unpacked once,
+    /// and its size is kept until the file changes
+    fn get_legacy_size(&self, download: &FileDownload) -> Result<u64, String> {
+        let cached = self.legacy_sizes.read()
+            .map_err(|err| format!("Error on reading the size cache: {:?}", err))?
+            .get(&download.path)
+            .filter(|(etag, _)| *etag == download.etag)
+            .map(|(_, size)| *size);
+        if let Some(size) = cached {
+            return Ok(size);
+        }
 
-        let mut file = if exists(&format!("{}/{}", filepath, directory)) {
-            match fs::File::create(format!("{}/{}", filepath, payload)) {
-                Ok(f) => f,
-                Err(e) => return Err(format!("Error on creating the file: {:?}", e))
-            }
-        } else {
-            match fs::create_dir_all(format!("{}/{}", filepath, directory)) {
-                Ok(_) => (),
-                Err(e) => return Err(format!("Error on making the directories: {:?}", e))
-            };
-            match fs::File::create(format!("{}/{}", filepath, payload)) {
-                Ok(f) => f,
-                Err(e) => return Err(format!("Error on creating the file: {:?}", e))
+        let size = io::copy(&mut download.read_from(0)?, &mut io::sink())
+            .map_err(|err| format!("Error on decompressing the file: {:?}", err))?;
+        self.legacy_sizes.write()
+            .map_err(|err| format!("Error on writing the size cache: {:?}", err))?
+            .insert(download.path.clone(), (download.etag.clone(), size));
+        Ok(size)
+    }
+
+    /// Opens the file (url-encoded path) of the user for reading
+    pub fn open_file(&self, username: &str, payload: &str) -> Result<FileDownload, String> {
+        let (path, modified, etag) = self.stat_file(username, payload)?;
+        let mut download = FileDownload {
+            filename: path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default(),
+            size: 0,
+            modified,
+            etag,
+            mime: String::new(),
+            path,
+        };
+        let mut file = fs::File::open(&download.path).map_err(|err| format!("Error on opening the file: {:?}", err))?;
+        download.size = match read_unpacked_size(&mut file) {
+            Some(size) => size,
+            None => self.get_legacy_size(&download)?
+        };
+        download.mime = match mime_guess::from_path(&download.path).first_raw() {
+            Some(mime) => mime.to_string(),
+            None => {
+                let mut head: Vec<u8> = vec![];
+                download.read_from(0)?.take(MAGIC_HEAD_LEN as u64).read_to_end(&mut head)
+                    .map_err(|err| format!("Error on decompressing the file: {:?}", err))?;
+                sniff_mime(&head).to_string()
             }
         };
+        Ok(download)
+    }
 
-        let res: Result<Result<(), String>, String> = self.buffered_files.lock().map(move |mut x| {
-            debug!("Start writing");
-            let bf_data = match x.remove(&format!("{}/{}", filepath, payload)) {
-                Some(f) => f,
-                None => return Err("No data to write".to_string())
-            };
+    /// Returns content of the file as vector of bytes
+    pub fn get_file(&self, username: &str, payload: &str) -> Result<Vec<u8>, String> {
+        let download = self.open_file(username, payload)?;
+        let mut decompressed: Vec<u8> = Vec::with_capacity(download.size as usize);
+        download.read_from(0)?.read_to_end(&mut decompressed)
+            .map_err(|err| format!("Error on decompressing the file: {}", err))?;
+        info!("Size of decompressed: {}", decompressed.len());
+        Ok(decompressed)
+    }
 
+    /// Returns the quota of the user in bytes, `None` is unlimited. Quotas of the user and the user's groups
+    /// are set by the root device, `filer_quota_mb` is used for the users without them
+    pub fn get_quota(&self, username: &str) -> Result<Option<u64>, String> {
+        Ok(match self.database.get_filer_quota(username)? {
+            Some(0) => None,
+            Some(limit) => Some(limit as u64),
+            None => self.quota
+        })
+    }
 
-            let data = &bf_data.data;
-            info!("Total file len: {}", data.len());
-            let mut file_compressed: Vec<u8> = vec![];
-            let mut encoder = GzEncoder::new(&data[..], Compression::best());
-            match encoder.read_to_end(&mut file_compressed) {
-                Ok(_) => (),
-                Err(e) => return Err(format!("Error on compressing the file: {:?}", e))
-            };
+    /// Returns the compressed size of the user's files. It is counted on the disk only for the first time,
+    /// then uploads keep it up to date
+    pub fn get_usage(&self, username: &str) -> Result<u64, String> {
+        if let Some(used) = self.database.get_filer_usage(username)? {
+            return Ok(used as u64);
+        }
+        let used = get_dir_size(Path::new(&self.storage).join(username).as_path());
+        self.database.set_filer_usage(username, used as i64)?;
+        Ok(used)
+    }
 
-            match file.write_all(&file_compressed) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Error on writing the file: {:?}", e))
-            }
-        }).map_err(|x| {
-            return format!("Error on finishing the file: {}", x);
-        });
+    /// Returns the quota and how many bytes the user may add to the storage, if `target` is replaced.
+    /// `None` is unlimited
+    fn get_quota_left(&self, username: &str, target: &Path) -> Result<Option<(u64, u64)>, String> {
+        let quota = match self.get_quota(username)? {
+            Some(quota) => quota,
+            None => return Ok(None)
+        };
+        let used = self.get_usage(username)?;
+        let replaced = fs::metadata(target).map(|x| x.len()).unwrap_or(0);
+        Ok(Some((quota, quota.saturating_sub(used.saturating_sub(replaced)))))
+    }
 
-        match res {
-            Ok(another) => another,
-            Err(e) => Err(e)
-        }
+    /// Starts the upload of the file to the `path` in the user's storage, see `resolve`.
+    /// Missing directories are created
+    pub fn start_upload(&self, username: &str, path: &SafePath) -> Result<FileUpload, String> {
+        let filename = match path.name() {
+            Some(filename) if !is_upload_temp(filename) => filename.to_string(),
+            _ => return Err(format!("Bad path of the file: `{}`", path.display()))
+        };
+        let (target, directory) = (path.full(), path.parent().full());
+        let quota = self.get_quota_left(username, &target)?;
+        fs::create_dir_all(&directory)
+            .map_err(|err| format!("Error on making the directories: {:?}", err))?;
+
+        let temp = directory.join(format!(".{}.{}{}", filename, &get_random_token()[..16], UPLOAD_SUFFIX));
+        let file = fs::File::create(&temp)
+            .map_err(|err| format!("Error on creating the file: {:?}", err))?;
+        debug!("Started upload of `{}` to `{}`", target.display(), temp.display());
+        Ok(FileUpload {
+            quota,
+            database: self.database.clone(),
+            username: username.to_string(),
+            container: self.get_container(username),
+            target,
+            temp,
+            encoder: Some(GzBuilder::new()
+                .extra(vec![SIZE_SUBFIELD[0], SIZE_SUBFIELD[1], 8, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+                .write(CountingWriter { inner: BufWriter::new(file), count: 0 }, Compression::best())),
+            received: 0,
+            max_size: self.max_upload_size,
+        })
     }
 
     /// Returns the list of files
     fn get_list(&self, username: &str, payload: &str) -> Result<jsVal, String> {
-        let filepath = format!("{}/{}", &self.storage, username);
-        if !exists(&filepath) {
+        let filepath = self.get_container(username);
+        if !filepath.exists() {
             if !self.database.has_access_to_group(username, DEV_GROUPS[Devices::Filer as usize][Groups::Write as usize].unwrap())
                 .unwrap_or(false) {
                 return Err("No storage for current user and no access for creating it".to_string());
@@ -171,23 +483,24 @@ impl FileDevice {
                 Err(err) => return Err(format!("No container was found and couldn't create new: {:?}", err))
             };
         }
-        let paths = urlencoding::decode(payload).map_err(|_| { format!("Couldn't decode payload: `{}`", payload) })?.to_string();
-        if paths.contains("..") {
-            return Err("Bad request".to_string());
-        }
-        let full_path = format!("{}/{}", filepath, paths);
-        let mut entries: Vec<jsVal> = match fs::read_dir(&full_path) {
-            Ok(f) => f.filter(|x| x.is_ok()).map(|x| {
+        let path = self.resolve_encoded(username, payload)?;
+        let paths = path.display();
+        let mut entries: Vec<jsVal> = match fs::read_dir(path.full()) {
+            Ok(f) => f.filter(|x| x.as_ref().map(|d| !is_upload_temp(&d.file_name().to_string_lossy())).unwrap_or(false)).map(|x| {
                 match x {
                     Ok(d) => {
                         if d.path().is_file() {
+                            let fname = d.file_name().to_string_lossy().to_string();
                             json!({
                                 "isfile": 1,
-                                "filename": d.file_name().to_string_lossy()
+                                "editable": 1,
+                                "full_path": urlencoding::encode(&format!("{}/{}", paths, fname)),
+                                "filename": fname
                             })
                         } else {
                             let fname = d.file_name().to_string_lossy().to_string();
                             json!({
+                                "editable": 1,
                                 "full_path": urlencoding::encode(&format!("{}/{}", paths, fname)),
                                 "display": fname,
                             })
@@ -199,13 +512,12 @@ impl FileDevice {
             Err(e) => return Err(format!("Error on opening the directory: {:?}", e))
         };
         entries.insert(0, json!({
-                                "full_path": &paths,
+                                "full_path": urlencoding::encode(&paths),
                                 "display": "."
                             }));
-        let last = paths.rfind("/");
-        if last.is_some() {
+        if !path.is_root() {
             entries.insert(1, json!({
-                                "full_path": &paths[..last.unwrap()],
+                                "full_path": urlencoding::encode(&path.parent().display()),
                                 "display": ".."
                             }));
         }
@@ -214,17 +526,16 @@ impl FileDevice {
         Ok(json!({
             "template": "file_device.hbs",
             "prepath": paths,
-            "prepath_fx": payload,
+            "prepath_fx": urlencoding::encode(&paths),
             "username": username,
             "entries": entries
         }))
     }
 
     fn create_dir(&self, username: &str, payload: &str) -> Result<jsVal, String> {
-        let filepath = format!("{}/{}", &self.storage, username);
-        let paths = urlencoding::decode(payload).map_err(|_| { format!("Couldn't decode payload: `{}`", payload) })?.to_string();
-        debug!("Create {}/{}", filepath, paths);
-        match fs::create_dir_all(format!("{}/{}", filepath, paths)) {
+        let path = self.resolve_encoded(username, payload)?.full();
+        debug!("Create {}", path.display());
+        match fs::create_dir_all(&path) {
             Ok(_) => return Ok(match self.get_list(username, payload) {
                 Ok(r) => r,
                 Err(e) => return Err(format!("Error on getting list after created the dir: {}", e))
@@ -232,6 +543,208 @@ impl FileDevice {
             Err(e) => return Err(format!("Error on making the directories: {:?}", e))
         };
     }
+
+    /// Returns the container of the user, which keeps all the user's files
+    fn get_container(&self, username: &str) -> PathBuf {
+        Path::new(&self.storage).join(username)
+    }
+
+    /// Returns the path (not url-encoded) in the user's storage. All the paths from the user must go through it
+    /// or through `resolve_encoded`, see `SafePath`
+    pub fn resolve(&self, username: &str, path: &str) -> Result<SafePath, String> {
+        SafePath::new(&self.get_container(username), path)
+    }
+
+    /// Returns the url-encoded path in the user's storage
+    pub fn resolve_encoded(&self, username: &str, payload: &str) -> Result<SafePath, String> {
+        SafePath::from_encoded(&self.get_container(username), payload)
+    }
+
+    /// Resolves the url-encoded path of the existing file or directory, which may be changed.
+    /// Storage itself can't be changed
+    fn resolve_existing(&self, username: &str, payload: &str) -> Result<SafePath, String> {
+        let path = self.resolve_encoded(username, payload)?;
+        if path.is_root() {
+            return Err("Storage itself can't be changed".to_string());
+        }
+        if fs::symlink_metadata(path.full()).is_err() {
+            return Err(format!("No such file or directory: `{}`", path.display()));
+        }
+        Ok(path)
+    }
+
+    /// Lists the directory, which contains the path
+    fn list_parent(&self, username: &str, path: &SafePath) -> Result<jsVal, String> {
+        self.get_list(username, &urlencoding::encode(&path.parent().display()))
+    }
+
+    /// Deletes the file. Symlink is deleted itself, its size is counted the same way as in the usage
+    fn delete_file(&self, username: &str, data: FilerPathPayload) -> Result<jsVal, String> {
+        let path = self.resolve_existing(username, &data.path)?;
+        let full_path = path.full();
+        let metadata = fs::symlink_metadata(&full_path).map_err(|err| format!("Error on deleting the file: {:?}", err))?;
+        if metadata.is_dir() {
+            return Err(format!("`{}` is not a file, use rmdir", path.display()));
+        }
+        fs::remove_file(&full_path).map_err(|err| format!("Error on deleting the file: {:?}", err))?;
+        info!("`{}` has deleted `{}`", username, full_path.display());
+        self.database.add_filer_usage(username, -(metadata.len() as i64))?;
+        self.list_parent(username, &path)
+    }
+
+    /// Deletes the directory. Directory with files is deleted only with `confirm`,
+    /// otherwise the listing asks to confirm it. Symlink to the directory is not a directory, it is deleted by `delete_file`
+    fn remove_dir(&self, username: &str, data: FilerPathPayload) -> Result<jsVal, String> {
+        let path = self.resolve_existing(username, &data.path)?;
+        let full_path = path.full();
+        let metadata = fs::symlink_metadata(&full_path).map_err(|err| format!("Error on opening the directory: {:?}", err))?;
+        if !metadata.is_dir() {
+            return Err(format!("`{}` is not a directory, use delete", path.display()));
+        }
+        let is_empty = fs::read_dir(&full_path).map(|mut x| x.next().is_none())
+            .map_err(|err| format!("Error on opening the directory: {:?}", err))?;
+        let size = get_dir_size(&full_path);
+        if !is_empty && !data.confirm {
+            let mut listing = self.list_parent(username, &path)?;
+            listing["confirm_rmdir"] = json!({
+                "path": urlencoding::encode(&path.display()),
+                "display": path.display(),
+                "files": count_files(&full_path),
+                "size": format_size(size)
+            });
+            return Ok(listing);
+        }
+        fs::remove_dir_all(&full_path).map_err(|err| format!("Error on deleting the directory: {:?}", err))?;
+        info!("`{}` has deleted `{}` with everything inside", username, full_path.display());
+        self.database.add_filer_usage(username, -(size as i64))?;
+        self.list_parent(username, &path)
+    }
+
+    /// Gives the new name to the file or directory, it stays in the same directory
+    fn rename(&self, username: &str, data: FilerRenamePayload) -> Result<jsVal, String> {
+        let path = self.resolve_existing(username, &data.path)?;
+        let name = safe_path::decode(&data.name)?;
+        if is_upload_temp(&name) {
+            return Err(format!("Bad name: `{}`", name));
+        }
+        let target = path.with_name(&name)?.full();
+        if target.exists() {
+            return Err(format!("`{}` already exists", name));
+        }
+        fs::rename(path.full(), &target).map_err(|err| format!("Error on renaming: {:?}", err))?;
+        info!("`{}` has renamed `{}` to `{}`", username, path.full().display(), name);
+        self.list_parent(username, &path)
+    }
+
+    /// Returns the place of the file or directory in the `to` directory (url-encoded).
+    /// Directory can't be put inside itself
+    fn resolve_target(&self, username: &str, source: &SafePath, to: &str) -> Result<PathBuf, String> {
+        let directory = self.resolve_encoded(username, to)?;
+        if !directory.full().is_dir() {
+            return Err(format!("No such directory: `{}`", directory.display()));
+        }
+        if source.contains(&directory) {
+            return Err("Directory can't be put inside itself".to_string());
+        }
+        let name = source.name().ok_or("Bad path of the source".to_string())?;
+        let target = directory.join_name(name)?.full();
+        if target.exists() {
+            return Err(format!("`{}` already exists in `{}`", name, directory.display()));
+        }
+        Ok(target)
+    }
+
+    /// Moves the file or directory to another directory
+    fn move_to(&self, username: &str, data: FilerTargetPayload) -> Result<jsVal, String> {
+        let path = self.resolve_existing(username, &data.path)?;
+        let (full_path, target) = (path.full(), self.resolve_target(username, &path, &data.to)?);
+        fs::rename(&full_path, &target).map_err(|err| format!("Error on moving: {:?}", err))?;
+        info!("`{}` has moved `{}` to `{}`", username, full_path.display(), target.display());
+        self.list_parent(username, &path)
+    }
+
+    /// Copies the file or directory with everything inside to another directory. Copy is counted in the quota
+    fn copy_to(&self, username: &str, data: FilerTargetPayload) -> Result<jsVal, String> {
+        let path = self.resolve_existing(username, &data.path)?;
+        let (full_path, target) = (path.full(), self.resolve_target(username, &path, &data.to)?);
+        let metadata = fs::symlink_metadata(&full_path).map_err(|err| format!("Error on copying: {:?}", err))?;
+        if metadata.file_type().is_symlink() {
+            return Err(format!("`{}` is a symlink, it can't be copied", path.display()));
+        }
+        let size = if metadata.is_dir() { get_dir_size(&full_path) } else { metadata.len() };
+        // with the quota, the copy is counted before it is made, so parallel commands can't exceed the quota
+        let quota = self.get_quota(username)?;
+        if let Some(quota) = quota {
+            reserve_usage(&self.database, username, &self.get_container(username), quota, size as i64, 0)
+                .map_err(|err| err.to_string())?;
+        }
+        if let Err(err) = copy_all(&full_path, &target) {
+            // partial copy is not left behind
+            let _ = if target.is_dir() { fs::remove_dir_all(&target) } else { fs::remove_file(&target) };
+            if quota.is_some() {
+                self.database.add_filer_usage(username, -(size as i64))?;
+            }
+            return Err(format!("Error on copying: {:?}", err));
+        }
+        info!("`{}` has copied `{}` to `{}`", username, full_path.display(), target.display());
+        if quota.is_none() {
+            self.database.add_filer_usage(username, size as i64)?;
+        }
+        self.list_parent(username, &path)
+    }
+}
+
+
+/// Returns number of files in the directory, including subdirectories
+fn count_files(path: &Path) -> usize {
+    match fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|x| x.ok()).map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => count_files(&entry.path()),
+            _ => 1
+        }).sum(),
+        Err(_) => 0
+    }
+}
+
+/// Copies the file or the directory with everything inside. Unfinished uploads are not copied, symlinks fail the copy
+fn copy_all(source: &Path, target: &Path) -> io::Result<()> {
+    let kind = fs::symlink_metadata(source)?.file_type();
+    // symlinks inside the directories aren't checked by `SafePath`, they may lead out of the storage
+    if kind.is_symlink() {
+        return Err(io::Error::other(format!("`{}` is a symlink", source.display())));
+    }
+    if !kind.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if !is_upload_temp(&entry.file_name().to_string_lossy()) {
+            copy_all(&entry.path(), &target.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats the number of bytes for people: `512 B`, `1.5 MB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Unfinished uploads are hidden from the listing
+fn is_upload_temp(filename: &str) -> bool {
+    filename.starts_with('.') && filename.ends_with(UPLOAD_SUFFIX)
 }
 
 
@@ -253,7 +766,14 @@ impl DeviceRead for FileDevice {
         if query.group != DEV_GROUPS[Devices::Zero as usize][Groups::RStatus as usize].unwrap() {
             return Err("No access to this action".to_string());
         }
-        self.get_list(&query.username, &query.payload)
+        let mut status = self.get_list(&query.username, &query.payload)?;
+        let used = self.get_usage(&query.username)?;
+        status["used"] = json!(format_size(used));
+        if let Some(quota) = self.get_quota(&query.username)? {
+            status["quota"] = json!(format_size(quota));
+            status["available"] = json!(format_size(quota.saturating_sub(used)));
+        }
+        Ok(status)
     }
 }
 
@@ -268,12 +788,46 @@ impl DeviceWrite for FileDevice {
 
         match command {
             "createdir" => self.create_dir(&query.username, &query.payload),
-            _ => return Err(format!("Unknown for FileDevice.read command: {}", command))
+            "delete" => self.delete_file(&query.username, parse_payload(&query.payload)?),
+            "rmdir" => self.remove_dir(&query.username, parse_payload(&query.payload)?),
+            "rename" => self.rename(&query.username, parse_payload(&query.payload)?),
+            "move" => self.move_to(&query.username, parse_payload(&query.payload)?),
+            "copy" => self.copy_to(&query.username, parse_payload(&query.payload)?),
+            _ => Err(format!("Unknown for FileDevice.write command: {}", command))
         }
     }
 }
 
 
+/// Paths are url-encoded and relative to the filer root
+impl DeviceCommands for FileDevice {
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new::<String>("R", "getlist")
+                .describe("Lists the directory")
+                .payload("path of the directory"),
+            CommandSpec::new::<String>("W", "createdir")
+                .describe("Creates the directory")
+                .payload("path of the new directory"),
+            CommandSpec::new::<FilerPathPayload>("W", "delete")
+                .describe("Deletes the file")
+                .payload(r#"{"path"}"#),
+            CommandSpec::new::<FilerPathPayload>("W", "rmdir")
+                .describe("Deletes the directory, the one with files only with confirm")
+                .payload(r#"{"path", "confirm": true}"#),
+            CommandSpec::new::<FilerRenamePayload>("W", "rename")
+                .describe("Renames the file or directory")
+                .payload(r#"{"path", "name"}"#),
+            CommandSpec::new::<FilerTargetPayload>("W", "move")
+                .describe("Moves the file or directory to another directory")
+                .payload(r#"{"path", "to"}"#),
+            CommandSpec::new::<FilerTargetPayload>("W", "copy")
+                .describe("Copies the file or directory to another directory")
+                .payload(r#"{"path", "to"}"#),
+        ]
+    }
+}
+
 impl DeviceRequest for FileDevice {
     fn request_query(&self, _query: &QCommand) -> Result<jsVal, String> {
         Err("Unimplemented".to_string())
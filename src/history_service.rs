@@ -0,0 +1,92 @@
+use crate::config::Config;
+use crate::database::Database;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use chrono::{Utc, NaiveDateTime, Duration as ChronoDuration};
+use log::{debug, info, error};
+
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const HISTORY_ARCHIVE_CHUNK: i64 = 1000;
+
+/// Moves the history older than `retention_days` to the new `history-<time>.ndjson.gz` in `archive_dir`
+/// and to the daily aggregates, which keep the long-term stats. Entries are removed from the database
+/// only after the archive is written, so a failure at most leaves them in both places.
+/// Returns number of archived entries
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::config::{read_config, Config};
+/// use webify::database::Database;
+/// use webify::history_service::archive_history;
+///
+/// let config = read_config::<Config>("config.toml").unwrap();
+/// let database = Database::new(&config).unwrap();
+/// println!("Archived {} entries", archive_history(&database, 90, "history_archive").unwrap());
+/// ```
+pub fn archive_history(database: &Database, retention_days: u32, archive_dir: &str) -> Result<usize, String> {
+    let before: NaiveDateTime = Utc::now().naive_utc() - ChronoDuration::days(retention_days as i64);
+    let mut chunk = database.get_history_before(before, 0, HISTORY_ARCHIVE_CHUNK)?;
+    if chunk.is_empty() {
+        return Ok(0);
+    }
+
+    fs::create_dir_all(archive_dir)
+        .map_err(|err| format!("Error on creating archive dir `{}`: {:?}", archive_dir, err))?;
+    let name = format!("history-{}.ndjson.gz", Utc::now().format("%Y%m%d%H%M%S"));
+    let path = Path::new(archive_dir).join(&name);
+    let part_path = Path::new(archive_dir).join(format!("{}.part", name));
+
+    let file = fs::File::create(&part_path)
+        .map_err(|err| format!("Error on creating archive `{}`: {:?}", part_path.display(), err))?;
+    let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+    let mut last_id = 0;
+    let mut archived: usize = 0;
+    while !chunk.is_empty() {
+        for entry in chunk.iter() {
+            encoder.write_all(entry.to_ndjson().as_bytes())
+                .map_err(|err| format!("Error on writing archive `{}`: {:?}", part_path.display(), err))?;
+            last_id = entry.id;
+        }
+        archived += chunk.len();
+        chunk = database.get_history_before(before, last_id, HISTORY_ARCHIVE_CHUNK)?;
+    }
+    let file = encoder.finish()
+        .and_then(|writer| writer.into_inner().map_err(|err| err.into_error()))
+        .map_err(|err| format!("Error on finishing archive `{}`: {:?}", part_path.display(), err))?;
+    file.sync_all()
+        .map_err(|err| format!("Error on syncing archive `{}`: {:?}", part_path.display(), err))?;
+    fs::rename(&part_path, &path)
+        .map_err(|err| format!("Error on renaming archive `{}`: {:?}", part_path.display(), err))?;
+    debug!("{} history entries have been written to `{}`", archived, path.display());
+
+    database.roll_up_history(before, last_id)?;
+    Ok(archived)
+}
+
+pub fn run_history_retention(database: &Database, config: &Config) {
+    if config.history_retention_days == 0 || config.history_archive_period_s == 0 {
+        return;
+    }
+    let db_copy = database.clone();
+    let retention_days = config.history_retention_days;
+    let archive_dir = config.history_archive_dir.clone();
+    let period = config.history_archive_period_s;
+
+    thread::spawn(move || {
+        loop {
+            match archive_history(&db_copy, retention_days, &archive_dir) {
+                Ok(archived) => info!("History archiver moved {} entries to `{}`", archived, archive_dir),
+                Err(err) => error!("Error occurred in history archiver: {}", err)
+            };
+            thread::sleep(Duration::from_secs(period as u64));
+        }
+    });
+    info!("History archiver thread spawned");
+}
@@ -15,7 +15,7 @@ use std::path::Path;
 /// let file_string = read_str("path/to/file");
 /// ```
 pub fn read_str(filename: &str) -> Result<String, String> {
-    let mut f = File::open(Path::new(filename)).unwrap();
+    let mut f = File::open(Path::new(filename)).map_err(|err| format!("Error on opening {}: {}", filename, err))?;
     let mut buffer = String::new();
     match f.read_to_string(&mut buffer) {
         Ok(_) => print!(""),
@@ -4,23 +4,34 @@ extern crate serde_derive;
 extern crate diesel;
 
 pub mod config;
+pub mod startup;
 pub mod io_tools;
 pub mod server;
+pub mod tls;
 pub mod database;
 pub mod dashboard;
+pub mod api;
 pub mod models;
 pub mod schema;
 pub mod devices;
 pub mod device_trait;
+pub mod device_registry;
+pub mod command_spec;
 pub mod root_device;
 pub mod file_device;
+pub mod safe_path;
 pub mod printer_device;
 pub mod file_cache;
 pub mod blog_device;
 pub mod stat_device;
 pub mod stat_service;
+pub mod stat_query;
 pub mod autoban_service;
+pub mod session_service;
+pub mod history_service;
 pub mod news_payload_parser;
 pub mod shikimori_scraper;
 pub mod template_cache;
-pub mod ecg_device;
\ No newline at end of file
+pub mod ecg_device;
+pub mod account_device;
+pub mod totp;
\ No newline at end of file
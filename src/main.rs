@@ -2,10 +2,13 @@ extern crate webify;
 
 use webify::server::run_server;
 use webify::config;
+use webify::startup::check_config;
+use webify::database::{get_connection, upgrade_database};
 
 use env_logger::Env;
 
 use std::env;
+use std::process;
 use std::sync::{Arc, Mutex};
 
 
@@ -23,12 +26,52 @@ fn main() {
                 config::add_user();
                 return;
             }
+            "--check-config" => {
+                let problems = check_config();
+                for problem in &problems {
+                    eprintln!("{}", problem);
+                }
+                if !problems.is_empty() {
+                    eprintln!("{} problem(s) found", problems.len());
+                    process::exit(1);
+                }
+                println!("Configuration is OK");
+                return;
+            }
+            "--migrate" => {
+                let applied = config::read_config::<config::Config>(config::DEFAULT_CONFIG_PATH)
+                    .map_err(|err| format!("{}", err))
+                    .and_then(|conf| get_connection(&conf.db_config))
+                    .and_then(|pool| upgrade_database(&pool));
+                match applied {
+                    Ok(names) if names.is_empty() => println!("Database is up to date"),
+                    Ok(names) => println!("Applied migrations: {}", names.join(", ")),
+                    Err(e) => {
+                        eprintln!("Error on migrating the database: {}", e);
+                        process::exit(1);
+                    }
+                };
+                return;
+            }
             _ => {
                 println!("Unknown argument, exiting");
                 return;
             }
         }
     }
-    let config = Arc::new(Mutex::new(config::read_config::<config::Config>(config::DEFAULT_CONFIG_PATH).unwrap()));
-    let _handler = run_server(config);
+    let config = match config::read_config::<config::Config>(config::DEFAULT_CONFIG_PATH) {
+        Ok(data) => Arc::new(Mutex::new(data)),
+        Err(e) => {
+            eprintln!("Error on starting the server: {}", e);
+            process::exit(1);
+        }
+    };
+    match run_server(config) {
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("Error on starting the server: {}", e);
+            eprintln!("Run `$ webify --check-config` to see all the problems");
+            process::exit(1);
+        }
+    };
 }
@@ -1,6 +1,6 @@
 use crate::schema::*;
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
 use serde_json::Value as jsVal;
 use serde_json::json;
 use diesel::sql_types::{Text, Integer};
@@ -9,13 +9,33 @@ use diesel::sql_types::{Text, Integer};
 pub enum RejectReason {
     Ok = 0,
     NoAuth = 1,
+    /// Device has refused to execute the command
     Error = 2,
+    /// Unknown device, command or query type, or the payload doesn't match the command
+    Invalid = 3,
+    /// Command has failed on our side (database and so on)
+    Internal = 4,
+}
+
+impl RejectReason {
+    /// Outcome of the command, as it is written to the history
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            RejectReason::Ok => "ok",
+            RejectReason::NoAuth => "denied",
+            RejectReason::Error => "device_error",
+            RejectReason::Invalid => "invalid",
+            RejectReason::Internal => "internal",
+        }
+    }
 }
 
 const REJECTED_REASON: &'static [&'static str] = &[
     "OK",
     "NOT AUTHORIZED",
-    "ERROR"
+    "ERROR",
+    "INVALID",
+    "INTERNAL"
 ];
 
 /// Represents that structure can be inserted in the table
@@ -24,8 +44,9 @@ pub trait LineWebify {
 }
 
 /// Represents user structure.
-/// User has id, username, password (should be always hashified),  cookie (if does have),
-/// groups user have, and number of wrong attempts he has made.
+/// User has id, username, password (should be always hashified), legacy groups string,
+/// and the second factor settings: TOTP secret and hashed recovery codes.
+/// Groups now live in `user_groups` and `user_roles`, `groups` is emptied when the database is upgraded.
 #[derive(Deserialize, Serialize, Queryable, PartialEq, Debug, Identifiable)]
 pub struct User {
     pub id: i32,
@@ -33,6 +54,13 @@ pub struct User {
     pub password: String,
     // It's probably okay because it remains hashed
     pub groups: String,
+    // secret and recovery codes never leave the database, so they are not in the cache
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: i32,
+    #[serde(default)]
+    pub recovery_codes: String,
 }
 
 impl LineWebify for User {
@@ -41,7 +69,8 @@ impl LineWebify for User {
             "id": self.id,
             "name": self.name,
             "password": self.password,
-            "groups": self.groups
+            "groups": self.groups,
+            "totp_enabled": self.totp_enabled
         })
     }
 }
@@ -64,6 +93,133 @@ pub struct History {
     pub qtype: String,
     pub rejected: i32,
     pub timestamp: NaiveDateTime,
+    pub api_token_id: Option<i32>,
+    pub ip: String,
+    pub user_agent: String,
+    /// `Database::get_payload_digest` of the payload, so the payload itself (passwords, codes) never gets to the history
+    pub payload_digest: String,
+    pub outcome: String,
+    pub error: String,
+    pub duration_ms: i32,
+}
+
+/// Columns of the history export in CSV
+pub const HISTORY_CSV_HEADER: &str = "id,username,device,command,qtype,rejected,timestamp,api_token_id,ip,user_agent,payload_digest,outcome,error,duration_ms\n";
+
+/// Quotes the CSV field, if it has commas, quotes or line breaks. Fields, which spreadsheets would take
+/// for formulas (starting with `=`, `+`, `-`, `@`, tab or CR), get `'` in front and are quoted as well
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::models::csv_field;
+///
+/// assert_eq!(csv_field("filer"), "filer");
+/// assert_eq!(csv_field("a,b"), "\"a,b\"");
+/// assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+/// assert_eq!(csv_field("-1"), "\"'-1\"");
+/// ```
+pub fn csv_field(text: &str) -> String {
+    if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("\"'{}\"", text.replace('"', "\"\""))
+    } else if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+impl History {
+    /// Line of the CSV export, columns are in the order of `HISTORY_CSV_HEADER`
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n", self.id, csv_field(&self.username), csv_field(&self.device),
+                csv_field(&self.command), csv_field(&self.qtype), self.rejected, self.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                self.api_token_id.map(|x| x.to_string()).unwrap_or_default(), csv_field(&self.ip), csv_field(&self.user_agent),
+                csv_field(&self.payload_digest), csv_field(&self.outcome), csv_field(&self.error), self.duration_ms)
+    }
+
+    /// Line of the NDJSON export
+    pub fn to_ndjson(&self) -> String {
+        format!("{}\n", self.get_content())
+    }
+}
+
+/// Filter, sorting and the page of the history. Empty fields don't filter anything.
+/// Time range is inclusive, `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS` in UTC.
+/// `sort` is one of `id`, `timestamp`, `username`, `device`, `command`, `duration_ms`; `order` is `asc` or `desc`
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HistoryFilter {
+    pub username: String,
+    pub device: String,
+    pub command: String,
+    pub qtype: String,
+    pub rejected: Option<i32>,
+    pub from: String,
+    pub to: String,
+    pub sort: String,
+    pub order: String,
+    /// Starts from 1
+    pub page: i64,
+    pub per_page: i64,
+}
+
+pub const HISTORY_PAGE_SIZE: i64 = 50;
+pub const HISTORY_MAX_PAGE_SIZE: i64 = 500;
+const HISTORY_SORT_FIELDS: &[&str] = &["id", "timestamp", "username", "device", "command", "duration_ms"];
+
+fn parse_history_time(text: &str, end_of_day: bool) -> Result<Option<NaiveDateTime>, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S") {
+        return Ok(Some(time));
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid time `{}`, expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS", text))?;
+    Ok(if end_of_day { date.and_hms_opt(23, 59, 59) } else { date.and_hms_opt(0, 0, 0) })
+}
+
+impl HistoryFilter {
+    /// Checks the sorting and returns the time range
+    pub fn get_range(&self) -> Result<(Option<NaiveDateTime>, Option<NaiveDateTime>), String> {
+        if !self.sort.is_empty() && !HISTORY_SORT_FIELDS.contains(&self.sort.as_str()) {
+            return Err(format!("Unknown sort field `{}`, expected one of {}", self.sort, HISTORY_SORT_FIELDS.join(", ")));
+        }
+        if !["", "asc", "desc"].contains(&self.order.as_str()) {
+            return Err(format!("Unknown order `{}`, expected asc or desc", self.order));
+        }
+        Ok((parse_history_time(&self.from, false)?, parse_history_time(&self.to, true)?))
+    }
+
+    pub fn is_ascending(&self) -> bool {
+        self.order == "asc"
+    }
+
+    /// Page number from 1. It comes from the user, so the offset of the page must not overflow
+    pub fn get_page(&self) -> i64 {
+        self.page.clamp(1, i64::MAX / HISTORY_MAX_PAGE_SIZE)
+    }
+
+    pub fn get_per_page(&self) -> i64 {
+        if self.per_page <= 0 { HISTORY_PAGE_SIZE } else { self.per_page.min(HISTORY_MAX_PAGE_SIZE) }
+    }
+
+    /// Query string of the filter without the page, for the export links
+    pub fn to_query_string(&self) -> String {
+        let mut pairs: Vec<(&str, String)> = vec![];
+        for (key, value) in [("username", &self.username), ("device", &self.device), ("command", &self.command),
+            ("qtype", &self.qtype), ("from", &self.from), ("to", &self.to), ("sort", &self.sort), ("order", &self.order)] {
+            if !value.is_empty() {
+                pairs.push((key, value.clone()));
+            }
+        }
+        if let Some(rejected) = self.rejected {
+            pairs.push(("rejected", rejected.to_string()));
+        }
+        pairs.iter().map(|(key, value)| format!("{}={}", key, urlencoding::encode(value))).collect::<Vec<String>>().join("&")
+    }
 }
 
 #[derive(Queryable, QueryableByName, Clone)]
@@ -87,7 +243,14 @@ impl LineWebify for History {
             } else {
                 format!("UNKNOWN {}", self.rejected)
             } ,
-            "timestamp": self.timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+            "timestamp": self.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "api_token_id": self.api_token_id,
+            "ip": self.ip,
+            "user_agent": self.user_agent,
+            "payload_digest": self.payload_digest,
+            "outcome": self.outcome,
+            "error": self.error,
+            "duration_ms": self.duration_ms
         })
     }
 }
@@ -101,4 +264,222 @@ pub struct HistoryForm<'a> {
     pub command: &'a str,
     pub qtype: &'a str,
     pub rejected: i32,
+    pub api_token_id: Option<i32>,
+    pub ip: &'a str,
+    pub user_agent: &'a str,
+    pub payload_digest: &'a str,
+    pub outcome: &'a str,
+    pub error: &'a str,
+    pub duration_ms: i32,
+}
+
+
+/// Represents login session. Token is stored hashed, so the cookie itself never touches the disk.
+#[derive(Queryable, PartialEq, Debug)]
+pub struct Session {
+    pub id: i32,
+    pub token: String,
+    pub username: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub ip: String,
+    pub user_agent: String,
+    pub csrf_token: String,
+}
+
+impl LineWebify for Session {
+    fn get_content(&self) -> jsVal {
+        json!({
+            "id": self.id,
+            "username": self.username,
+            "created_at": self.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "last_seen": self.last_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "ip": self.ip,
+            "user_agent": self.user_agent
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = sessions)]
+pub struct SessionForm<'a> {
+    pub token: &'a str,
+    pub username: &'a str,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub ip: &'a str,
+    pub user_agent: &'a str,
+    pub csrf_token: &'a str,
+}
+
+
+/// Represents active login lockout, stored in redis.
+/// `kind` is either `user` or `ip`, `expires_in` is the number of seconds left
+#[derive(Clone, Debug)]
+pub struct Lockout {
+    pub kind: String,
+    pub subject: String,
+    pub expires_in: i64,
+}
+
+impl LineWebify for Lockout {
+    fn get_content(&self) -> jsVal {
+        json!({
+            "kind": self.kind,
+            "subject": self.subject,
+            "expires_in": self.expires_in
+        })
+    }
+}
+
+
+/// Personal API token of the user. Only the hash of the token is stored,
+/// the prefix is kept to let the owner recognize the token in the list.
+/// `groups` is the comma-separated subset of the user's groups, which the token is allowed to use.
+#[derive(Queryable, PartialEq, Debug)]
+pub struct ApiToken {
+    pub id: i32,
+    pub token: String,
+    pub prefix: String,
+    pub username: String,
+    pub name: String,
+    pub groups: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl LineWebify for ApiToken {
+    fn get_content(&self) -> jsVal {
+        json!({
+            "id": self.id,
+            "prefix": self.prefix,
+            "name": self.name,
+            "groups": self.groups,
+            "created_at": self.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "expires_at": self.expires_at.map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string()),
+            "last_used": self.last_used.map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string())
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiTokenForm<'a> {
+    pub token: &'a str,
+    pub prefix: &'a str,
+    pub username: &'a str,
+    pub name: &'a str,
+    pub groups: &'a str,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// The one, who sends the command: either the user through the session (all the user's groups are available),
+/// or the API token of the user, which is limited to the subset of the groups
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub username: String,
+    pub groups: Option<Vec<String>>,
+    pub api_token_id: Option<i32>,
+}
+
+impl Principal {
+    /// Principal of the user, who is logged in with the session
+    pub fn user(username: &str) -> Principal {
+        Principal { username: username.to_string(), groups: None, api_token_id: None }
+    }
+
+    /// Whether the group is in the scope of the principal. User's own rights are checked separately
+    pub fn allows_group(&self, group: &str) -> bool {
+        match &self.groups {
+            Some(groups) => groups.iter().any(|x| x == group),
+            None => true
+        }
+    }
+}
+
+/// Named bundle of groups. Role may inherit groups of other roles
+#[derive(Queryable, PartialEq, Debug, Clone)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = roles)]
+pub struct RoleForm<'a> {
+    pub name: &'a str,
+    pub description: &'a str,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = role_groups)]
+pub struct RoleGroupForm<'a> {
+    pub role_id: i32,
+    pub group_name: &'a str,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = role_inherits)]
+pub struct RoleInheritForm {
+    pub role_id: i32,
+    pub parent_id: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_roles)]
+pub struct UserRoleForm<'a> {
+    pub username: &'a str,
+    pub role_id: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_groups)]
+pub struct UserGroupForm<'a> {
+    pub username: &'a str,
+    pub group_name: &'a str,
+}
+
+/// Role with its own groups, the roles it inherits and all the groups it grants including inherited ones
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoleInfo {
+    pub name: String,
+    pub description: String,
+    pub groups: Vec<String>,
+    pub inherits: Vec<String>,
+    pub effective: Vec<String>,
+}
+
+impl LineWebify for RoleInfo {
+    fn get_content(&self) -> jsVal {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "groups": self.groups.join(","),
+            "inherits": self.inherits.join(","),
+            "effective": self.effective.join(",")
+        })
+    }
+}
+
+/// Storage limit of the filer for the user or for every member of the group.
+/// `kind` is either `user` or `group`, limit of 0 means unlimited
+#[derive(Queryable, Insertable, PartialEq, Debug, Clone)]
+#[diesel(table_name = filer_quotas)]
+pub struct FilerQuota {
+    pub kind: String,
+    pub name: String,
+    pub limit_bytes: i64,
+}
+
+impl LineWebify for FilerQuota {
+    fn get_content(&self) -> jsVal {
+        json!({
+            "kind": self.kind,
+            "name": self.name,
+            "limit_mb": self.limit_bytes / (1024 * 1024)
+        })
+    }
 }
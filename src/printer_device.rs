@@ -5,6 +5,7 @@ use crate::file_device::FileDevice;
 use crate::io_tools;
 use crate::io_tools::exists;
 use crate::devices::{Devices, Groups, DEV_GROUPS};
+use crate::command_spec::{CommandSpec, NoPayload, parse_payload};
 
 use serde_json::Value as jsVal;
 use serde_json::json;
@@ -138,11 +139,7 @@ impl PrinterDevice {
         })
     }
 
-    fn delete_query(&self, ids: &str) -> Result<String, String> {
-        let id: u32 = match ids.parse() {
-            Ok(d) => d,
-            Err(e) => return Err(format!("Error: wrong payload: {}", e))
-        };
+    fn delete_query(&self, id: u32) -> Result<String, String> {
         self.queue.lock().map(|mut x| {
             match x.remove(&id) {
                 Some(_d) => format!("OK, deleted {}", &id),
@@ -153,11 +150,7 @@ impl PrinterDevice {
         })
     }
 
-    fn confirm_query(&self, ids: &str) -> Result<String, String> {
-        let id: u32 = match ids.parse() {
-            Ok(d) => d,
-            Err(e) => return Err(format!("Error: wrong payload: {}", e))
-        };
+    fn confirm_query(&self, id: u32) -> Result<String, String> {
         let req = match self.queue.lock().map(|mut x| {
             match x.remove(&id) {
                 Some(d) => Ok(d),
@@ -254,6 +247,40 @@ impl DeviceWrite for PrinterDevice {
 }
 
 
+/// Files are named by their paths in the filer (or in the storage for `print_file`),
+/// requests in the queue are named by their ids
+impl DeviceCommands for PrinterDevice {
+    fn commands(&self) -> Vec<CommandSpec> {
+        let id = json!({"type": "integer", "minimum": 0});
+        vec![
+            CommandSpec::new::<NoPayload>("R", "lpstat").describe("Shows the printing queue"),
+            CommandSpec::new::<NoPayload>("R", "printers").describe("Lists the printers"),
+            CommandSpec::new::<String>("W", "print_file")
+                .describe("Prints the file from the storage")
+                .payload("name of the file in the storage"),
+            CommandSpec::new::<String>("W", "cancel")
+                .describe("Cancels the printing job")
+                .payload("id of the job"),
+            CommandSpec::new::<String>("W", "cache")
+                .describe("Copies the file from the filer to the storage")
+                .payload("path in the filer"),
+            CommandSpec::new::<NoPayload>("W", "cache_clear").describe("Clears the storage"),
+            CommandSpec::new::<String>("Q", "print_file")
+                .describe("Asks to print the file, someone has to confirm it")
+                .payload("path in the filer"),
+            CommandSpec::new::<u32>("C", "confirm")
+                .describe("Prints the requested file")
+                .payload("id of the request")
+                .with_schema(id.clone()),
+            CommandSpec::new::<NoPayload>("C", "list").describe("Lists the requests").readonly(),
+            CommandSpec::new::<u32>("D", "dismiss")
+                .describe("Dismisses the request")
+                .payload("id of the request")
+                .with_schema(id),
+        ]
+    }
+}
+
 impl DeviceRequest for PrinterDevice {
     fn request_query(&self, query: &QCommand) -> Result<jsVal, String> {
         if &query.group != DEV_GROUPS[Devices::Printer as usize][Groups::Request as usize].unwrap() {
@@ -280,7 +307,7 @@ impl DeviceConfirm for PrinterDevice {
             return Err("Error: wrong permissions".to_string());
         }
         match query.command.as_str() {
-            "confirm" => match self.confirm_query(&query.payload) {
+            "confirm" => match self.confirm_query(parse_payload(&query.payload)?) {
                 Ok(message) => Ok(json!({
                     "template": "simple_message.hbs",
                     "message": message,
@@ -304,7 +331,7 @@ impl DeviceConfirm for PrinterDevice {
         }
         match {
             match query.command.as_str() {
-                "dismiss" => self.delete_query(&query.payload),
+                "dismiss" => self.delete_query(parse_payload(&query.payload)?),
                 _ => Err("Unknown command".to_string())
             }
         } {
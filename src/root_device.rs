@@ -1,20 +1,142 @@
 use crate::database::Database;
-use crate::models::LineWebify;
+use crate::models::{LineWebify, HistoryFilter};
 use crate::dashboard::QCommand;
 use crate::device_trait::*;
 use crate::devices::{Devices, Groups, DEV_GROUPS};
+use crate::command_spec::{CommandSpec, CommandInfo, NoPayload, parse_payload};
+use crate::file_device::format_size;
 
-use serde_json::{Value as jsVal, json, from_str as js_from_str};
+use serde_json::{Value as jsVal, json};
 use secstr::SecStr;
 
+use std::sync::{Arc, RwLock};
+
+/// Payload of `add_user`. Groups are comma-separated, the user gets the default ones if they are missing
+#[derive(Deserialize)]
+pub struct NewUserPayload {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub groups: Option<String>,
+}
+
+/// Payload of `update_user_password`
+#[derive(Deserialize)]
+pub struct PasswordPayload {
+    pub username: String,
+    pub password: String,
+}
+
+/// Payload of `update_user_groups`. Groups are comma-separated and may be empty
+#[derive(Deserialize)]
+pub struct GroupsPayload {
+    pub username: String,
+    pub groups: String,
+}
+
+/// Payload of `unlock_user`. At least one of the fields must be set
+#[derive(Deserialize)]
+pub struct UnlockPayload {
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub ip: String,
+}
+
+/// Payload of `reset_totp` and `reset_filer_usage`
+#[derive(Deserialize)]
+pub struct UsernamePayload {
+    pub username: String,
+}
+
+/// Payload of `add_role`. Groups and inherited roles are comma-separated
+#[derive(Deserialize)]
+pub struct RolePayload {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub groups: String,
+    #[serde(default)]
+    pub inherits: String,
+}
+
+/// Payload of `update_role`. Missing fields are not changed
+#[derive(Deserialize)]
+pub struct RoleUpdatePayload {
+    pub name: String,
+    pub description: Option<String>,
+    pub groups: Option<String>,
+    pub inherits: Option<String>,
+}
+
+/// Payload of `delete_role`
+#[derive(Deserialize)]
+pub struct RoleNamePayload {
+    pub name: String,
+}
+
+/// Payload of `assign_role` and `revoke_role`
+#[derive(Deserialize)]
+pub struct UserRolePayload {
+    pub username: String,
+    pub role: String,
+}
+
+/// Payload of `set_filer_quota`. `kind` is `user` or `group`, limit of 0 means unlimited
+#[derive(Deserialize)]
+pub struct QuotaPayload {
+    pub kind: String,
+    pub name: String,
+    pub limit_mb: u32,
+}
+
+/// Payload of `remove_filer_quota`
+#[derive(Deserialize)]
+pub struct QuotaNamePayload {
+    pub kind: String,
+    pub name: String,
+}
+
 #[derive(Clone)]
 pub struct RootDev {
     database: Database,
+    commands: Arc<RwLock<Vec<CommandInfo>>>,
 }
 
 impl RootDev {
     pub fn new(db: &Database) -> RootDev {
-        RootDev { database: db.clone() }
+        RootDev { database: db.clone(), commands: Arc::new(RwLock::new(vec![])) }
+    }
+
+    /// Sets the command catalogue. It is known only after all the devices are registered
+    pub fn set_commands(&self, commands: Vec<CommandInfo>) -> Result<(), String> {
+        *self.commands.write().map_err(|err| format!("Error on writing the commands: {:?}", err))? = commands;
+        Ok(())
+    }
+
+    /// Shows the commands, which the user may invoke. Current user is used, if the payload is empty
+    fn read_commands(&self, username: &str, payload: &str) -> Result<jsVal, String> {
+        let target: String = parse_payload(payload)?;
+        let target = if target.is_empty() { username } else { target.as_str() };
+        let groups = self.database.get_user_groups(target)
+            .map_err(|err| format!("Error in RootDev.read_commands: {}", err))?;
+        let commands = self.commands.read().map_err(|err| format!("Error on reading the commands: {:?}", err))?;
+
+        Ok(json!({
+            "template": "root_commands_table.hbs",
+            "username": target,
+            "commands": commands.iter().filter(|x| groups.contains(&x.group)).map(|x| json!({
+                "device": x.device,
+                "qtype": x.qtype,
+                "command": x.command,
+                "group": x.group,
+                "mutating": x.mutating,
+                "description": x.description,
+                "payload": x.payload,
+                "schema": x.schema.as_ref().map(|schema| schema.to_string()).unwrap_or_default()
+            })).collect::<jsVal>()
+        }))
     }
 
     fn read_users(&self) -> Result<jsVal, String> {
@@ -23,24 +145,55 @@ impl RootDev {
             Err(err) => return Err(format!("Error in RootDev.read_users: {}", err))
         };
 
+        let mut users: Vec<jsVal> = vec![];
+        for user in res.iter() {
+            let mut content = user.get_content();
+            content["groups"] = json!(self.database.get_user_direct_groups(&user.name)?.join(","));
+            content["roles"] = json!(self.database.get_user_roles(&user.name)?.join(","));
+            users.push(content);
+        }
+
         Ok(json!({
             "template": "root_users_table.hbs",
-            "users": res.iter().map(|x| x.get_content()).collect::<jsVal>()
+            "users": users
         }))
     }
 
-    fn read_history(&self) -> Result<jsVal, String> {
-        let res = match self.database.get_all_history() {
+    fn read_history(&self, username: &str, filter: HistoryFilter) -> Result<jsVal, String> {
+        let (res, total) = match self.database.get_history_page(&filter) {
             Ok(d) => d,
-            Err(err) => return Err(format!("Error in RootDev.read_users: {}", err))
+            Err(err) => return Err(format!("Error in RootDev.read_history: {}", err))
         };
+        let page = filter.get_page();
+        let per_page = filter.get_per_page();
+        let pages = (total + per_page - 1) / per_page;
         Ok(json!({
             "template": "root_history_table.hbs",
-            "entries": res.iter().map(|x| x.get_content()).collect::<jsVal>()
+            "username": username,
+            "entries": res.iter().map(|x| x.get_content()).collect::<jsVal>(),
+            "total": total,
+            "page": page,
+            "pages": pages,
+            "prev_page": if page > 1 { json!(page - 1) } else { jsVal::Null },
+            "next_page": if page < pages { json!(page + 1) } else { jsVal::Null },
+            "filter": filter,
+            "export_query": filter.to_query_string()
         }))
     }
 
 
+    fn read_lockouts(&self) -> Result<jsVal, String> {
+        let res = match self.database.get_all_lockouts() {
+            Ok(d) => d,
+            Err(err) => return Err(format!("Error in RootDev.read_lockouts: {}", err))
+        };
+
+        Ok(json!({
+            "template": "root_lockouts_table.hbs",
+            "lockouts": res.iter().map(|x| x.get_content()).collect::<jsVal>()
+        }))
+    }
+
     fn read_groups(&self) -> Result<jsVal, String> {
         let res = match self.database.get_all_groups() {
             Ok(d) => d,
@@ -55,69 +208,129 @@ impl RootDev {
         }))
     }
 
-    fn insert_new_user(&self, query: &str) -> Result<String, String> {
-        let data: jsVal = js_from_str(query).map_err(|err| { format!("Couldn't parse JSON: {:?}", err) })?;
-        let name = match data.get("username") {
-            Some(d) => d.as_str().unwrap_or(""),
-            None => return Err(format!("Error on inserting users: invalid syntax: couldn't find username"))
+    fn read_roles(&self) -> Result<jsVal, String> {
+        let res = match self.database.get_all_roles() {
+            Ok(d) => d,
+            Err(err) => return Err(format!("Error in RootDev.read_roles: {}", err))
         };
 
-        let password = match data.get("password") {
-            Some(d) => SecStr::from(d.as_str().unwrap_or("")),
-            None => return Err(format!("Error on inserting users: invalid syntax: couldn't find password"))
-        };
-        if password.unsecure().len() < 1 || name.len() < 1 {
-            return Err(format!("Username or password is in wrong format"));
-        }
+        Ok(json!({
+            "template": "root_roles_table.hbs",
+            "roles": res.iter().map(|x| x.get_content()).collect::<jsVal>()
+        }))
+    }
+
+    fn read_filer_quotas(&self) -> Result<jsVal, String> {
+        let quotas = self.database.get_filer_quotas()
+            .map_err(|err| format!("Error in RootDev.read_filer_quotas: {}", err))?;
+        let usage = self.database.get_all_filer_usage()
+            .map_err(|err| format!("Error in RootDev.read_filer_quotas: {}", err))?;
 
-        let groups = data.get("groups").map(|data| { data.as_str() }).unwrap_or(None);
-        match self.database.insert_user(name, &password, groups) {
+        Ok(json!({
+            "template": "root_quotas_table.hbs",
+            "quotas": quotas.iter().map(|x| x.get_content()).collect::<jsVal>(),
+            "usage": usage.iter().map(|(username, used)| {
+                json!({"username": username, "used": format_size(*used as u64)})
+            }).collect::<jsVal>()
+        }))
+    }
+
+    fn insert_new_user(&self, data: NewUserPayload) -> Result<String, String> {
+        let password = SecStr::from(data.password);
+        match self.database.insert_user(&data.username, &password, data.groups.as_deref()) {
             Ok(_) => Ok("Ok".to_string()),
             Err(e) => Err(format!("Error on inserting user: {}", e))
         }
     }
 
-    fn update_user_pass(&self, query: &str) -> Result<String, String> {
-        let data: jsVal = js_from_str(query).map_err(|err| { format!("Couldn't parse JSON: {:?}", err) })?;
-        let name = match data.get("username") {
-            Some(d) => d.as_str().unwrap_or(""),
-            None => return Err(format!("Error on updating users pass: invalid syntax: couldn't find username"))
-        };
+    fn update_user_pass(&self, data: PasswordPayload) -> Result<String, String> {
+        let password = SecStr::from(data.password);
+        match self.database.update_user_pass(&data.username, &password) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on updating user pass: {}", e))
+        }
+    }
 
-        let password = match data.get("password") {
-            Some(d) => SecStr::from(d.as_str().unwrap_or("")),
-            None => return Err(format!("Error on updating users : invalid syntax: couldn't find password"))
-        };
+    fn update_user_group(&self, data: GroupsPayload) -> Result<String, String> {
+        match self.database.update_user_group(&data.username, &data.groups) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on updating user group: {}", e))
+        }
+    }
 
-        if password.unsecure().len() < 1 || name.len() < 1 {
-            return Err(format!("Username or password is in wrong format"));
+    fn add_role(&self, data: RolePayload) -> Result<String, String> {
+        match self.database.create_role(&data.name, &data.description, &data.groups, &data.inherits) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on adding role: {}", e))
         }
+    }
 
-        match self.database.update_user_pass(name, &password) {
+    fn update_role(&self, data: RoleUpdatePayload) -> Result<String, String> {
+        // missing fields are kept as they are
+        match self.database.update_role(&data.name, data.description.as_deref(), data.groups.as_deref(), data.inherits.as_deref()) {
             Ok(_) => Ok("Ok".to_string()),
-            Err(e) => Err(format!("Error on updating user pass: {}", e))
+            Err(e) => Err(format!("Error on updating role: {}", e))
         }
     }
 
-    fn update_user_group(&self, query: &str) -> Result<String, String> {
-        let data: jsVal = js_from_str(query).map_err(|err| { format!("Couldn't parse JSON: {:?}", err) })?;
-        let name = match data.get("username") {
-            Some(d) => d.as_str().unwrap_or(""),
-            None => return Err(format!("Error on updating users group: invalid syntax: couldn't find username"))
-        };
+    fn delete_role(&self, data: RoleNamePayload) -> Result<String, String> {
+        match self.database.delete_role(&data.name) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on deleting role: {}", e))
+        }
+    }
 
-        let groups = match data.get("groups") {
-            Some(d) => d.as_str().unwrap_or(""),
-            None => return Err(format!("Error on updating users group: invalid syntax: couldn't find groups"))
+    fn change_user_role(&self, data: UserRolePayload, assign: bool) -> Result<String, String> {
+        let res = if assign {
+            self.database.assign_role(&data.username, &data.role)
+        } else {
+            self.database.revoke_role(&data.username, &data.role)
         };
+        match res {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on changing user role: {}", e))
+        }
+    }
 
-        if groups.len() < 1 || name.len() < 1 {
-            return Err(format!("Username or groups is in wrong format"));
+    fn set_filer_quota(&self, data: QuotaPayload) -> Result<String, String> {
+        match self.database.set_filer_quota(&data.kind, &data.name, data.limit_mb as i64 * 1024 * 1024) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on setting filer quota: {}", e))
         }
+    }
 
-        match self.database.update_user_group(name, groups) {
+    fn remove_filer_quota(&self, data: QuotaNamePayload) -> Result<String, String> {
+        match self.database.remove_filer_quota(&data.kind, &data.name) {
             Ok(_) => Ok("Ok".to_string()),
-            Err(e) => Err(format!("Error on updating user group: {}", e))
+            Err(e) => Err(format!("Error on removing filer quota: {}", e))
+        }
+    }
+
+    fn reset_filer_usage(&self, data: UsernamePayload) -> Result<String, String> {
+        match self.database.reset_filer_usage(&data.username) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on resetting filer usage: {}", e))
+        }
+    }
+
+    fn reset_totp(&self, data: UsernamePayload) -> Result<String, String> {
+        match self.database.reset_totp(&data.username) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on resetting TOTP: {}", e))
+        }
+    }
+
+    fn unlock_user(&self, data: UnlockPayload) -> Result<String, String> {
+        let name = Some(data.username.as_str()).filter(|d| !d.is_empty());
+        let ip = Some(data.ip.as_str()).filter(|d| !d.is_empty());
+
+        if name.is_none() && ip.is_none() {
+            return Err("Error on unlocking: invalid syntax: couldn't find username or ip".to_string());
+        }
+
+        match self.database.unlock_login(name, ip) {
+            Ok(_) => Ok("Ok".to_string()),
+            Err(e) => Err(format!("Error on unlocking: {}", e))
         }
     }
 }
@@ -131,8 +344,12 @@ impl DeviceRead for RootDev {
         }
         match command {
             "read_all_users" => self.read_users(),
-            "read_all_hist" => self.read_history(),
+            "read_all_hist" => self.read_history(&query.username, parse_payload(&query.payload)?),
             "read_all_groups" => self.read_groups(),
+            "read_lockouts" => self.read_lockouts(),
+            "read_all_roles" => self.read_roles(),
+            "read_commands" => self.read_commands(&query.username, &query.payload),
+            "read_filer_quotas" => self.read_filer_quotas(),
             _ => Err(format!("Unknown command"))
         }
     }
@@ -151,10 +368,21 @@ impl DeviceWrite for RootDev {
             return Err("No access".to_string());
         }
 
+        let payload = query.payload.as_str();
         match command {
-            "add_user" => self.insert_new_user(query.payload.as_str()),
-            "update_user_password" => self.update_user_pass(query.payload.as_str()),
-            "update_user_groups" => self.update_user_group(query.payload.as_str()),
+            "add_user" => self.insert_new_user(parse_payload(payload)?),
+            "update_user_password" => self.update_user_pass(parse_payload(payload)?),
+            "update_user_groups" => self.update_user_group(parse_payload(payload)?),
+            "unlock_user" => self.unlock_user(parse_payload(payload)?),
+            "reset_totp" => self.reset_totp(parse_payload(payload)?),
+            "add_role" => self.add_role(parse_payload(payload)?),
+            "update_role" => self.update_role(parse_payload(payload)?),
+            "delete_role" => self.delete_role(parse_payload(payload)?),
+            "assign_role" => self.change_user_role(parse_payload(payload)?, true),
+            "revoke_role" => self.change_user_role(parse_payload(payload)?, false),
+            "set_filer_quota" => self.set_filer_quota(parse_payload(payload)?),
+            "remove_filer_quota" => self.remove_filer_quota(parse_payload(payload)?),
+            "reset_filer_usage" => self.reset_filer_usage(parse_payload(payload)?),
             _ => Err(format!("Unknown command"))
         }.map(|mess| {
             json!({
@@ -165,6 +393,83 @@ impl DeviceWrite for RootDev {
 }
 
 
+impl DeviceCommands for RootDev {
+    fn commands(&self) -> Vec<CommandSpec> {
+        let non_empty = json!({"type": "string", "minLength": 1});
+        let credentials = json!({
+            "type": "object",
+            "required": ["username", "password"],
+            "properties": {"username": non_empty, "password": non_empty}
+        });
+        vec![
+            CommandSpec::new::<NoPayload>("R", "read_all_users").describe("Lists users with their groups and roles"),
+            CommandSpec::new::<HistoryFilter>("R", "read_all_hist")
+                .describe("Shows the page of the history")
+                .payload(r#"{"username", "device", "command", "qtype", "rejected", "from", "to", "sort", "order", "page", "per_page"}"#),
+            CommandSpec::new::<NoPayload>("R", "read_all_groups").describe("Lists groups and their devices"),
+            CommandSpec::new::<NoPayload>("R", "read_lockouts").describe("Lists locked out users and IPs"),
+            CommandSpec::new::<NoPayload>("R", "read_all_roles").describe("Lists roles with their effective groups"),
+            CommandSpec::new::<String>("R", "read_commands")
+                .describe("Lists commands, which the user may invoke")
+                .payload("username, empty for yourself"),
+            CommandSpec::new::<NoPayload>("R", "read_filer_quotas").describe("Lists filer quotas and counted usage of the users"),
+            CommandSpec::new::<NewUserPayload>("W", "add_user")
+                .describe("Adds the user")
+                .payload(r#"{"username", "password", "groups": "g1,g2"}"#)
+                .with_schema(credentials.clone()),
+            CommandSpec::new::<PasswordPayload>("W", "update_user_password")
+                .describe("Sets the new password")
+                .payload(r#"{"username", "password"}"#)
+                .with_schema(credentials),
+            CommandSpec::new::<GroupsPayload>("W", "update_user_groups")
+                .describe("Replaces groups, given to the user directly")
+                .payload(r#"{"username", "groups": "g1,g2"}"#)
+                .with_schema(json!({
+                    "type": "object",
+                    "properties": {"username": non_empty, "groups": {"type": "string"}}
+                })),
+            CommandSpec::new::<UnlockPayload>("W", "unlock_user")
+                .describe("Lifts the login lockout")
+                .payload(r#"{"username", "ip"}"#),
+            CommandSpec::new::<UsernamePayload>("W", "reset_totp")
+                .describe("Disables the second factor of the user")
+                .payload(r#"{"username"}"#),
+            CommandSpec::new::<RolePayload>("W", "add_role")
+                .describe("Creates the role")
+                .payload(r#"{"name", "description", "groups": "g1,g2", "inherits": "r1,r2"}"#)
+                .with_schema(json!({
+                    "type": "object",
+                    "properties": {"name": non_empty}
+                })),
+            CommandSpec::new::<RoleUpdatePayload>("W", "update_role")
+                .describe("Changes the role, missing fields are kept")
+                .payload(r#"{"name", "description", "groups": "g1,g2", "inherits": "r1,r2"}"#),
+            CommandSpec::new::<RoleNamePayload>("W", "delete_role")
+                .describe("Deletes the role")
+                .payload(r#"{"name"}"#),
+            CommandSpec::new::<UserRolePayload>("W", "assign_role")
+                .describe("Gives the role to the user")
+                .payload(r#"{"username", "role"}"#),
+            CommandSpec::new::<UserRolePayload>("W", "revoke_role")
+                .describe("Takes the role from the user")
+                .payload(r#"{"username", "role"}"#),
+            CommandSpec::new::<QuotaPayload>("W", "set_filer_quota")
+                .describe("Limits the filer storage of the user or of each member of the group, 0 is unlimited")
+                .payload(r#"{"kind": "user|group", "name", "limit_mb"}"#)
+                .with_schema(json!({
+                    "type": "object",
+                    "properties": {"kind": {"enum": ["user", "group"]}, "name": non_empty}
+                })),
+            CommandSpec::new::<QuotaNamePayload>("W", "remove_filer_quota")
+                .describe("Removes the filer quota of the user or the group")
+                .payload(r#"{"kind": "user|group", "name"}"#),
+            CommandSpec::new::<UsernamePayload>("W", "reset_filer_usage")
+                .describe("Counts the filer usage of the user on the disk again")
+                .payload(r#"{"username"}"#),
+        ]
+    }
+}
+
 impl DeviceRequest for RootDev {
     fn request_query(&self, _query: &QCommand) -> Result<jsVal, String> {
         Err("Unimplemented".to_string())
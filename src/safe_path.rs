@@ -0,0 +1,255 @@
+use urlencoding;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Path in the container of the filer user (`filer/{username}`), which never leads out of it.
+///
+/// User's path is split by `/`, empty parts and `.` are skipped and `..` goes one level up, but never
+/// above the container, so `/docs/a.txt` and `docs//./a.txt` are the same path, and absolute paths
+/// are taken relative to the container. Then the deepest existing part of the path is resolved
+/// with all its symlinks, and it must stay inside the container as well. Names with dots,
+/// like `v1..2.txt`, are fine
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::safe_path::SafePath;
+///
+/// let storage = tempfile::tempdir().unwrap();
+/// let container = storage.path().join("user");
+/// std::fs::create_dir(&container).unwrap();
+///
+/// let path = SafePath::new(&container, "/docs/./v1..2.txt").unwrap();
+/// assert_eq!(path.display(), "/docs/v1..2.txt");
+/// assert_eq!(path.full(), container.join("docs").join("v1..2.txt"));
+/// assert_eq!(SafePath::new(&container, "docs/../a.txt").unwrap().display(), "/a.txt");
+/// assert!(SafePath::new(&container, "/etc/passwd").unwrap().full().starts_with(&container));
+/// assert!(SafePath::new(&container, "../other_user/a.txt").is_err());
+/// assert!(SafePath::from_encoded(&container, "%2E%2E%2Fother_user").is_err());
+/// assert!(SafePath::new(&container, "").unwrap().is_root());
+/// ```
+///
+/// Symlinks are followed, so they can't lead out of the container either:
+///
+/// ```rust
+/// use webify::safe_path::SafePath;
+///
+/// let storage = tempfile::tempdir().unwrap();
+/// let container = storage.path().join("user");
+/// std::fs::create_dir_all(container.join("docs")).unwrap();
+/// std::os::unix::fs::symlink("/etc", container.join("etc")).unwrap();
+/// std::os::unix::fs::symlink(container.join("docs"), container.join("shortcut")).unwrap();
+///
+/// assert!(SafePath::new(&container, "etc/passwd").is_err());
+/// assert!(SafePath::new(&container, "etc/new_file").is_err());
+/// assert!(SafePath::new(&container, "shortcut/new_file").is_ok());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SafePath {
+    container: PathBuf,
+    parts: Vec<String>,
+}
+
+impl SafePath {
+    /// Joins the path (not url-encoded) onto the container
+    pub fn new(container: &Path, path: &str) -> Result<SafePath, String> {
+        if path.contains('\0') {
+            return Err("Forbidden path".to_string());
+        }
+        let mut parts: Vec<String> = vec![];
+        for part in path.split('/') {
+            match part {
+                "" | "." => continue,
+                ".." => {
+                    if parts.pop().is_none() {
+                        return Err("Forbidden path: it leads out of the storage".to_string());
+                    }
+                }
+                name => parts.push(name.to_string())
+            };
+        }
+        let path = SafePath { container: container.to_path_buf(), parts };
+        path.check_links()?;
+        Ok(path)
+    }
+
+    /// Joins the url-encoded path onto the container. Encoded separators are separators too
+    pub fn from_encoded(container: &Path, payload: &str) -> Result<SafePath, String> {
+        SafePath::new(container, &decode(payload)?)
+    }
+
+    /// Returns the path in the same directory with another name. Name can't contain separators
+    pub fn with_name(&self, name: &str) -> Result<SafePath, String> {
+        self.parent().join_name(name)
+    }
+
+    /// Returns the path of `name` inside this directory. Name can't contain separators
+    pub fn join_name(&self, name: &str) -> Result<SafePath, String> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+            return Err(format!("Bad name: `{}`", name));
+        }
+        let mut parts = self.parts.clone();
+        parts.push(name.to_string());
+        let path = SafePath { container: self.container.clone(), parts };
+        path.check_links()?;
+        Ok(path)
+    }
+
+    /// Returns the directory, which contains the path. Container is the parent of itself
+    pub fn parent(&self) -> SafePath {
+        let mut parts = self.parts.clone();
+        parts.pop();
+        SafePath { container: self.container.clone(), parts }
+    }
+
+    /// Whether the path is the container itself
+    pub fn is_root(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Returns the last part of the path, `None` for the container
+    pub fn name(&self) -> Option<&str> {
+        self.parts.last().map(|x| x.as_str())
+    }
+
+    /// Returns the full path on the disk
+    pub fn full(&self) -> PathBuf {
+        self.parts.iter().fold(self.container.clone(), |path, part| path.join(part))
+    }
+
+    /// Returns the path, as the user sees it: `/docs/a.txt`, and the empty string for the container
+    pub fn display(&self) -> String {
+        self.parts.iter().map(|x| format!("/{}", x)).collect()
+    }
+
+    /// Whether the path is this one or lies inside it
+    pub fn contains(&self, other: &SafePath) -> bool {
+        self.container == other.container && other.parts.starts_with(&self.parts)
+    }
+
+    /// Resolves symlinks of the deepest existing part of the path, it must be inside the container.
+    /// If the container doesn't exist yet, nothing inside it can lead out
+    fn check_links(&self) -> Result<(), String> {
+        let container = match fs::canonicalize(&self.container) {
+            Ok(path) => path,
+            Err(_) => return Ok(())
+        };
+        let mut existing = self.full();
+        while fs::symlink_metadata(&existing).is_err() {
+            if !existing.pop() {
+                return Ok(());
+            }
+        }
+        let resolved = fs::canonicalize(&existing)
+            .map_err(|_| format!("Forbidden path: `{}` leads nowhere", self.display()))?;
+        if !resolved.starts_with(&container) {
+            return Err("Forbidden path: it leads out of the storage".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the url-encoded path or name
+pub fn decode(payload: &str) -> Result<String, String> {
+    urlencoding::decode(payload).map(|x| x.to_string()).map_err(|_| format!("Couldn't decode payload: `{}`", payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config, TestRunner};
+    use std::os::unix::fs::symlink;
+    use std::path::Component;
+
+    /// Makes the container with a directory and symlinks, which lead inside, out of it, in loops and nowhere.
+    /// `other` next to it plays the storage of another user
+    fn make_container(storage: &Path) -> PathBuf {
+        let container = storage.join("user");
+        fs::create_dir_all(container.join("docs")).unwrap();
+        fs::create_dir_all(storage.join("other")).unwrap();
+        symlink("docs", container.join("in")).unwrap();
+        symlink(".", container.join("self")).unwrap();
+        symlink("..", container.join("up")).unwrap();
+        symlink("../other", container.join("out")).unwrap();
+        symlink("/etc", container.join("abs")).unwrap();
+        symlink("loop", container.join("loop")).unwrap();
+        symlink("missing", container.join("dangling")).unwrap();
+        symlink("../../other", container.join("docs").join("deep_out")).unwrap();
+        container
+    }
+
+    fn segment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(String::new()),
+            Just(".".to_string()),
+            Just("..".to_string()),
+            Just("...".to_string()),
+            Just("%2F".to_string()),
+            Just("%2E%2E".to_string()),
+            Just("..%2F..".to_string()),
+            Just("%252E%252E".to_string()),
+            Just("\\..".to_string()),
+            Just("other".to_string()),
+            Just("docs".to_string()),
+            Just("in".to_string()),
+            Just("self".to_string()),
+            Just("up".to_string()),
+            Just("out".to_string()),
+            Just("abs".to_string()),
+            Just("loop".to_string()),
+            Just("dangling".to_string()),
+            Just("deep_out".to_string()),
+            "[a-z.%]{1,6}",
+        ]
+    }
+
+    /// Checks that the path is inside the container both lexically and on the disk, and has no `.` or `..`
+    fn check_path(container: &Path, input: &str, path: &SafePath) -> Result<(), TestCaseError> {
+        let full = path.full();
+        prop_assert!(full.starts_with(container), "`{}` leads to {:?}", input, full);
+        prop_assert!(full.strip_prefix(container).unwrap().components().all(|x| matches!(x, Component::Normal(_))),
+                     "`{}` isn't normalised: {:?}", input, full);
+        prop_assert!(!path.display().split('/').skip(1).any(|x| x.is_empty() || x == "." || x == ".."),
+                     "`{}` is displayed as `{}`", input, path.display());
+
+        let mut existing = full.clone();
+        while fs::symlink_metadata(&existing).is_err() {
+            existing.pop();
+        }
+        let resolved = fs::canonicalize(&existing).unwrap();
+        prop_assert!(resolved.starts_with(fs::canonicalize(container).unwrap()), "`{}` resolves to {:?}", input, resolved);
+        Ok(())
+    }
+
+    #[test]
+    fn stays_in_container() {
+        let storage = tempfile::tempdir().unwrap();
+        let container = make_container(storage.path());
+        let mut runner = TestRunner::new(Config::with_cases(2000));
+        runner.run(&prop::collection::vec(segment(), 0..8), |segments| {
+            let input = segments.join("/");
+            for path in [SafePath::new(&container, &input), SafePath::from_encoded(&container, &input)].iter().flatten() {
+                check_path(&container, &input, path)?;
+            }
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn joined_names_stay_in_container() {
+        let storage = tempfile::tempdir().unwrap();
+        let container = make_container(storage.path());
+        let mut runner = TestRunner::new(Config::with_cases(2000));
+        runner.run(&(prop::collection::vec(segment(), 0..4), segment()), |(segments, name)| {
+            let input = segments.join("/");
+            if let Ok(path) = SafePath::new(&container, &input) {
+                for joined in [path.join_name(&name), path.with_name(&name)].iter().flatten() {
+                    check_path(&container, &format!("{} + {}", input, name), joined)?;
+                }
+            }
+            Ok(())
+        }).unwrap();
+    }
+}
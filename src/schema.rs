@@ -7,6 +7,13 @@ table! {
         qtype -> Text,
         rejected -> Integer,
         timestamp -> Timestamp,
+        api_token_id -> Nullable<Integer>,
+        ip -> Text,
+        user_agent -> Text,
+        payload_digest -> Text,
+        outcome -> Text,
+        error -> Text,
+        duration_ms -> Integer,
     }
 }
 
@@ -16,6 +23,22 @@ table! {
         name -> Text,
         password -> Text,
         groups -> Text,
+        totp_secret -> Nullable<Text>,
+        totp_enabled -> Integer,
+        recovery_codes -> Text,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Integer,
+        token -> Text,
+        username -> Text,
+        created_at -> Timestamp,
+        last_seen -> Timestamp,
+        ip -> Text,
+        user_agent -> Text,
+        csrf_token -> Text,
     }
 }
 
@@ -27,7 +50,112 @@ table! {
     }
 }
 
+table! {
+    api_tokens (id) {
+        id -> Integer,
+        token -> Text,
+        prefix -> Text,
+        username -> Text,
+        name -> Text,
+        groups -> Text,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    roles (id) {
+        id -> Integer,
+        name -> Text,
+        description -> Text,
+    }
+}
+
+table! {
+    role_groups (role_id, group_name) {
+        role_id -> Integer,
+        group_name -> Text,
+    }
+}
+
+table! {
+    role_inherits (role_id, parent_id) {
+        role_id -> Integer,
+        parent_id -> Integer,
+    }
+}
+
+table! {
+    user_roles (username, role_id) {
+        username -> Text,
+        role_id -> Integer,
+    }
+}
+
+table! {
+    user_groups (username, group_name) {
+        username -> Text,
+        group_name -> Text,
+    }
+}
+
+table! {
+    history_daily (day, username, device, command, qtype, rejected, outcome) {
+        day -> Text,
+        username -> Text,
+        device -> Text,
+        command -> Text,
+        qtype -> Text,
+        rejected -> Integer,
+        outcome -> Text,
+        counter -> Integer,
+    }
+}
+
+table! {
+    schema_version (version) {
+        version -> Integer,
+        name -> Text,
+        applied_at -> Timestamp,
+    }
+}
+
+table! {
+    filer_quotas (kind, name) {
+        kind -> Text,
+        name -> Text,
+        limit_bytes -> BigInt,
+    }
+}
+
+table! {
+    filer_usage (username) {
+        username -> Text,
+        used_bytes -> BigInt,
+    }
+}
+
+table! {
+    device_groups (group_name) {
+        group_name -> Text,
+        device -> Text,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(
     history,
+    history_daily,
     users,
+    sessions,
+    api_tokens,
+    roles,
+    role_groups,
+    role_inherits,
+    user_roles,
+    user_groups,
+    schema_version,
+    filer_quotas,
+    filer_usage,
+    device_groups,
 );
@@ -1,25 +1,149 @@
 extern crate actix_web;
 extern crate actix_form_data;
 
-use crate::dashboard::{dashboard_page, DashBoard, dashboard_page_req, file_sender, upload_index, uploader, dashboard_reload_templates};
-use crate::database::get_random_token;
-use crate::config::Config;
+use crate::dashboard::{dashboard_page, DashBoard, dashboard_page_req, file_sender, history_export, upload_index, uploader, dashboard_reload_templates};
+use crate::database::{get_random_token, is_csrf_token_valid};
+use crate::config::{Config, DEFAULT_CONFIG_PATH};
+use crate::api::{api_dispatch, api_commands, json_config};
 use crate::file_cache::FileCache;
+use crate::startup::StartupError;
+use crate::device_registry::RegisterDevices;
+use crate::tls::{ReloadableCert, ClientCertificate, make_server_config, run_tls_reloader, reload_on_sighup,
+                 save_client_certificate, get_certificate_names, CertField};
 
 use cookie::Cookie;
 use actix_web::{App, HttpResponse, HttpServer, middleware, web, cookie, HttpRequest};
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use actix_web::{Error, http};
-use rustls_pemfile;
-use rustls::server::ServerConfig;
-use rustls::{Certificate, PrivateKey};
 use secstr::SecStr;
+use chrono::Utc;
 
 use std::sync::{Arc, Mutex};
-use std::fs::File;
-use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
 
 pub const AUTH_COOKIE: &'static str = "authid";
+/// Cookie of the user, who has passed the password check, but not the second factor yet
+pub const PENDING_LOGIN_COOKIE: &str = "authid_pending";
+
+/// Reverse proxies (`trusted_proxies` of the config), which may tell the client address
+/// with `Forwarded`/`X-Forwarded-For` headers. Headers from the other peers are ignored
+#[derive(Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// Parses the addresses of the proxies
+    pub fn new(proxies: &[String]) -> Result<TrustedProxies, String> {
+        proxies.iter()
+            .map(|x| x.trim().parse::<IpAddr>().map_err(|_| format!("Bad address of the trusted proxy: `{}`", x)))
+            .collect::<Result<Vec<IpAddr>, String>>()
+            .map(TrustedProxies)
+    }
+
+    /// Returns the client address. If the peer is the trusted proxy, hops of the forwarded headers
+    /// are walked from the right, and the first one, which isn't the trusted proxy, is the client
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use webify::server::TrustedProxies;
+    ///
+    /// let proxies = TrustedProxies::new(&["10.0.0.1".to_string(), "10.0.0.2".to_string()]).unwrap();
+    /// let hops = vec!["1.1.1.1".to_string(), "2.2.2.2".to_string(), "10.0.0.2".to_string()];
+    /// assert_eq!(proxies.get_client_ip("10.0.0.1".parse().unwrap(), &hops).to_string(), "2.2.2.2");
+    /// assert_eq!(proxies.get_client_ip("3.3.3.3".parse().unwrap(), &hops).to_string(), "3.3.3.3");
+    /// ```
+    pub fn get_client_ip(&self, peer: IpAddr, hops: &[String]) -> IpAddr {
+        let mut client = peer;
+        if !self.0.contains(&peer) {
+            return client;
+        }
+        for hop in hops.iter().rev() {
+            match parse_hop(hop) {
+                Some(ip) => client = ip,
+                None => break
+            };
+            if !self.0.contains(&client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Parses the address of the forwarded hop: `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:80"` or `2001:db8::1`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split(']').next().and_then(|x| x.parse::<IpAddr>().ok());
+    }
+    hop.parse::<IpAddr>().ok().or(hop.parse::<SocketAddr>().ok().map(|x| x.ip()))
+}
+
+/// Returns hops of `Forwarded` (its `for=` values) or, if there is no such header, of `X-Forwarded-For`,
+/// from the client to the last proxy
+fn get_forwarded_hops(req: &HttpRequest) -> Vec<String> {
+    let values = |name: &str| req.headers().get_all(name)
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim().to_string())
+        .collect::<Vec<String>>();
+
+    let forwarded = values("forwarded");
+    if forwarded.is_empty() {
+        return values("x-forwarded-for");
+    }
+    forwarded.iter()
+        .map(|element| element.split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default())
+        .collect()
+}
+
+/// Returns IP address and User-Agent of the request, empty strings if they are unknown
+pub fn get_client_info(req: &HttpRequest) -> (String, String) {
+    let ip = match (req.peer_addr(), req.app_data::<web::Data<TrustedProxies>>()) {
+        (Some(peer), Some(proxies)) => proxies.get_client_ip(peer.ip(), &get_forwarded_hops(req)).to_string(),
+        (Some(peer), None) => peer.ip().to_string(),
+        (None, _) => String::new()
+    };
+    let user_agent = req.headers().get(http::header::USER_AGENT)
+        .and_then(|val| val.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    (ip, user_agent)
+}
+
+/// Makes the cookie, which is only sent over HTTPS, is hidden from the scripts
+/// and is not sent with the requests initiated by other sites
+pub fn make_secure_cookie(name: &str, value: &str) -> Cookie<'static> {
+    Cookie::build(name.to_string(), value.to_string())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(cookie::SameSite::Strict)
+        .finish()
+}
+
+/// Checks that the request has been sent from the pages of this server.
+/// `Origin` header is used, `Referer` is the fallback. Requests without both of them are allowed,
+/// as browsers always send at least one of them with the cross-site form submissions
+pub fn is_same_origin(req: &HttpRequest) -> bool {
+    let source = match req.headers().get(http::header::ORIGIN).or(req.headers().get(http::header::REFERER)) {
+        Some(val) => match val.to_str() {
+            Ok(data) => data.to_string(),
+            Err(_) => return false
+        },
+        None => return true
+    };
+    let host = req.connection_info().host().to_string();
+    let without_scheme = match source.split_once("://") {
+        Some((_, rest)) => rest,
+        None => return false
+    };
+    without_scheme.split('/').next() == Some(host.as_str())
+}
 
 fn get_static_file(info: &str, mdata: web::Data<FileCache>) -> Result<HttpResponse, Error> {
     let static_str = match mdata.get_ref().clone().get_str_file(info) {
@@ -60,19 +184,68 @@ struct LoginInfo {
     password: String,
 }
 
+/// Info, used in the second factor form when logging in
+#[derive(Deserialize)]
+struct SecondFactorInfo {
+    code: String,
+}
+
+/// Info, used in the logout form
+#[derive(Deserialize)]
+struct LogoutInfo {
+    csrf_token: String,
+}
+
+/// Starts new session for the user, who has passed all the checks
+fn start_session(req: &HttpRequest, nick: &str, mdata: &web::Data<DashBoard<'_>>, f_cache: web::Data<FileCache>) -> Result<HttpResponse, Error> {
+    let (ip, user_agent) = get_client_info(req);
+    let token = get_random_token();
+    let cookie = make_secure_cookie(AUTH_COOKIE, &token);
+
+    match mdata.database.assign_cookie(nick, &token, &ip, &user_agent) {
+        Ok(_) => { debug!("New login: `{}` -> `{}`", nick, token) }
+        Err(e) => {
+            error!("Error on assigning cookies: {}", e);
+        }
+    };
+
+    get_static_file("login_success.html", f_cache).map(|mut resp| {
+        resp.add_cookie(&cookie).map_err(|err| {
+            error!("Error in adding cookies: {:?}", err);
+        }).unwrap_or(());
+        resp
+    })
+}
 
 /// Handles login requests when LoginInfo has been already sent
 async fn login_handler(req: HttpRequest, form: web::Form<LoginInfo>, mdata: web::Data<DashBoard<'_>>, f_cache: web::Data<FileCache>) -> Result<HttpResponse, Error> {
     debug!("login_handler: {:?}", req.cookie(AUTH_COOKIE));
+    if !is_same_origin(&req) {
+        warn!("Rejected cross-origin login request");
+        return Ok(HttpResponse::Forbidden().body("Forbidden: cross-origin request"));
+    }
 
     let nick = form.username.clone();
     let password = SecStr::from(form.password.as_str());
+    let (ip, _) = get_client_info(&req);
 
-    let validated = match mdata.database.validate_user(&nick, &password) {
+    match mdata.database.get_lockout(&nick, &ip) {
+        Ok(Some(left)) => {
+            warn!("Rejected login of `{}` from {}: locked out", nick, ip);
+            return Ok(HttpResponse::TooManyRequests().body(format!("Too many failed attempts, try again in {} seconds", left)));
+        }
+        Ok(None) => (),
+        Err(e) => {
+            error!("Error on checking lockout: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
+        }
+    };
+
+    let validated = match mdata.database.validate_user(&nick, &password, &ip) {
         Ok(data) => data,
         Err(e) => {
             error!("Error on handling login: {}", e);
-            return Ok(HttpResponse::InternalServerError().body(format!("Error on login")));
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
         }
     };
 
@@ -80,17 +253,33 @@ async fn login_handler(req: HttpRequest, form: web::Form<LoginInfo>, mdata: web:
         return Ok(HttpResponse::Ok().body("Incorrect login or password"));
     }
 
-    let token = get_random_token();
-    let cookie = Cookie::new(AUTH_COOKIE, &token);
+    finish_login(&req, &nick, &mdata, f_cache)
+}
 
-    match mdata.database.assign_cookie(&nick, &token) {
-        Ok(_) => { debug!("New login: `{}` -> `{}`", nick, token) }
+/// Starts the session of the user, who has passed the first factor, or asks for the second one, if it is enabled
+fn finish_login(req: &HttpRequest, nick: &str, mdata: &web::Data<DashBoard<'_>>, f_cache: web::Data<FileCache>) -> Result<HttpResponse, Error> {
+    let totp_enabled = match mdata.database.is_totp_enabled(nick) {
+        Ok(data) => data,
         Err(e) => {
-            error!("Error on assigning cookies: {}", e);
+            error!("Error on checking the second factor: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
         }
     };
 
-    get_static_file("login_success.html", f_cache).map(|mut resp| {
+    if !totp_enabled {
+        return start_session(req, nick, mdata, f_cache);
+    }
+
+    let pending = match mdata.database.create_pending_login(nick) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error on creating pending login: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
+        }
+    };
+    let cookie = make_secure_cookie(PENDING_LOGIN_COOKIE, &pending);
+
+    get_static_file("login_totp.html", f_cache).map(|mut resp| {
         resp.add_cookie(&cookie).map_err(|err| {
             error!("Error in adding cookies: {:?}", err);
         }).unwrap_or(());
@@ -98,12 +287,140 @@ async fn login_handler(req: HttpRequest, form: web::Form<LoginInfo>, mdata: web:
     })
 }
 
-async fn logout_handler(req: HttpRequest, mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
-    let cookie = match req.cookie(AUTH_COOKIE) {
+/// Handles the second factor, when the password or the client certificate has been already checked
+async fn login_totp_handler(req: HttpRequest, form: web::Form<SecondFactorInfo>, mdata: web::Data<DashBoard<'_>>, f_cache: web::Data<FileCache>) -> Result<HttpResponse, Error> {
+    if !is_same_origin(&req) {
+        warn!("Rejected cross-origin login request");
+        return Ok(HttpResponse::Forbidden().body("Forbidden: cross-origin request"));
+    }
+    let pending = match req.cookie(PENDING_LOGIN_COOKIE) {
         Some(data) => data.value().to_string(),
         None => return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish()),
     };
 
+    let nick = match mdata.database.get_pending_login(&pending) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Error in login_totp_handler at getting the user: {}", e);
+            return Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/login")).finish());
+        }
+    };
+    let (ip, _) = get_client_info(&req);
+
+    match mdata.database.get_lockout(&nick, &ip) {
+        Ok(Some(left)) => {
+            warn!("Rejected second factor of `{}` from {}: locked out", nick, ip);
+            return Ok(HttpResponse::TooManyRequests().body(format!("Too many failed attempts, try again in {} seconds", left)));
+        }
+        Ok(None) => (),
+        Err(e) => {
+            error!("Error on checking lockout: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
+        }
+    };
+
+    let validated = match mdata.database.validate_second_factor(&nick, &form.code, &ip, Utc::now().timestamp() as u64) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error on handling second factor: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
+        }
+    };
+
+    if !validated {
+        return Ok(HttpResponse::Ok().body("Incorrect code"));
+    }
+
+    match mdata.database.remove_pending_login(&pending) {
+        Ok(_) => (),
+        Err(e) => error!("Error on removing pending login: {}", e)
+    };
+
+    start_session(&req, &nick, &mdata, f_cache).map(|mut resp| {
+        resp.add_removal_cookie(&make_secure_cookie(PENDING_LOGIN_COOKIE, "")).map_err(|err| {
+            error!("Error in removing cookies: {:?}", err);
+        }).unwrap_or(());
+        resp
+    })
+}
+
+/// Logs in the user with the client certificate, verified by the CA from `tls_client_ca_path`.
+/// The field from `tls_client_cert_field` must have the single value, the name of the user.
+/// Lockout and the second factor are checked the same way as for the password. It starts the session,
+/// so other sites must not be able to send it, same as `/get_logged_in`
+async fn login_cert_handler(req: HttpRequest, mdata: web::Data<DashBoard<'_>>, cert_field: web::Data<CertField>, f_cache: web::Data<FileCache>) -> Result<HttpResponse, Error> {
+    if !is_same_origin(&req) {
+        warn!("Rejected cross-origin certificate login request");
+        return Ok(HttpResponse::Forbidden().body("Forbidden: cross-origin request"));
+    }
+
+    let cert = match req.conn_data::<ClientCertificate>() {
+        Some(data) => data.clone(),
+        None => return Ok(HttpResponse::Unauthorized().body("No client certificate has been presented")),
+    };
+
+    let names = match get_certificate_names(&cert.0, *cert_field.get_ref()) {
+        Some(data) => data,
+        None => {
+            warn!("Couldn't parse the client certificate");
+            return Ok(HttpResponse::BadRequest().body("Couldn't parse the client certificate"));
+        }
+    };
+    let name = match names.as_slice() {
+        [name] => name,
+        _ => {
+            warn!("Client certificate should have the single {:?}, got {:?}", cert_field.get_ref(), names);
+            return Ok(HttpResponse::Forbidden().body("There is no user for this certificate"));
+        }
+    };
+
+    let nick = match mdata.database.find_user_by_cert_name(name) {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            warn!("No user for the client certificate with the name `{}`", name);
+            return Ok(HttpResponse::Forbidden().body("There is no user for this certificate"));
+        }
+        Err(e) => {
+            error!("Error on finding user for the certificate: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
+        }
+    };
+    let (ip, _) = get_client_info(&req);
+
+    match mdata.database.get_lockout(&nick, &ip) {
+        Ok(Some(left)) => {
+            warn!("Rejected certificate login of `{}` from {}: locked out", nick, ip);
+            return Ok(HttpResponse::TooManyRequests().body(format!("Too many failed attempts, try again in {} seconds", left)));
+        }
+        Ok(None) => (),
+        Err(e) => {
+            error!("Error on checking lockout: {}", e);
+            return Ok(HttpResponse::InternalServerError().body("Error on login".to_string()));
+        }
+    };
+
+    info!("Login of `{}` with the client certificate", nick);
+    finish_login(&req, &nick, &mdata, f_cache)
+}
+
+/// Ends the session. It changes the state, so it is POST with the CSRF token of the session,
+/// otherwise any page could log the user out
+async fn logout_handler(req: HttpRequest, form: web::Form<LogoutInfo>, mdata: web::Data<DashBoard<'_>>) -> Result<HttpResponse, Error> {
+    let cookie = match req.cookie(AUTH_COOKIE) {
+        Some(data) => data.value().to_string(),
+        None => return Ok(HttpResponse::SeeOther().append_header((http::header::LOCATION, "/login")).finish()),
+    };
+
+    let (ip, user_agent) = get_client_info(&req);
+    let session = match mdata.database.get_session_from_cookie(&cookie, &ip, &user_agent) {
+        Ok(data) => data,
+        Err(_) => return Ok(HttpResponse::SeeOther().append_header((http::header::LOCATION, "/login")).finish()),
+    };
+    if !is_csrf_token_valid(&session.csrf_token, &form.csrf_token) {
+        warn!("Rejected logout of `{}` from {}: invalid CSRF token", session.username, ip);
+        return Ok(HttpResponse::Forbidden().body("Forbidden: invalid CSRF token, reload the page"));
+    }
+
     match mdata.database.remove_cookie(&cookie) {
         Ok(_) => { debug!("Logout {}", &cookie) }
         Err(e) => {
@@ -111,7 +428,11 @@ async fn logout_handler(req: HttpRequest, mdata: web::Data<DashBoard<'_>>) -> Re
         }
     };
 
-    Ok(HttpResponse::TemporaryRedirect().append_header((http::header::LOCATION, "/main")).finish())
+    let mut resp = HttpResponse::SeeOther().append_header((http::header::LOCATION, "/main")).finish();
+    resp.add_removal_cookie(&make_secure_cookie(AUTH_COOKIE, "")).map_err(|err| {
+        error!("Error in removing cookies: {:?}", err);
+    }).unwrap_or(());
+    Ok(resp)
 }
 
 /// Returns standard login page with form for signing in
@@ -125,7 +446,7 @@ async fn main_page(mdata: web::Data<FileCache>) -> Result<HttpResponse, Error> {
 }
 
 /// Runs all initial functions and starts the server.
-/// Reference to the mutexed config is needed
+/// Reference to the mutexed config is needed. Returns the error, if the server couldn't start
 ///
 /// # Example
 /// ```rust
@@ -136,45 +457,125 @@ async fn main_page(mdata: web::Data<FileCache>) -> Result<HttpResponse, Error> {
 ///
 /// let config = Arc::new(Mutex::new(config::read_config::<config::Config>(config::DEFAULT_CONFIG_PATH).unwrap()));
 /// let handler = thread::spawn(move || run_server(config));
-/// assert!(handler.join().is_ok());
+/// assert!(handler.join().unwrap().is_ok());
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>) -> Result<(), StartupError> {
+    run_server_with_devices(a_config, |_, _, _| Ok(()))
+}
+
+/// Same as `run_server`, but `register` adds more devices after the built-in ones,
+/// so other crates can bring their own devices
+///
+/// # Example
+/// ```rust,no_run
+/// use std::sync::{Arc, Mutex};
+/// use serde_json::{json, Value as jsVal};
+/// use webify::config::{self, Config};
+/// use webify::dashboard::QCommand;
+/// use webify::database::Database;
+/// use webify::device_registry::DeviceRegistry;
+/// use webify::device_trait::*;
+/// use webify::server::run_server_with_devices;
+/// use webify::startup::StartupError;
+///
+/// struct Thermometer;
+///
+/// impl Thermometer {
+///     fn new(_config: &Config) -> Thermometer {
+///         Thermometer
+///     }
+/// }
+///
+/// impl DeviceRead for Thermometer {
+///     fn read_data(&self, _query: &QCommand) -> Result<jsVal, String> {
+///         Ok(json!({"template": "thermo.hbs", "celsius": 21.5}))
+///     }
+///     fn read_status(&self, query: &QCommand) -> Result<jsVal, String> {
+///         self.read_data(query)
+///     }
+/// }
+///
+/// impl DeviceWrite for Thermometer {
+///     fn write_data(&self, _query: &QCommand) -> Result<jsVal, String> {
+///         Err("Unimplemented".to_string())
+///     }
+/// }
+///
+/// impl DeviceRequest for Thermometer {
+///     fn request_query(&self, _query: &QCommand) -> Result<jsVal, String> {
+///         Err("Unimplemented".to_string())
+///     }
+/// }
+///
+/// impl DeviceConfirm for Thermometer {
+///     fn confirm_query(&self, _query: &QCommand) -> Result<jsVal, String> {
+///         Err("Unimplemented".to_string())
+///     }
+///     fn dismiss_query(&self, _query: &QCommand) -> Result<jsVal, String> {
+///         Err("Unimplemented".to_string())
+///     }
+/// }
+///
+/// impl DeviceCommands for Thermometer {}
+///
+/// fn register(registry: &mut DeviceRegistry, _database: &Database, config: &Config) -> Result<(), StartupError> {
+///     if !registry.is_enabled("thermo") {
+///         return Ok(());
+///     }
+///     registry.register("thermo", [None, Some("thermo_read"), None, None, None, None], &["thermo.hbs"], Thermometer::new(config))
+///         .map_err(|reason| StartupError::Device { device: "thermo".to_string(), reason })
+/// }
+///
+/// let config = Arc::new(Mutex::new(config::read_config::<Config>(config::DEFAULT_CONFIG_PATH).unwrap()));
+/// run_server_with_devices(config, register).unwrap();
 /// ```
 #[actix_rt::main]
-pub async fn run_server(a_config: Arc<Mutex<Config>>) {
-    let config: Config = { a_config.lock().unwrap().clone() };
-    let ds = match DashBoard::new(&config) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Error on starting the server (make dashboard): {:?}", e);
-            return;
-        }
+pub async fn run_server_with_devices(a_config: Arc<Mutex<Config>>, register: RegisterDevices) -> Result<(), StartupError> {
+    let config: Config = match a_config.lock() {
+        Ok(data) => data.clone(),
+        Err(e) => return Err(StartupError::ConfigInvalid { path: "<memory>".to_string(), reason: format!("{:?}", e) })
     };
-    let stat_files = FileCache::new();
-
-    let cert_file = &mut BufReader::new(File::open("cert.pem").unwrap());
-    let key_file = &mut BufReader::new(File::open("key.pem").unwrap());
 
-    let cert_chain = rustls_pemfile::certs(cert_file).unwrap();
-    let keys = rustls_pemfile::rsa_private_keys(key_file).unwrap();
+    let config_tls = if config.tls_enabled {
+        let resolver = Arc::new(ReloadableCert::new(&config.tls_cert_path, &config.tls_key_path).map_err(StartupError::Tls)?);
+        let client_ca = Some(config.tls_client_ca_path.as_str()).filter(|x| !x.is_empty());
+        let config_tls = make_server_config(resolver.clone(), client_ca).map_err(StartupError::Tls)?;
+        run_tls_reloader(&resolver, &config);
+        reload_on_sighup(&resolver);
+        Some(config_tls)
+    } else {
+        warn!("TLS is disabled, serving plain HTTP. Make sure the reverse proxy terminates TLS");
+        None
+    };
 
-    let config_tls = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(vec![Certificate { 0: cert_chain[0].clone() }], PrivateKey { 0: keys[0].clone() })
-        .unwrap();
+    let ds = DashBoard::with_devices(&config, register)?;
+    let stat_files = FileCache::new();
+    let trusted_proxies = TrustedProxies::new(&config.trusted_proxies)
+        .map_err(|reason| StartupError::ConfigInvalid { path: DEFAULT_CONFIG_PATH.to_string(), reason })?;
+    let cert_field = CertField::new(&config.tls_client_cert_field)
+        .map_err(|reason| StartupError::ConfigInvalid { path: DEFAULT_CONFIG_PATH.to_string(), reason })?;
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::DefaultHeaders::new().add(("X-Version", "0.2")))
             .wrap(middleware::Logger::new("%T sec  from %a `%r` -> `%s` %b `%{Referer}i` `%{User-Agent}i`"))
             .wrap(middleware::Compress::default())
             .app_data(web::Data::new(ds.clone()))
             .app_data(web::Data::new(stat_files.clone()))
+            .app_data(web::Data::new(trusted_proxies.clone()))
+            .app_data(web::Data::new(cert_field))
             .service(web::resource("/main").to(main_page))
             .service(web::resource("/").to(main_page))
             .service(web::resource("/login").to(login_page))
             .service(web::resource("/reload").to(dashboard_reload_templates))
-            .service(web::resource("/logout").to(logout_handler))
+            .service(web::resource("/logout").route(web::post().to(logout_handler)))
             .service(web::resource("/get_logged_in").route(web::post().to(login_handler)))
+            .service(web::resource("/get_logged_in_2fa").route(web::post().to(login_totp_handler)))
+            .service(web::resource("/login_cert").route(web::post().to(login_cert_handler)))
+            .service(web::resource("/api/commands").route(web::get().to(api_commands)))
+            .service(web::scope("/api/v1")
+                .app_data(json_config())
+                .service(web::resource("/{device}").route(web::post().to(api_dispatch))))
             .service(web::resource("/dashboard/{device}")
                 .route(web::post().to(dashboard_page_req))
                 .route(web::get().to(dashboard_page)))
@@ -182,13 +583,24 @@ pub async fn run_server(a_config: Arc<Mutex<Config>>) {
             .service(web::resource("/static/{path}").to(responce_static_file))
             .service(web::resource("/rstatic/{path}").to(responce_static_file_raw))
             .service(web::resource("/download/{path}").to(file_sender))
+            .service(web::resource("/export/history/{format}").route(web::get().to(history_export)))
             .service(
                 web::resource("/upload/{path}")
                     .route(web::get().to(upload_index))
                     .route(web::post().to(uploader)),
             )
-    })
-        .bind_rustls(config.bind_address, config_tls)
-        .unwrap()
-        .run().await.unwrap();
+    }).on_connect(save_client_certificate);
+
+    let bound = match config_tls {
+        Some(config_tls) => server.bind_rustls(&config.bind_address, config_tls),
+        None => server.bind(&config.bind_address),
+    };
+    let server = bound.map_err(|e| StartupError::Bind { address: config.bind_address.clone(), reason: format!("{}", e) })?;
+
+    info!("Listening on {}", config.bind_address);
+    match server.run().await {
+        Ok(_) => (),
+        Err(e) => error!("Error on running the server: {:?}", e)
+    };
+    Ok(())
 }
\ No newline at end of file
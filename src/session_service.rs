@@ -0,0 +1,26 @@
+use crate::config::Config;
+use crate::database::Database;
+
+use log::{debug, info, error};
+
+use std::thread;
+use std::time::Duration;
+
+pub fn run_session_sweeper(database: &Database, config: &Config) {
+    if config.session_sweep_period_s == 0 {
+        return;
+    }
+    let db_copy = database.clone();
+    let period = config.session_sweep_period_s;
+
+    thread::spawn(move || {
+        loop {
+            match db_copy.sweep_sessions() {
+                Ok(removed) => debug!("Session sweeper removed {} expired sessions", removed),
+                Err(err) => error!("Error occurred in session sweeper: {}", err)
+            };
+            thread::sleep(Duration::from_secs(period as u64));
+        }
+    });
+    info!("Session sweeper thread spawned");
+}
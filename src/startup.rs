@@ -0,0 +1,139 @@
+use crate::config::{read_config, Config, DEFAULT_CONFIG_PATH};
+use crate::printer_device::{PrinterConfig, PRINTER_CONFIG_PATH};
+use crate::database::check_database;
+use crate::template_cache::TemplateCache;
+use crate::tls::{load_certified_key, load_client_roots, CertField};
+use crate::io_tools::exists;
+use crate::server::TrustedProxies;
+
+use std::fmt;
+use std::net::ToSocketAddrs;
+
+/// Everything that can stop the server from starting. Each variant knows what has failed,
+/// so the message tells the admin what to fix
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartupError {
+    /// Config file doesn't exist
+    ConfigMissing(String),
+    /// Config file exists, but can't be read or parsed
+    ConfigInvalid { path: String, reason: String },
+    /// SQLite database can't be opened or upgraded
+    Database(String),
+    /// Redis server is unreachable. `role` tells which one of the configured URLs it is
+    Redis { role: String, url: String, reason: String },
+    /// Certificate, key or client CA can't be loaded
+    Tls(String),
+    /// Templates can't be loaded
+    Templates(String),
+    /// Device has failed to start
+    Device { device: String, reason: String },
+    /// Server can't listen on the address
+    Bind { address: String, reason: String },
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::ConfigMissing(path) =>
+                write!(f, "No `{}` file, run `$ webify --setup`", path),
+            StartupError::ConfigInvalid { path, reason } =>
+                write!(f, "Config `{}` is invalid: {}", path, reason),
+            StartupError::Database(reason) =>
+                write!(f, "Database error: {}", reason),
+            StartupError::Redis { role, url, reason } =>
+                write!(f, "Redis for {} at `{}` is unavailable: {}", role, url, reason),
+            StartupError::Tls(reason) =>
+                write!(f, "TLS error: {}", reason),
+            StartupError::Templates(reason) =>
+                write!(f, "Templates error: {}", reason),
+            StartupError::Device { device, reason } =>
+                write!(f, "Device `{}` has failed to start: {}", device, reason),
+            StartupError::Bind { address, reason } =>
+                write!(f, "Couldn't listen on `{}`: {}", address, reason),
+        }
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+/// Connects to redis and sends PING
+pub fn check_redis(url: &str) -> Result<(), String> {
+    let client = redis::Client::open(url).map_err(|err| format!("{}", err))?;
+    let mut connection = client.get_connection().map_err(|err| format!("{}", err))?;
+    redis::cmd("PING").query::<String>(&mut connection).map_err(|err| format!("{}", err))?;
+    Ok(())
+}
+
+/// Validates everything the server needs to start and returns all the problems at once.
+/// Nothing is modified, so it is safe to run next to the working server
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::startup::check_config;
+/// for problem in check_config() {
+///     eprintln!("{}", problem);
+/// }
+/// ```
+pub fn check_config() -> Vec<StartupError> {
+    let mut problems: Vec<StartupError> = vec![];
+
+    let config = match read_config::<Config>(DEFAULT_CONFIG_PATH) {
+        Ok(data) => data,
+        Err(err) => {
+            problems.push(err);
+            return problems;
+        }
+    };
+
+    match read_config::<PrinterConfig>(PRINTER_CONFIG_PATH) {
+        Ok(printer) => if !printer.storage.is_empty() && !exists(&printer.storage) {
+            problems.push(StartupError::ConfigInvalid {
+                path: PRINTER_CONFIG_PATH.to_string(),
+                reason: format!("printer storage `{}` doesn't exist", printer.storage),
+            });
+        },
+        Err(err) => problems.push(err)
+    };
+
+    if let Err(err) = config.bind_address.to_socket_addrs() {
+        problems.push(StartupError::Bind { address: config.bind_address.clone(), reason: format!("{}", err) });
+    }
+
+    if let Err(err) = check_database(&config.db_config) {
+        problems.push(StartupError::Database(err));
+    }
+
+    for (role, url) in [("blog (redis_config)", &config.redis_config), ("cache (redis_cache)", &config.redis_cache)] {
+        if let Err(reason) = check_redis(url) {
+            problems.push(StartupError::Redis { role: role.to_string(), url: url.clone(), reason });
+        }
+    }
+
+    if config.tls_enabled {
+        if let Err(err) = load_certified_key(&config.tls_cert_path, &config.tls_key_path) {
+            problems.push(StartupError::Tls(err));
+        }
+    }
+    if !config.tls_client_ca_path.is_empty() {
+        if !config.tls_enabled {
+            problems.push(StartupError::Tls("`tls_client_ca_path` is set, but TLS is disabled".to_string()));
+        }
+        if let Err(err) = load_client_roots(&config.tls_client_ca_path) {
+            problems.push(StartupError::Tls(err));
+        }
+        if let Err(err) = CertField::new(&config.tls_client_cert_field) {
+            problems.push(StartupError::Tls(err));
+        }
+    }
+
+    if let Err(reason) = TrustedProxies::new(&config.trusted_proxies) {
+        problems.push(StartupError::ConfigInvalid { path: DEFAULT_CONFIG_PATH.to_string(), reason });
+    }
+
+    if let Err(err) = TemplateCache::new().load("templates") {
+        problems.push(StartupError::Templates(err));
+    }
+
+    problems
+}
@@ -3,6 +3,7 @@ use crate::device_trait::*;
 use crate::dashboard::QCommand;
 use crate::config::Config;
 use crate::devices::{Devices, Groups, DEV_GROUPS};
+use crate::command_spec::{CommandSpec, NoPayload};
 use crate::stat_service::run_stat_service;
 use crate::autoban_service::run_autoban_svc;
 
@@ -32,12 +33,14 @@ pub const CROSS_STAT_CHARTS: [&'static str; 2] = [
 ];
 
 impl StatDevice {
-    pub fn new(database: &Database, config: &Config) -> Self {
-        let manager = RedisConnectionManager::new(config.redis_cache.as_str()).unwrap();
-        let pool = RedisPool::builder().build(manager).unwrap();
+    pub fn new(database: &Database, config: &Config) -> Result<Self, String> {
+        let manager = RedisConnectionManager::new(config.redis_cache.as_str())
+            .map_err(|err| format!("Error on creating redis manager for {}: {:?}", config.redis_cache, err))?;
+        let pool = RedisPool::builder().build(manager)
+            .map_err(|err| format!("Error on creating redis pool for {}: {:?}", config.redis_cache, err))?;
         run_stat_service(&pool, database, config);
         run_autoban_svc(database, config);
-        StatDevice { redis_pool: pool, database: database.clone() }
+        Ok(StatDevice { redis_pool: pool, database: database.clone() })
     }
 
     fn get_chart_data(&self, username: &str, chart_name: &str) -> Result<jsVal, String> {
@@ -105,6 +108,17 @@ impl DeviceRead for StatDevice {
 }
 
 
+/// Cross charts take the username
+impl DeviceCommands for StatDevice {
+    fn commands(&self) -> Vec<CommandSpec> {
+        STAT_CHARTS.iter().map(|x| CommandSpec::new::<NoPayload>("R", x).describe("Shows the chart"))
+            .chain(CROSS_STAT_CHARTS.iter().map(|x| {
+                CommandSpec::new::<String>("R", x).describe("Shows the chart of the user").payload("username")
+            }))
+            .collect()
+    }
+}
+
 impl DeviceWrite for StatDevice {
     fn write_data(&self, _query: &QCommand) -> Result<jsVal, String> {
         Err("Unimplemented".to_string())
@@ -0,0 +1,142 @@
+use crate::models::StatEntry;
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text, Timestamp};
+use diesel::sqlite::Sqlite;
+use chrono::{Utc, NaiveDateTime, Duration as ChronoDuration};
+
+/// Column of the history, which the stats are grouped by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatColumn {
+    Username,
+    Device,
+    Command,
+    Qtype,
+}
+
+impl StatColumn {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            StatColumn::Username => "username",
+            StatColumn::Device => "device",
+            StatColumn::Command => "command",
+            StatColumn::Qtype => "qtype",
+        }
+    }
+}
+
+/// Counts the history entries grouped by the column, the biggest counters go first.
+/// Only the column name gets into SQL text, all the values are bound, so the query is safe for any username.
+/// Run it with `Database::load_stats`
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::stat_query::{StatQuery, StatColumn};
+///
+/// // 10 most used commands of `alice` for the last 30 days, including the archived history
+/// let query = StatQuery::count_by(StatColumn::Command)
+///     .since_s(30 * 24 * 3600)
+///     .for_user("alice")
+///     .with_archive()
+///     .limit(10);
+/// ```
+#[derive(Clone, Debug)]
+pub struct StatQuery {
+    column: StatColumn,
+    since: Option<NaiveDateTime>,
+    username: Option<String>,
+    with_archive: bool,
+    limit: Option<i64>,
+}
+
+impl StatQuery {
+    /// Counts the whole history by `column`
+    pub fn count_by(column: StatColumn) -> StatQuery {
+        StatQuery {
+            column,
+            since: None,
+            username: None,
+            with_archive: false,
+            limit: None,
+        }
+    }
+
+    /// Counts only the entries for the last `seconds`
+    pub fn since_s(mut self, seconds: u32) -> StatQuery {
+        self.since = Some(Utc::now().naive_utc() - ChronoDuration::seconds(seconds as i64));
+        self
+    }
+
+    /// Counts only the entries of the user
+    pub fn for_user(mut self, username: &str) -> StatQuery {
+        self.username = Some(username.to_string());
+        self
+    }
+
+    /// Counts the daily aggregates of the archived history as well, see `history_service`.
+    /// Aggregates have no time of the day, so the first day of the period is counted as a whole
+    pub fn with_archive(mut self) -> StatQuery {
+        self.with_archive = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> StatQuery {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// `WHERE` clause of the table, `time_check` compares the time column with the bound `since`
+    fn where_sql(&self, time_check: &str) -> String {
+        let mut conditions: Vec<&str> = vec![];
+        if self.since.is_some() {
+            conditions.push(time_check);
+        }
+        if self.username.is_some() {
+            conditions.push("username = ?");
+        }
+        if conditions.is_empty() {
+            return String::new();
+        }
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+
+    fn to_sql(&self) -> String {
+        let column = self.column.as_sql();
+        let history_where = self.where_sql("timestamp > ?");
+        let mut sql = if self.with_archive {
+            format!("SELECT label, SUM(counter) AS counter FROM (\
+                SELECT {column} AS label, 1 AS counter FROM history{history_where} \
+                UNION ALL \
+                SELECT {column} AS label, counter FROM history_daily{daily_where}\
+                ) GROUP BY label ORDER BY SUM(counter) DESC",
+                    column = column, history_where = history_where, daily_where = self.where_sql("day >= date(?)"))
+        } else {
+            format!("SELECT {column} AS label, COUNT(*) AS counter FROM history{history_where} \
+                GROUP BY {column} ORDER BY COUNT(*) DESC", column = column, history_where = history_where)
+        };
+        if self.limit.is_some() {
+            sql.push_str(" LIMIT ?");
+        }
+        sql
+    }
+
+    /// Runs the query on the connection
+    pub fn load(&self, connection: &mut SqliteConnection) -> Result<Vec<StatEntry>, String> {
+        let mut query = diesel::sql_query(self.to_sql()).into_boxed::<Sqlite>();
+        let tables = if self.with_archive { 2 } else { 1 };
+        for _ in 0..tables {
+            if let Some(since) = self.since {
+                query = query.bind::<Timestamp, _>(since);
+            }
+            if let Some(username) = &self.username {
+                query = query.bind::<Text, _>(username.clone());
+            }
+        }
+        if let Some(limit) = self.limit {
+            query = query.bind::<BigInt, _>(limit);
+        }
+        query.load::<StatEntry>(connection)
+            .map_err(|err| format!("Error on loading stats by {}: {:?}", self.column.as_sql(), err))
+    }
+}
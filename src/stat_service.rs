@@ -2,6 +2,7 @@ use crate::config::Config;
 use crate::stat_device;
 use crate::template_cache::TemplateCache;
 use crate::database::Database;
+use crate::stat_query::{StatQuery, StatColumn};
 
 use log::{debug, info, error};
 use r2d2_redis::{RedisConnectionManager, r2d2 as r2d2_red};
@@ -37,38 +38,43 @@ const COLORS_SET: [Color; 10] = [
 ];
 
 
-type Formatter1 = fn(u32) -> String;
-type Formatter2 = fn(u32, &str) -> String;
+type ChartQuery = fn(u32) -> StatQuery;
+type CrossChartQuery = fn(u32, &str) -> StatQuery;
 
-fn chart_device_fmt(seconds: u32) -> String {
-    format!("SELECT device as label, COUNT(*) as counter FROM history WHERE timestamp > date('now', '-{} second') GROUP BY device ORDER BY COUNT(timestamp) DESC LIMIT 10;", seconds)
+/// Charts show 10 top labels for the period, including the archived history
+fn chart_query(column: StatColumn, seconds: u32) -> StatQuery {
+    StatQuery::count_by(column).since_s(seconds).with_archive().limit(10)
 }
 
-fn chart_user_fmt(seconds: u32) -> String {
-    format!("SELECT username as label, COUNT(*) as counter FROM history WHERE timestamp > date('now', '-{} second') GROUP BY username ORDER BY COUNT(timestamp) DESC LIMIT 10;", seconds)
+fn chart_device_query(seconds: u32) -> StatQuery {
+    chart_query(StatColumn::Device, seconds)
 }
 
-fn chart_command_fmt(seconds: u32) -> String {
-    format!("SELECT command as label, COUNT(*) as counter FROM history WHERE timestamp > date('now', '-{} second') GROUP BY command ORDER BY COUNT(timestamp) DESC LIMIT 10;", seconds)
+fn chart_user_query(seconds: u32) -> StatQuery {
+    chart_query(StatColumn::Username, seconds)
 }
 
-fn chart_device_cross_user_fmt(seconds: u32, username: &str) -> String {
-    format!("SELECT device as label, COUNT(*) as counter FROM history WHERE timestamp > date('now', '-{} second') AND username = '{}' GROUP BY device ORDER BY COUNT(timestamp) DESC LIMIT 10;", seconds, username)
+fn chart_command_query(seconds: u32) -> StatQuery {
+    chart_query(StatColumn::Command, seconds)
 }
 
-fn chart_cmd_cross_user_fmt(seconds: u32, username: &str) -> String {
-    format!("SELECT command as label, COUNT(*) as counter FROM history WHERE timestamp > date('now', '-{} second') AND username = '{}' GROUP BY command ORDER BY COUNT(timestamp) DESC LIMIT 10;", seconds, username)
+fn chart_device_cross_user_query(seconds: u32, username: &str) -> StatQuery {
+    chart_query(StatColumn::Device, seconds).for_user(username)
 }
 
-static CHARTS_QUERIES: phf::Map<&'static str, Formatter1> = phf_map! {
-    "chart_device" => chart_device_fmt,
-    "chart_user" => chart_user_fmt,
-    "chart_command" => chart_command_fmt
+fn chart_cmd_cross_user_query(seconds: u32, username: &str) -> StatQuery {
+    chart_query(StatColumn::Command, seconds).for_user(username)
+}
+
+static CHARTS_QUERIES: phf::Map<&'static str, ChartQuery> = phf_map! {
+    "chart_device" => chart_device_query,
+    "chart_user" => chart_user_query,
+    "chart_command" => chart_command_query
 };
 
-static CROSS_CHARTS_QUERIES: phf::Map<&'static str, Formatter2> = phf_map! {
-    "chart_cmd_cross_user" => chart_cmd_cross_user_fmt,
-    "chart_device_cross_user" => chart_device_cross_user_fmt
+static CROSS_CHARTS_QUERIES: phf::Map<&'static str, CrossChartQuery> = phf_map! {
+    "chart_cmd_cross_user" => chart_cmd_cross_user_query,
+    "chart_device_cross_user" => chart_device_cross_user_query
 };
 
 fn cache_general_stats(conn_pool: &RedisPool, database: &Database, template_cache: &TemplateCache, period_s: u32) -> Result<(), String> {
@@ -78,14 +84,14 @@ fn cache_general_stats(conn_pool: &RedisPool, database: &Database, template_cach
     };
 
     for chart in stat_device::STAT_CHARTS {
-        let query: String = match CHARTS_QUERIES.get(chart) {
+        let query: StatQuery = match CHARTS_QUERIES.get(chart) {
             Some(v) => v(period_s),
             None => {
                 error!("No such chart available: `{}`", chart);
                 continue;
             }
         };
-        let stats_db = match database.load_stats_by_query(&query) {
+        let stats_db = match database.load_stats(&query) {
             Ok(data) => data,
             Err(err) => {
                 error!("Failed to load stats `{}`: `{}`", chart, err);
@@ -146,14 +152,14 @@ fn cache_cross_stats(conn_pool: &RedisPool, database: &Database, template_cache:
 
     for chart in stat_device::CROSS_STAT_CHARTS {
         for user in &users_db {
-            let query: String = match CROSS_CHARTS_QUERIES.get(chart) {
+            let query: StatQuery = match CROSS_CHARTS_QUERIES.get(chart) {
                 Some(v) => v(period_s, user),
                 None => {
                     error!("No such chart available: `{}`", chart);
                     continue;
                 }
             };
-            let stats_db = match database.load_stats_by_query(&query) {
+            let stats_db = match database.load_stats(&query) {
                 Ok(data) => data,
                 Err(err) => {
                     error!("Failed to load stats `{}`: `{}`", chart, err);
@@ -17,7 +17,7 @@ impl TemplateCache<'_> {
     }
 
     pub fn load(&self, path_to_add: &str) -> Result<(), String> {
-        let entries = read_dir(path_to_add).map_err(|err| { format!("Error on reading templates directory {}: {:?}", path_to_add, err) })?;
+        let entries = read_dir(path_to_add).map_err(|err| { format!("Error on reading templates directory {}: {}", path_to_add, err) })?;
         let mut err_counter: u16 = 0;
         {
             let mut handler = self.templater.write().unwrap();
@@ -67,6 +67,11 @@ impl TemplateCache<'_> {
         }
     }
 
+    /// Whether the template with such name has been loaded
+    pub fn has_template(&self, name: &str) -> bool {
+        self.templater.read().map(|handler| handler.has_template(name)).unwrap_or(false)
+    }
+
     pub fn render_template<T>(&self, tmpl: &str, data: &T) -> Result<String, String> where T: Serialize{
         self.templater.read().unwrap().render(tmpl, data).map_err(|err| {
             format!("Error in rendering {}: {:?}", tmpl, err)
@@ -0,0 +1,308 @@
+use crate::config::Config;
+
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert, ServerConfig};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls_pemfile::Item;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use log::{debug, info, error};
+
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Reads the whole certificate chain from the PEM file: the leaf certificate goes first,
+/// intermediate ones follow it
+pub fn load_cert_chain(cert_path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(cert_path)
+        .map_err(|err| format!("Error on opening certificate `{}`: {}", cert_path, err))?;
+    let chain = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|err| format!("Error on reading certificate `{}`: {}", cert_path, err))?;
+    if chain.is_empty() {
+        return Err(format!("No certificates in `{}`", cert_path));
+    }
+    Ok(chain.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first private key from the PEM file. PKCS#1 (RSA), PKCS#8 and SEC1 (EC) keys are supported
+pub fn load_private_key(key_path: &str) -> Result<PrivateKey, String> {
+    let file = File::open(key_path)
+        .map_err(|err| format!("Error on opening key `{}`: {}", key_path, err))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| format!("Error on reading key `{}`: {}", key_path, err))?;
+    for item in items {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue
+        };
+    }
+    Err(format!("No supported private keys in `{}`", key_path))
+}
+
+/// Loads the certificate chain and the key, and checks that rustls can sign with this key
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
+    let chain = load_cert_chain(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|err| format!("Unsupported private key `{}`: {:?}", key_path, err))?;
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+fn get_mtime(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Keeps the current certificate and replaces it when the files are renewed.
+/// New connections get the new certificate, the running ones are not touched
+pub struct ReloadableCert {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<CertifiedKey>>,
+    mtimes: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadableCert {
+    pub fn new(cert_path: &str, key_path: &str) -> Result<ReloadableCert, String> {
+        let mtimes = (get_mtime(cert_path), get_mtime(key_path));
+        Ok(ReloadableCert {
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            mtimes: Mutex::new(mtimes),
+        })
+    }
+
+    /// Reads the files again. If they are broken (for example, the renewal is still in progress),
+    /// the old certificate stays in use
+    pub fn reload(&self) -> Result<(), String> {
+        let mtimes = (get_mtime(&self.cert_path), get_mtime(&self.key_path));
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().map_err(|err| format!("Error on writing the certificate: {:?}", err))? = Arc::new(key);
+        *self.mtimes.lock().map_err(|err| format!("Error on locking mtimes: {:?}", err))? = mtimes;
+        info!("TLS certificate has been reloaded from `{}`", self.cert_path);
+        Ok(())
+    }
+
+    /// Reloads the certificate, if the files were modified since the last load. Returns whether it was reloaded
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let mtimes = (get_mtime(&self.cert_path), get_mtime(&self.key_path));
+        let changed = *self.mtimes.lock().map_err(|err| format!("Error on locking mtimes: {:?}", err))? != mtimes;
+        if changed {
+            self.reload()?;
+        }
+        Ok(changed)
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+/// Reads CA bundle, which is trusted to issue the client certificates
+pub fn load_client_roots(ca_path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_cert_chain(ca_path)? {
+        roots.add(&cert).map_err(|err| format!("Invalid CA certificate in `{}`: {:?}", ca_path, err))?;
+    }
+    Ok(roots)
+}
+
+/// Makes rustls config, which takes the certificate from the resolver on every handshake.
+/// When the CA bundle is given, clients may present the certificate, issued by this CA.
+/// Clients without certificates are still accepted, they log in with the password
+pub fn make_server_config(resolver: Arc<ReloadableCert>, client_ca_path: Option<&str>) -> Result<ServerConfig, String> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let config = match client_ca_path {
+        Some(path) => builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(load_client_roots(path)?))
+            .with_cert_resolver(resolver),
+        None => builder.with_no_client_auth().with_cert_resolver(resolver)
+    };
+    Ok(config)
+}
+
+/// DER of the client certificate, which has been verified during the handshake
+#[derive(Clone, Debug)]
+pub struct ClientCertificate(pub Vec<u8>);
+
+/// Saves the verified client certificate to the connection data, so handlers can get it
+/// with `req.conn_data::<ClientCertificate>()`
+pub fn save_client_certificate(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(cert) = tls.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+            data.insert(ClientCertificate(cert.0.clone()));
+        }
+    }
+}
+
+/// Field of the client certificate, which holds the name of the user (`tls_client_cert_field` of the config)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CertField {
+    /// Subject CN, `cn`
+    CommonName,
+    /// E-mail from the Subject Alternative Name, `email`
+    Email,
+    /// DNS name from the Subject Alternative Name, `dns`
+    Dns,
+}
+
+impl CertField {
+    /// Parses the name of the field from the config
+    pub fn new(name: &str) -> Result<CertField, String> {
+        match name {
+            "cn" => Ok(CertField::CommonName),
+            "email" => Ok(CertField::Email),
+            "dns" => Ok(CertField::Dns),
+            _ => Err(format!("Unknown field of the client certificate: `{}`, use cn, email or dns", name))
+        }
+    }
+}
+
+/// Returns all the values of the field from the certificate: subject CN, or e-mails or DNS names
+/// from the Subject Alternative Name extension. Broken certificates and values give `None`,
+/// so the certificate is never matched by a part of its names
+pub fn get_certificate_names(der: &[u8], field: CertField) -> Option<Vec<String>> {
+    let (rest, cert) = X509Certificate::from_der(der).ok()?;
+    if !rest.is_empty() {
+        return None;
+    }
+
+    if field == CertField::CommonName {
+        return cert.subject().iter_common_name()
+            .map(|name| name.as_str().ok().map(|x| x.to_string()))
+            .collect();
+    }
+
+    let mut names = vec![];
+    if let Some(san) = cert.subject_alternative_name().ok()? {
+        for name in san.value.general_names.iter() {
+            match (name, field) {
+                (GeneralName::RFC822Name(value), CertField::Email) | (GeneralName::DNSName(value), CertField::Dns) => names.push(value.to_string()),
+                _ => continue
+            };
+        }
+    }
+    Some(names)
+}
+
+/// Polls modification time of the certificate and the key, and reloads them when they change
+pub fn run_tls_reloader(resolver: &Arc<ReloadableCert>, config: &Config) {
+    if config.tls_reload_poll_s == 0 {
+        return;
+    }
+    let resolver_copy = resolver.clone();
+    let period = config.tls_reload_poll_s;
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(period as u64));
+            match resolver_copy.reload_if_changed() {
+                Ok(changed) => debug!("TLS reloader has checked the certificate, changed: {}", changed),
+                Err(err) => error!("Error occurred in TLS reloader: {}", err)
+            };
+        }
+    });
+    info!("TLS reloader thread spawned");
+}
+
+/// Reloads the certificate on every SIGHUP. Must be called inside the actix runtime
+#[cfg(unix)]
+pub fn reload_on_sighup(resolver: &Arc<ReloadableCert>) {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    let resolver_copy = resolver.clone();
+    actix_rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Error on listening to SIGHUP: {:?}", err);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match resolver_copy.reload() {
+                Ok(_) => (),
+                Err(err) => error!("Error on reloading TLS certificate after SIGHUP: {}", err)
+            };
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_resolver: &Arc<ReloadableCert>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_fixture(pem: &[u8]) -> Vec<u8> {
+        rustls_pemfile::certs(&mut BufReader::new(pem)).unwrap().remove(0)
+    }
+
+    fn names_of(pem: &[u8], field: CertField) -> Option<Vec<String>> {
+        get_certificate_names(&read_fixture(pem), field)
+    }
+
+    const CN_ONLY: &[u8] = include_bytes!("../tests/certs/cn_only.pem");
+    const SAN: &[u8] = include_bytes!("../tests/certs/san.pem");
+    const MULTI_CN: &[u8] = include_bytes!("../tests/certs/multi_cn.pem");
+    const LONG_LENGTHS: &[u8] = include_bytes!("../tests/certs/long_lengths.pem");
+    const NO_FIELDS: &[u8] = include_bytes!("../tests/certs/no_fields.pem");
+
+    #[test]
+    fn reads_common_name() {
+        assert_eq!(names_of(CN_ONLY, CertField::CommonName), Some(vec!["thomasfire".to_string()]));
+        assert_eq!(names_of(CN_ONLY, CertField::Email), Some(vec![]));
+        assert_eq!(names_of(CN_ONLY, CertField::Dns), Some(vec![]));
+    }
+
+    #[test]
+    fn reads_alternative_names() {
+        assert_eq!(names_of(SAN, CertField::CommonName), Some(vec!["server".to_string()]));
+        assert_eq!(names_of(SAN, CertField::Email), Some(vec!["alice@example.com".to_string(), "bob@example.com".to_string()]));
+        assert_eq!(names_of(SAN, CertField::Dns), Some(vec!["alice.example.com".to_string()]));
+    }
+
+    #[test]
+    fn returns_every_common_name() {
+        assert_eq!(names_of(MULTI_CN, CertField::CommonName), Some(vec!["alice".to_string(), "bob".to_string()]));
+    }
+
+    #[test]
+    fn reads_long_form_lengths() {
+        assert_eq!(names_of(LONG_LENGTHS, CertField::CommonName), Some(vec!["b".repeat(64)]));
+        let dns = names_of(LONG_LENGTHS, CertField::Dns).unwrap();
+        assert_eq!(dns.len(), 8);
+        assert_eq!(dns[7], "host08.long-subdomain-name.example.com");
+    }
+
+    #[test]
+    fn no_matching_field() {
+        for field in [CertField::CommonName, CertField::Email, CertField::Dns] {
+            assert_eq!(names_of(NO_FIELDS, field), Some(vec![]));
+        }
+    }
+
+    #[test]
+    fn rejects_broken_der() {
+        let der = read_fixture(SAN);
+        for field in [CertField::CommonName, CertField::Email, CertField::Dns] {
+            assert_eq!(get_certificate_names(&[], field), None);
+            assert_eq!(get_certificate_names(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff], field), None);
+            assert_eq!(get_certificate_names(b"garbage, not a certificate", field), None);
+            for len in [1, 2, 4, der.len() / 2, der.len() - 1] {
+                assert_eq!(get_certificate_names(&der[..len], field), None);
+            }
+            let mut trailing = der.clone();
+            trailing.push(0);
+            assert_eq!(get_certificate_names(&trailing, field), None);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+extern crate crypto;
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use base32::Alphabet;
+use qrcode::QrCode;
+use qrcode::render::svg;
+use rand::random;
+
+/// Length of the time step, as recommended by RFC 6238
+pub const TOTP_STEP_S: u64 = 30;
+/// How many steps before and after the current one are still accepted, to tolerate clock drift
+pub const TOTP_SKEW_STEPS: u64 = 1;
+const TOTP_DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+const ISSUER: &str = "webify";
+
+const B32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// Generates new random secret, encoded as base32 without padding
+pub fn generate_secret() -> String {
+    let bytes: Vec<u8> = (0..SECRET_LEN).map(|_| random::<u8>()).collect();
+    base32::encode(B32, &bytes)
+}
+
+/// Returns the number of the time step for the unix time
+pub fn get_step(unix_time: u64) -> u64 {
+    unix_time / TOTP_STEP_S
+}
+
+/// Calculates HOTP code (RFC 4226) for the base32 secret and the counter
+pub fn get_hotp(secret: &str, counter: u64) -> Result<u32, String> {
+    let key = base32::decode(B32, secret).ok_or("Invalid TOTP secret".to_string())?;
+    let mut mac = Hmac::new(Sha1::new(), &key);
+    mac.input(&counter.to_be_bytes());
+    let result = mac.result();
+    let digest = result.code();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = ((digest[offset] as u32 & 0x7f) << 24)
+        | ((digest[offset + 1] as u32) << 16)
+        | ((digest[offset + 2] as u32) << 8)
+        | (digest[offset + 3] as u32);
+    Ok(binary % 10u32.pow(TOTP_DIGITS))
+}
+
+/// Calculates TOTP code (RFC 6238) for the base32 secret at the given unix time
+///
+/// # Examples
+///
+/// ```rust
+/// use webify::totp::get_totp;
+/// // secret "12345678901234567890" from the RFC 6238 test vectors
+/// assert_eq!(get_totp("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", 59).unwrap(), 287082);
+/// ```
+pub fn get_totp(secret: &str, unix_time: u64) -> Result<u32, String> {
+    get_hotp(secret, get_step(unix_time))
+}
+
+/// Checks the code at the given unix time. Returns the matched time step, so the caller can
+/// refuse to accept the same code twice
+pub fn verify_totp(secret: &str, code: &str, unix_time: u64) -> Result<Option<u64>, String> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|x| x.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let parsed: u32 = code.parse().map_err(|err| format!("Couldn't parse the code: {:?}", err))?;
+    let current = get_step(unix_time);
+    for step in current.saturating_sub(TOTP_SKEW_STEPS)..=current + TOTP_SKEW_STEPS {
+        if get_hotp(secret, step)? == parsed {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns `otpauth://` URI, which is understood by authenticator apps
+pub fn get_otpauth_uri(username: &str, secret: &str) -> String {
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            ISSUER, urlencoding::encode(username), secret, ISSUER, TOTP_DIGITS, TOTP_STEP_S)
+}
+
+/// Renders the URI as QR code in SVG format
+pub fn get_qr_svg(uri: &str) -> Result<String, String> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|err| format!("Error on making QR code: {:?}", err))?;
+    Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+/// Generates one-time recovery codes in form of `xxxxx-xxxxx`
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    (0..count).map(|_| {
+        let raw = base32::encode(B32, &(0..7).map(|_| random::<u8>()).collect::<Vec<u8>>()).to_lowercase();
+        format!("{}-{}", &raw[0..5], &raw[5..10])
+    }).collect()
+}